@@ -0,0 +1,759 @@
+use std::mem;
+
+use crate::parsing::{read_bytes, read_le_primitive, read_string, write_le_primitive, write_string, ParseError};
+
+#[derive(Clone, PartialEq, Debug)]
+#[repr(C)]
+pub struct Animation {
+    pub header: Option<Header>,
+    pub animation_speed: u32,
+    pub palette_filename_length: u32,
+    pub palette_filename: String,
+    pub num_frames: u32,
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[repr(C)]
+pub struct Header {
+    pub ztaf_string: u32,
+    pub empty_4_bytes: u32,
+    pub extra_frame: bool,
+}
+
+/// A frame decoded to one palette index per pixel, None where nothing is drawn. Every row is pixel_width long
+pub type FramePixels = Vec<Vec<Option<u8>>>;
+
+// Runs store their skip and color counts as u8 so longer runs are split
+const MAX_RUN_LENGTH: usize = u8::MAX as usize;
+
+#[derive(Clone, PartialEq, Debug)]
+#[repr(C)]
+pub struct Frame {
+    pub num_bytes: u32,
+    pub pixel_height: u16,
+    pub pixel_width: u16,
+    pub vertical_offset_y: u16,
+    pub horizontal_offset_x: u16,
+    pub mystery_u16: u16,
+    pub lines: Vec<Line>,
+}
+
+impl Frame {
+    // num_bytes covers everything after itself, reads are limited to that so a bad line can't run into the next frame.
+    // Some files pad frames past the end of their lines, the padding is skipped
+    fn parse(data: &[u8], index: &mut usize) -> Result<Frame, ParseError> {
+        let num_bytes: u32 = read_le_primitive(data, index, "num_bytes")?;
+        let frame_start = *index;
+        let remaining = data.len() - frame_start;
+        if num_bytes as usize > remaining {
+            return Err(ParseError::new(
+                frame_start - mem::size_of::<u32>(),
+                "num_bytes",
+                format!("at most {} bytes", remaining),
+                num_bytes.to_string(),
+            ));
+        }
+        let frame_data = &data[..frame_start + num_bytes as usize];
+
+        let pixel_height = read_le_primitive(frame_data, index, "pixel height")?;
+        let pixel_width = read_le_primitive(frame_data, index, "pixel width")?;
+        let vertical_offset_y = read_le_primitive(frame_data, index, "vertical offset")?;
+        let horizontal_offset_x = read_le_primitive(frame_data, index, "horizontal offset")?;
+        let mystery_u16 = read_le_primitive(frame_data, index, "mystery u16")?;
+        let mut lines = Vec::with_capacity(pixel_height as usize);
+        for line_index in 0..pixel_height {
+            let line = Line::parse(frame_data, index).map_err(|e| e.within(&format!("line {}", line_index)))?;
+            lines.push(line);
+        }
+
+        *index = frame_data.len();
+
+        Ok(Frame {
+            num_bytes,
+            pixel_height,
+            pixel_width,
+            vertical_offset_y,
+            horizontal_offset_x,
+            mystery_u16,
+            lines,
+        })
+    }
+
+    pub fn to_pixels(&self) -> Result<FramePixels, &'static str> {
+        let mut pixels = Vec::with_capacity(self.lines.len());
+        for line in self.lines.iter() {
+            let mut row = vec![None; self.pixel_width as usize];
+            let mut x = 0;
+            for draw_instruction in line.draw_instructions.iter() {
+                x += draw_instruction.offset as usize;
+                for color in draw_instruction.colors.iter() {
+                    let Some(pixel) = row.get_mut(x) else {
+                        return Err("Line is wider than its frame");
+                    };
+                    *pixel = Some(*color);
+                    x += 1;
+                }
+            }
+            pixels.push(row);
+        }
+        Ok(pixels)
+    }
+
+    /// Re-encodes the frame from pixels, the width, height and num_bytes are updated to match but the offsets are left alone
+    pub fn set_pixels(&mut self, pixels: &FramePixels) -> Result<(), &'static str> {
+        let width = pixels.first().map_or(0, |row| row.len());
+        if pixels.iter().any(|row| row.len() != width) {
+            return Err("Every row must be the same width");
+        }
+        if width > u16::MAX as usize || pixels.len() > u16::MAX as usize {
+            return Err("Frame is too large");
+        }
+        self.lines = pixels.iter().map(|row| Line::from_pixels(row)).collect::<Result<Vec<Line>, &'static str>>()?;
+        self.pixel_width = width as u16;
+        self.pixel_height = pixels.len() as u16;
+        self.num_bytes = self.calc_byte_size() as u32;
+        Ok(())
+    }
+
+    // Offsets are stored as u16 but are signed, they are the distance from the frame's top left corner to the anchor
+    fn set_offsets(&mut self, x: i32, y: i32) -> Result<(), &'static str> {
+        self.horizontal_offset_x = i16::try_from(x).map_err(|_| "Horizontal offset out of range")? as u16;
+        self.vertical_offset_y = i16::try_from(y).map_err(|_| "Vertical offset out of range")? as u16;
+        Ok(())
+    }
+
+    fn offsets(&self) -> (i32, i32) {
+        (self.horizontal_offset_x as i16 as i32, self.vertical_offset_y as i16 as i32)
+    }
+
+    pub fn calc_byte_size(&self) -> usize {
+        let mut size = mem::size_of::<u16>() * 5;
+        for line in &self.lines {
+            size += line.calc_byte_size()
+        }
+        size
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[repr(C)]
+pub struct Line {
+    pub num_draw_instructions: u8,
+    pub draw_instructions: Vec<DrawInstruction>,
+}
+
+impl Line {
+    fn parse(data: &[u8], index: &mut usize) -> Result<Line, ParseError> {
+        let num_draw_instructions = read_le_primitive(data, index, "draw instruction count")?;
+        let mut draw_instructions = Vec::with_capacity(num_draw_instructions as usize);
+        for _ in 0..num_draw_instructions {
+            let offset = read_le_primitive(data, index, "draw instruction offset")?;
+            let num_colors = read_le_primitive(data, index, "draw instruction color count")?;
+            let colors = read_bytes(data, index, num_colors as usize, "draw instruction colors")?.to_vec();
+            draw_instructions.push(DrawInstruction { offset, num_colors, colors });
+        }
+        Ok(Line {
+            num_draw_instructions,
+            draw_instructions,
+        })
+    }
+
+    /// Encodes a row of pixels as runs, fails if the row needs more runs than a line can hold
+    pub fn from_pixels(pixels: &[Option<u8>]) -> Result<Line, &'static str> {
+        let mut draw_instructions = Vec::new();
+        let mut skip = 0;
+        let mut colors = Vec::new();
+        for pixel in pixels.iter() {
+            match pixel {
+                None => {
+                    if !colors.is_empty() {
+                        push_run(&mut draw_instructions, skip, mem::take(&mut colors));
+                        skip = 0;
+                    }
+                    skip += 1;
+                }
+                Some(color) => {
+                    if colors.len() == MAX_RUN_LENGTH {
+                        push_run(&mut draw_instructions, skip, mem::take(&mut colors));
+                        skip = 0;
+                    }
+                    colors.push(*color);
+                }
+            }
+        }
+        // Trailing transparent pixels don't need a run
+        if !colors.is_empty() {
+            push_run(&mut draw_instructions, skip, colors);
+        }
+        if draw_instructions.len() > u8::MAX as usize {
+            return Err("Line needs more runs than a line can hold");
+        }
+        Ok(Line {
+            num_draw_instructions: draw_instructions.len() as u8,
+            draw_instructions,
+        })
+    }
+
+    pub fn calc_byte_size(&self) -> usize {
+        let mut size = mem::size_of::<u8>();
+        for draw_instruction in &self.draw_instructions {
+            size += mem::size_of::<u8>() + mem::size_of::<u8>() + (draw_instruction.colors.len() * mem::size_of::<u8>());
+        }
+        size
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[repr(C)]
+pub struct DrawInstruction {
+    pub offset: u8,
+    pub num_colors: u8,
+    pub colors: Vec<u8>,
+}
+
+// Runs can only skip 255 pixels, longer gaps are made up of runs that don't draw anything
+fn push_run(draw_instructions: &mut Vec<DrawInstruction>, mut skip: usize, colors: Vec<u8>) {
+    while skip > MAX_RUN_LENGTH {
+        draw_instructions.push(DrawInstruction {
+            offset: MAX_RUN_LENGTH as u8,
+            num_colors: 0,
+            colors: Vec::new(),
+        });
+        skip -= MAX_RUN_LENGTH;
+    }
+    draw_instructions.push(DrawInstruction {
+        offset: skip as u8,
+        num_colors: colors.len() as u8,
+        colors,
+    });
+}
+
+// Marks an animation with a header, "ZTAF" read as a little endian u32
+const ZTAF_STRING: u32 = 0x5A544146;
+
+// TODO: Write test based on mod data
+impl Animation {
+    /// Parses an animation, every read is bounds checked and each frame's lines must fit in its num_bytes so malformed files are rejected rather than misread
+    pub fn parse(data: &[u8]) -> Result<Animation, ParseError> {
+        let mut index = 0;
+        let mut header = None;
+        let maybe_header = read_le_primitive(data, &mut index, "header or animation speed")?;
+        let animation_speed = match maybe_header {
+            ZTAF_STRING => {
+                header = Some(Header {
+                    ztaf_string: maybe_header,
+                    empty_4_bytes: read_le_primitive(data, &mut index, "header padding")?,
+                    extra_frame: read_le_primitive(data, &mut index, "header extra frame flag")?,
+                });
+                read_le_primitive(data, &mut index, "animation speed")?
+            }
+            _ => maybe_header,
+        };
+
+        let palette_filename_length: u32 = read_le_primitive(data, &mut index, "palette filename length")?;
+        let palette_filename = read_string(data, &mut index, palette_filename_length as usize, "palette filename")?;
+        let num_frames: u32 = read_le_primitive(data, &mut index, "frame count")?;
+        let extra_frames = if header.as_ref().is_some_and(|h| h.extra_frame) { 1 } else { 0 };
+        let mut frames = Vec::new();
+        for frame_index in 0..num_frames as u64 + extra_frames {
+            let frame = Frame::parse(data, &mut index).map_err(|e| e.within(&format!("frame {}", frame_index)))?;
+            frames.push(frame);
+        }
+
+        Ok(Animation {
+            header,
+            animation_speed,
+            palette_filename_length,
+            palette_filename,
+            num_frames,
+            frames,
+        })
+    }
+
+    pub fn write(self) -> (Vec<u8>, usize) {
+        let mut accumulator: usize = 0;
+        let mut bytes = Vec::new();
+        if let Some(header) = self.header {
+            write_le_primitive(&mut bytes, header.ztaf_string, &mut accumulator);
+            write_le_primitive(&mut bytes, header.empty_4_bytes, &mut accumulator);
+            write_le_primitive(&mut bytes, header.extra_frame, &mut accumulator);
+        }
+        write_le_primitive(&mut bytes, self.animation_speed, &mut accumulator);
+        write_string(&mut bytes, &self.palette_filename, &mut accumulator);
+        write_le_primitive(&mut bytes, self.num_frames, &mut accumulator);
+
+        for frame in self.frames {
+            write_le_primitive(&mut bytes, frame.num_bytes, &mut accumulator);
+            write_le_primitive(&mut bytes, frame.pixel_height, &mut accumulator);
+            write_le_primitive(&mut bytes, frame.pixel_width, &mut accumulator);
+            write_le_primitive(&mut bytes, frame.vertical_offset_y, &mut accumulator);
+            write_le_primitive(&mut bytes, frame.horizontal_offset_x, &mut accumulator);
+            write_le_primitive(&mut bytes, frame.mystery_u16, &mut accumulator);
+
+            // Padded frames keep their size so num_bytes still points at the next frame
+            let padding = (frame.num_bytes as usize).saturating_sub(frame.calc_byte_size());
+            for line in frame.lines {
+                write_le_primitive(&mut bytes, line.num_draw_instructions, &mut accumulator);
+                for draw_instruction in line.draw_instructions {
+                    write_le_primitive(&mut bytes, draw_instruction.offset, &mut accumulator);
+                    write_le_primitive(&mut bytes, draw_instruction.num_colors, &mut accumulator);
+                    for color in draw_instruction.colors {
+                        write_le_primitive(&mut bytes, color, &mut accumulator);
+                    }
+                }
+            }
+            for _ in 0..padding {
+                write_le_primitive(&mut bytes, 0u8, &mut accumulator);
+            }
+        }
+        bytes.shrink_to_fit();
+        (bytes, accumulator)
+    }
+
+    pub fn duplicate_pixel_rows(&mut self, frame: usize, start_index: usize, end_index: usize) -> Result<&mut Self, &'static str> {
+        let mut additional_bytes: usize = 0;
+        if start_index > end_index {
+            return Err("Start index must be less than end index");
+        }
+        if self.frames.len() < frame {
+            return Err("Frame index out of bounds");
+        }
+        if self.frames[frame].lines.len() < end_index {
+            return Err("End index out of bounds");
+        }
+        let mut new_lines = Vec::new();
+        for line in self.frames[frame].lines[start_index..end_index].iter() {
+            new_lines.push(line.clone());
+            additional_bytes += line.calc_byte_size();
+        }
+
+        self.frames[frame].lines.splice(end_index..end_index, new_lines);
+
+        self.frames[frame].num_bytes += additional_bytes as u32;
+
+        self.frames[frame].pixel_height += (end_index - start_index) as u16;
+
+        Ok(self)
+    }
+
+    pub fn set_palette_filename(&mut self, palette_filename: String) {
+        self.palette_filename = palette_filename;
+        self.palette_filename_length = self.palette_filename.len() as u32 + 1;
+    }
+
+    pub fn set_animation_speed(&mut self, animation_speed: u32) -> &mut Self {
+        self.animation_speed = animation_speed;
+        self
+    }
+
+    /// Mirrors every frame left to right around its anchor, e.g. to make a west view from an east view
+    pub fn flip_horizontal(&mut self) -> Result<&mut Self, &'static str> {
+        for frame in self.frames.iter_mut() {
+            let mut pixels = frame.to_pixels()?;
+            pixels.iter_mut().for_each(|row| row.reverse());
+            frame.set_pixels(&pixels)?;
+            // The anchor pixel stays put, it was offset_x from the left so is now offset_x from the right
+            let (offset_x, offset_y) = frame.offsets();
+            frame.set_offsets(frame.pixel_width as i32 - 1 - offset_x, offset_y)?;
+        }
+        Ok(self)
+    }
+
+    /// Grows (positive) or shrinks (negative) every frame by the given number of pixels on each side,
+    /// new pixels are transparent and the anchor stays on the same pixel
+    pub fn resize_frames(&mut self, left: i32, top: i32, right: i32, bottom: i32) -> Result<&mut Self, &'static str> {
+        for frame in self.frames.iter_mut() {
+            let width = frame.pixel_width as i32 + left + right;
+            let height = frame.pixel_height as i32 + top + bottom;
+            if width < 0 || height < 0 {
+                return Err("Can't crop more than the size of the frame");
+            }
+            let pixels = frame.to_pixels()?;
+            let resized = (0..height)
+                .map(|y| {
+                    (0..width)
+                        .map(|x| {
+                            let row = usize::try_from(y - top).ok().and_then(|y| pixels.get(y))?;
+                            usize::try_from(x - left).ok().and_then(|x| row.get(x)).copied().flatten()
+                        })
+                        .collect()
+                })
+                .collect();
+            frame.set_pixels(&resized)?;
+            let (offset_x, offset_y) = frame.offsets();
+            frame.set_offsets(offset_x + left, offset_y + top)?;
+        }
+        Ok(self)
+    }
+
+    pub fn pad(&mut self, left: u16, top: u16, right: u16, bottom: u16) -> Result<&mut Self, &'static str> {
+        self.resize_frames(left as i32, top as i32, right as i32, bottom as i32)
+    }
+
+    pub fn crop(&mut self, left: u16, top: u16, right: u16, bottom: u16) -> Result<&mut Self, &'static str> {
+        self.resize_frames(-(left as i32), -(top as i32), -(right as i32), -(bottom as i32))
+    }
+
+    /// Moves every frame relative to its anchor, positive values move the frame right and down
+    pub fn shift_offsets(&mut self, x: i32, y: i32) -> Result<&mut Self, &'static str> {
+        for frame in self.frames.iter_mut() {
+            let (offset_x, offset_y) = frame.offsets();
+            frame.set_offsets(offset_x - x, offset_y - y)?;
+        }
+        Ok(self)
+    }
+
+    /// Replaces every palette index with remap_table[index], e.g. a table from Palette::remap_table or a recolour.
+    /// Nothing is changed if an index is outside the table
+    pub fn remap_colors(&mut self, remap_table: &[u8]) -> Result<&mut Self, &'static str> {
+        let mut colors = self
+            .frames
+            .iter()
+            .flat_map(|frame| frame.lines.iter())
+            .flat_map(|line| line.draw_instructions.iter())
+            .flat_map(|draw_instruction| draw_instruction.colors.iter());
+        if colors.any(|color| *color as usize >= remap_table.len()) {
+            return Err("Color index outside the remap table");
+        }
+        for frame in self.frames.iter_mut() {
+            for line in frame.lines.iter_mut() {
+                for draw_instruction in line.draw_instructions.iter_mut() {
+                    draw_instruction.colors.iter_mut().for_each(|color| *color = remap_table[*color as usize]);
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    // The extra frame (if the header has one) comes after the num_frames regular frames and isn't part of the animation loop
+    fn extra_frame_count(&self) -> usize {
+        if self.header.as_ref().is_some_and(|header| header.extra_frame) {
+            1
+        } else {
+            0
+        }
+    }
+
+    pub fn insert_frame(&mut self, index: usize, frame: Frame) -> Result<&mut Self, &'static str> {
+        if index > self.num_frames as usize {
+            return Err("Frame index out of bounds");
+        }
+        self.frames.insert(index, frame);
+        self.num_frames += 1;
+        Ok(self)
+    }
+
+    pub fn delete_frame(&mut self, index: usize) -> Result<&mut Self, &'static str> {
+        if index >= self.num_frames as usize {
+            return Err("Frame index out of bounds");
+        }
+        if self.num_frames == 1 {
+            return Err("Can't delete the only frame");
+        }
+        self.frames.remove(index);
+        self.num_frames -= 1;
+        Ok(self)
+    }
+
+    /// Rebuilds the frame list from existing frame indices, frames can be repeated or left out
+    pub fn reorder_frames(&mut self, order: &[usize]) -> Result<&mut Self, &'static str> {
+        if order.is_empty() {
+            return Err("An animation needs at least one frame");
+        }
+        if order.iter().any(|index| *index >= self.num_frames as usize) {
+            return Err("Frame index out of bounds");
+        }
+        let extra_frames = self.frames.split_off(self.num_frames as usize);
+        let mut frames = order.iter().map(|index| self.frames[*index].clone()).collect::<Vec<Frame>>();
+        frames.extend(extra_frames);
+        self.frames = frames;
+        self.num_frames = order.len() as u32;
+        Ok(self)
+    }
+
+    /// Adds the regular frames of other after this animation's frames, both animations must use the same palette
+    pub fn append_frames(&mut self, other: &Animation) -> Result<&mut Self, &'static str> {
+        if !self.palette_filename.eq_ignore_ascii_case(&other.palette_filename) {
+            return Err("Animations use different palettes, remap one of them first");
+        }
+        let index = self.num_frames as usize;
+        let other_frames = other.frames.iter().take(other.num_frames as usize).cloned();
+        self.frames.splice(index..index, other_frames);
+        self.num_frames = (self.frames.len() - self.extra_frame_count()) as u32;
+        Ok(self)
+    }
+
+    /// Joins animations into one, the header, speed and palette come from the first
+    pub fn combine(animations: &[Animation]) -> Result<Animation, &'static str> {
+        let Some((first, rest)) = animations.split_first() else {
+            return Err("Nothing to combine");
+        };
+        let mut combined = first.clone();
+        for animation in rest {
+            combined.append_frames(animation)?;
+        }
+        Ok(combined)
+    }
+}
+
+#[cfg(test)]
+mod parsing_tests {
+    use super::{Animation, Line};
+    use crate::parsing::ParseError;
+
+    #[test]
+    fn test_parse_simple_anim_no_header() {
+        let animation = Animation::parse(include_bytes!("../../../resources/test/N-noheader")).unwrap();
+        assert!(animation.header.is_none());
+        assert_eq!(animation.palette_filename, "ui/sharedui/listbk/ltb.pal".to_string());
+        assert_eq!(animation.num_frames, 1);
+        assert_eq!(animation.frames.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_simple_anim_with_header() {
+        let animation = Animation::parse(include_bytes!("../../../resources/test/N")).unwrap();
+        assert!(animation.header.is_some());
+        assert!(!animation.header.unwrap().extra_frame);
+        assert_eq!(animation.palette_filename, "ANIMALS/01BFCC32/ICMEIOLA/ICMEIOLA.PAL".to_string());
+        assert_eq!(animation.num_frames, 1);
+        assert_eq!(animation.frames.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_and_write() {
+        let animation = Animation::parse(include_bytes!("../../../resources/test/N")).unwrap();
+        let animation_to_write = animation.clone();
+        let (animation_bytes, _) = animation_to_write.write();
+        let animation_2 = Animation::parse(&animation_bytes[..]).unwrap();
+        assert!(animation == animation_2);
+    }
+
+    #[test]
+    fn test_calc_byte_size() {
+        let animation = Animation::parse(include_bytes!("../../../resources/test/N")).unwrap();
+        assert_eq!(animation.frames[0].num_bytes, animation.frames[0].calc_byte_size() as u32);
+    }
+
+    #[test]
+    fn test_parse_modify_and_write() {
+        let animation = Animation::parse(include_bytes!("../../../resources/test/N")).unwrap();
+        let mut animation_to_modify = animation.clone();
+        animation_to_modify.duplicate_pixel_rows(0, 0, 1).unwrap();
+        assert_eq!(animation.frames[0].pixel_height + 1, animation_to_modify.frames[0].pixel_height);
+        animation_to_modify.set_palette_filename(animation.palette_filename.clone());
+        assert_eq!(animation.palette_filename_length, animation_to_modify.palette_filename_length);
+        let (animation_bytes, _) = animation_to_modify.write();
+        let animation_2 = Animation::parse(&animation_bytes[..]).unwrap();
+        assert_eq!(animation.frames[0].pixel_height + 1, animation_2.frames[0].pixel_height);
+        assert_eq!(animation.palette_filename, animation_2.palette_filename);
+        assert_eq!(animation.palette_filename_length, animation_2.palette_filename_length);
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let data = include_bytes!("../../../resources/test/N");
+        for length in [0, 3, 10, data.len() / 2, data.len() - 1] {
+            assert!(Animation::parse(&data[..length]).is_err(), "parsed {} of {} bytes", length, data.len());
+        }
+    }
+
+    #[test]
+    fn test_parse_truncated_error() {
+        let data = include_bytes!("../../../resources/test/N-noheader");
+        // Speed, palette filename length, palette filename and frame count come before the first frame's num_bytes
+        let frame_offset = 4 + 4 + "ui/sharedui/listbk/ltb.pal".len() + 1 + 4;
+        let error = Animation::parse(&data[..frame_offset + 2]).unwrap_err();
+        assert_eq!(
+            error,
+            ParseError {
+                offset: frame_offset,
+                field: "frame 0 num_bytes".to_string(),
+                expected: "4 bytes".to_string(),
+                actual: "2 bytes before the end of the data".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_wrong_num_bytes() {
+        let mut animation = Animation::parse(include_bytes!("../../../resources/test/N")).unwrap();
+        animation.frames[0].num_bytes -= 1;
+        let (animation_bytes, _) = animation.write();
+        let error = Animation::parse(&animation_bytes).unwrap_err();
+        assert!(error.field.starts_with("frame 0"), "{}", error);
+    }
+
+    #[test]
+    fn test_parse_and_write_padded_frame() {
+        let animation = Animation::parse(include_bytes!("../../../resources/test/N-noheader")).unwrap();
+        assert!(animation.frames[0].num_bytes as usize > animation.frames[0].calc_byte_size());
+        let (animation_bytes, _) = animation.clone().write();
+        assert_eq!(animation_bytes.len(), include_bytes!("../../../resources/test/N-noheader").len());
+        assert_eq!(Animation::parse(&animation_bytes).unwrap(), animation);
+    }
+
+    #[test]
+    fn test_parse_empty_palette_filename() {
+        let mut data = 1000u32.to_le_bytes().to_vec();
+        data.extend(0u32.to_le_bytes());
+        let error = Animation::parse(&data).unwrap_err();
+        assert_eq!(error.field, "palette filename");
+        assert_eq!(error.offset, 8);
+    }
+
+    fn test_animation() -> Animation {
+        Animation::parse(include_bytes!("../../../resources/test/N")).unwrap()
+    }
+
+    // Frames built by the operations must parse back to themselves and have consistent sizes
+    fn assert_round_trip(animation: &Animation) {
+        for frame in animation.frames.iter() {
+            assert_eq!(frame.num_bytes, frame.calc_byte_size() as u32);
+        }
+        let (animation_bytes, _) = animation.clone().write();
+        assert_eq!(&Animation::parse(&animation_bytes).unwrap(), animation);
+    }
+
+    #[test]
+    fn test_pixels_round_trip() {
+        let animation = test_animation();
+        let mut frame = animation.frames[0].clone();
+        let pixels = frame.to_pixels().unwrap();
+        assert_eq!(pixels.len(), frame.pixel_height as usize);
+        assert!(pixels.iter().all(|row| row.len() == frame.pixel_width as usize));
+        frame.set_pixels(&pixels).unwrap();
+        assert_eq!(frame.to_pixels().unwrap(), pixels);
+        assert_eq!(frame.num_bytes, frame.calc_byte_size() as u32);
+    }
+
+    #[test]
+    fn test_line_from_pixels_long_runs() {
+        let mut pixels = vec![None; 300];
+        pixels.extend(vec![Some(1); 300]);
+        let line = Line::from_pixels(&pixels).unwrap();
+        let offsets = line.draw_instructions.iter().map(|draw_instruction| (draw_instruction.offset, draw_instruction.num_colors)).collect::<Vec<(u8, u8)>>();
+        assert_eq!(offsets, vec![(255, 0), (45, 255), (0, 45)]);
+        assert_eq!(line.num_draw_instructions, 3);
+
+        // Alternating pixels need a run each, more than a line can hold
+        let alternating = (0..600).map(|x| if x % 2 == 0 { Some(1) } else { None }).collect::<Vec<Option<u8>>>();
+        assert!(Line::from_pixels(&alternating).is_err());
+    }
+
+    #[test]
+    fn test_flip_horizontal() {
+        let animation = test_animation();
+        let mut flipped = animation.clone();
+        flipped.flip_horizontal().unwrap();
+        assert_round_trip(&flipped);
+
+        let frame = &animation.frames[0];
+        let flipped_frame = &flipped.frames[0];
+        let pixels = frame.to_pixels().unwrap();
+        let flipped_pixels = flipped_frame.to_pixels().unwrap();
+        let mut mirrored = pixels[0].clone();
+        mirrored.reverse();
+        assert_eq!(flipped_pixels[0], mirrored);
+        assert_eq!(flipped_frame.horizontal_offset_x, frame.pixel_width - 1 - frame.horizontal_offset_x);
+        assert_eq!(flipped_frame.vertical_offset_y, frame.vertical_offset_y);
+
+        flipped.flip_horizontal().unwrap();
+        assert_eq!(flipped.frames[0].to_pixels().unwrap(), pixels);
+        assert_eq!(flipped.frames[0].horizontal_offset_x, frame.horizontal_offset_x);
+    }
+
+    #[test]
+    fn test_pad_and_crop() {
+        let animation = test_animation();
+        let frame = &animation.frames[0];
+        let mut padded = animation.clone();
+        padded.pad(2, 3, 4, 5).unwrap();
+        assert_round_trip(&padded);
+        let padded_frame = &padded.frames[0];
+        assert_eq!(padded_frame.pixel_width, frame.pixel_width + 6);
+        assert_eq!(padded_frame.pixel_height, frame.pixel_height + 8);
+        assert_eq!(padded_frame.horizontal_offset_x, frame.horizontal_offset_x + 2);
+        assert_eq!(padded_frame.vertical_offset_y, frame.vertical_offset_y + 3);
+        assert!(padded_frame.to_pixels().unwrap()[..3].iter().flatten().all(|pixel| pixel.is_none()));
+
+        padded.crop(2, 3, 4, 5).unwrap();
+        assert_eq!(padded.frames[0].to_pixels().unwrap(), frame.to_pixels().unwrap());
+        assert_eq!(padded.frames[0].horizontal_offset_x, frame.horizontal_offset_x);
+        assert_eq!(padded.frames[0].vertical_offset_y, frame.vertical_offset_y);
+
+        assert!(padded.crop(frame.pixel_width, 0, 1, 0).is_err());
+    }
+
+    #[test]
+    fn test_shift_offsets() {
+        let animation = test_animation();
+        let mut shifted = animation.clone();
+        shifted.shift_offsets(5, -3).unwrap();
+        assert_eq!(shifted.frames[0].horizontal_offset_x, animation.frames[0].horizontal_offset_x - 5);
+        assert_eq!(shifted.frames[0].vertical_offset_y, animation.frames[0].vertical_offset_y + 3);
+        assert!(shifted.shift_offsets(i16::MAX as i32 * 2, 0).is_err());
+    }
+
+    #[test]
+    fn test_remap_colors() {
+        let animation = test_animation();
+        let mut remapped = animation.clone();
+        let reverse_table = (0..=255u8).rev().collect::<Vec<u8>>();
+        remapped.remap_colors(&reverse_table).unwrap();
+        assert_round_trip(&remapped);
+        let original_run = &animation.frames[0].lines.iter().find(|line| !line.draw_instructions.is_empty()).unwrap().draw_instructions[0];
+        let remapped_run = &remapped.frames[0].lines.iter().find(|line| !line.draw_instructions.is_empty()).unwrap().draw_instructions[0];
+        assert_eq!(remapped_run.colors[0], 255 - original_run.colors[0]);
+
+        // Short tables are rejected without changing anything
+        let mut unchanged = animation.clone();
+        assert!(unchanged.remap_colors(&[0]).is_err());
+        assert_eq!(unchanged, animation);
+    }
+
+    #[test]
+    fn test_frame_operations() {
+        let mut animation = test_animation();
+        let mut shifted_frame = animation.frames[0].clone();
+        shifted_frame.horizontal_offset_x += 1;
+
+        animation.insert_frame(1, shifted_frame.clone()).unwrap();
+        assert_eq!(animation.num_frames, 2);
+        assert!(animation.insert_frame(5, shifted_frame.clone()).is_err());
+
+        animation.reorder_frames(&[1, 0, 1]).unwrap();
+        assert_eq!(animation.num_frames, 3);
+        assert_eq!(animation.frames[0], shifted_frame);
+        assert_eq!(animation.frames[2], shifted_frame);
+        assert!(animation.reorder_frames(&[3]).is_err());
+        assert!(animation.reorder_frames(&[]).is_err());
+
+        animation.delete_frame(0).unwrap();
+        animation.delete_frame(1).unwrap();
+        assert_eq!(animation.num_frames, 1);
+        assert_ne!(animation.frames[0], shifted_frame);
+        assert!(animation.delete_frame(0).is_err());
+
+        animation.set_animation_speed(250);
+        assert_round_trip(&animation);
+        assert_eq!(animation.animation_speed, 250);
+    }
+
+    #[test]
+    fn test_combine() {
+        let animation = test_animation();
+        let mut flipped = animation.clone();
+        flipped.flip_horizontal().unwrap();
+        let combined = Animation::combine(&[animation.clone(), flipped.clone()]).unwrap();
+        assert_round_trip(&combined);
+        assert_eq!(combined.num_frames, 2);
+        assert_eq!(combined.frames[1], flipped.frames[0]);
+
+        let mut other_palette = animation.clone();
+        other_palette.set_palette_filename("other.pal".to_string());
+        assert!(Animation::combine(&[animation, other_palette]).is_err());
+        assert!(Animation::combine(&[]).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    animation::{Animation, Frame},
+    palette::Palette,
+    sprite::{render_frame, FrameBounds, RgbaImage},
+};
+
+// Colours used in diff images, unchanged pixels are drawn faded so the changes stand out
+const ADDED_COLOR: [u8; 4] = [0x00, 0xff, 0x00, 0xff];
+const REMOVED_COLOR: [u8; 4] = [0xff, 0x00, 0x00, 0xff];
+const CHANGED_COLOR: [u8; 4] = [0xff, 0x00, 0xff, 0xff];
+const UNCHANGED_ALPHA: u8 = 0x40;
+
+/// Frame by frame comparison of two animations, e.g. a vanilla animation and a mod's override of it
+pub struct AnimationDiff {
+    // Differences in everything but the frames
+    pub header_changes: Vec<String>,
+    pub frames: Vec<FrameDiff>,
+}
+
+pub struct FrameDiff {
+    pub index: usize,
+    pub old_bounds: Option<FrameBounds>,
+    pub new_bounds: Option<FrameBounds>,
+    pub added_pixels: usize,
+    pub removed_pixels: usize,
+    pub changed_pixels: usize,
+    /// Both frames aligned by their anchor, added pixels are green, removed red, recoloured magenta and unchanged pixels faded
+    pub image: RgbaImage,
+}
+
+impl FrameDiff {
+    pub fn is_unchanged(&self) -> bool {
+        self.old_bounds == self.new_bounds && self.added_pixels == 0 && self.removed_pixels == 0 && self.changed_pixels == 0
+    }
+}
+
+impl AnimationDiff {
+    pub fn is_unchanged(&self) -> bool {
+        self.header_changes.is_empty() && self.frames.iter().all(FrameDiff::is_unchanged)
+    }
+}
+
+fn describe_bounds(bounds: &Option<FrameBounds>) -> String {
+    match bounds {
+        Some(bounds) => format!("{}x{} at ({}, {})", bounds.width, bounds.height, bounds.left, bounds.top),
+        None => "missing".to_string(),
+    }
+}
+
+impl fmt::Display for AnimationDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for change in self.header_changes.iter() {
+            writeln!(f, "{}", change)?;
+        }
+        for frame in self.frames.iter().filter(|frame| !frame.is_unchanged()) {
+            write!(f, "frame {}:", frame.index)?;
+            if frame.old_bounds != frame.new_bounds {
+                write!(f, " {} -> {},", describe_bounds(&frame.old_bounds), describe_bounds(&frame.new_bounds))?;
+            }
+            writeln!(
+                f,
+                " {} added, {} removed, {} recoloured pixels",
+                frame.added_pixels, frame.removed_pixels, frame.changed_pixels
+            )?;
+        }
+        let changed_frames = self.frames.iter().filter(|frame| !frame.is_unchanged()).count();
+        writeln!(f, "{} of {} frames changed", changed_frames, self.frames.len())
+    }
+}
+
+fn compare_field<T: PartialEq + fmt::Debug>(changes: &mut Vec<String>, field: &str, old: T, new: T) {
+    if old != new {
+        changes.push(format!("{}: {:?} -> {:?}", field, old, new));
+    }
+}
+
+/// Palettes are compared by the colours drawn rather than palette indices, so an animation moved to a new palette with the same colours is unchanged
+pub fn diff_animations(old: &Animation, old_palette: &Palette, new: &Animation, new_palette: &Palette) -> anyhow::Result<AnimationDiff> {
+    let mut header_changes = Vec::new();
+    compare_field(&mut header_changes, "header", old.header.is_some(), new.header.is_some());
+    compare_field(
+        &mut header_changes,
+        "extra frame",
+        old.header.as_ref().is_some_and(|header| header.extra_frame),
+        new.header.as_ref().is_some_and(|header| header.extra_frame),
+    );
+    compare_field(&mut header_changes, "animation speed", old.animation_speed, new.animation_speed);
+    compare_field(&mut header_changes, "palette", &old.palette_filename, &new.palette_filename);
+    compare_field(&mut header_changes, "frame count", old.frames.len(), new.frames.len());
+
+    let mut frames = Vec::with_capacity(old.frames.len().max(new.frames.len()));
+    for index in 0..old.frames.len().max(new.frames.len()) {
+        let frame_diff = diff_frames(index, old.frames.get(index), old_palette, new.frames.get(index), new_palette)
+            .with_context(|| format!("Error comparing frame {}", index))?;
+        frames.push(frame_diff);
+    }
+    Ok(AnimationDiff { header_changes, frames })
+}
+
+fn diff_frames(index: usize, old: Option<&Frame>, old_palette: &Palette, new: Option<&Frame>, new_palette: &Palette) -> anyhow::Result<FrameDiff> {
+    let old_bounds = old.map(FrameBounds::of);
+    let new_bounds = new.map(FrameBounds::of);
+    let bounds = match (old_bounds, new_bounds) {
+        (Some(old_bounds), Some(new_bounds)) => old_bounds.union(&new_bounds),
+        (Some(bounds), None) | (None, Some(bounds)) => bounds,
+        (None, None) => unreachable!("diff_frames needs at least one frame"),
+    };
+    let old_image = render_aligned(old, old_palette, &bounds)?;
+    let new_image = render_aligned(new, new_palette, &bounds)?;
+
+    let mut image = RgbaImage::new(bounds.width, bounds.height);
+    let (mut added_pixels, mut removed_pixels, mut changed_pixels) = (0, 0, 0);
+    for y in 0..bounds.height {
+        for x in 0..bounds.width {
+            let (Some(old_pixel), Some(new_pixel)) = (old_image.get_pixel(x, y), new_image.get_pixel(x, y)) else {
+                continue;
+            };
+            let pixel = match (old_pixel[3] != 0, new_pixel[3] != 0) {
+                (false, false) => continue,
+                (false, true) => {
+                    added_pixels += 1;
+                    ADDED_COLOR
+                }
+                (true, false) => {
+                    removed_pixels += 1;
+                    REMOVED_COLOR
+                }
+                (true, true) if old_pixel != new_pixel => {
+                    changed_pixels += 1;
+                    CHANGED_COLOR
+                }
+                (true, true) => [new_pixel[0], new_pixel[1], new_pixel[2], UNCHANGED_ALPHA],
+            };
+            image.put_pixel(x, y, pixel);
+        }
+    }
+    Ok(FrameDiff {
+        index,
+        old_bounds,
+        new_bounds,
+        added_pixels,
+        removed_pixels,
+        changed_pixels,
+        image,
+    })
+}
+
+// A missing frame renders as a fully transparent image
+fn render_aligned(frame: Option<&Frame>, palette: &Palette, bounds: &FrameBounds) -> anyhow::Result<RgbaImage> {
+    let mut image = RgbaImage::new(bounds.width, bounds.height);
+    if let Some(frame) = frame {
+        let frame_bounds = FrameBounds::of(frame);
+        image.blit(&render_frame(frame, palette)?, (frame_bounds.left - bounds.left) as u32, (frame_bounds.top - bounds.top) as u32);
+    }
+    Ok(image)
+}
+
+/// Writes the diff image of every changed frame as name_diff_<index>.png in directory, returns the paths written
+pub fn export_diff(diff: &AnimationDiff, directory: &Path, name: &str) -> anyhow::Result<Vec<PathBuf>> {
+    fs::create_dir_all(directory).with_context(|| format!("Error creating {}", directory.display()))?;
+    let mut paths = Vec::new();
+    for frame in diff.frames.iter().filter(|frame| !frame.is_unchanged()) {
+        let path = directory.join(format!("{}_diff_{}.png", name, frame.index));
+        fs::write(&path, frame.image.to_png()?).with_context(|| format!("Error writing {}", path.display()))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod parsing_tests {
+    use super::{diff_animations, ADDED_COLOR, CHANGED_COLOR, REMOVED_COLOR, UNCHANGED_ALPHA};
+    use crate::{animation::Animation, test_fixtures::test_animation};
+
+    // First opaque pixel of the first frame
+    fn first_pixel(animation: &Animation) -> (usize, usize) {
+        let pixels = animation.frames[0].to_pixels().unwrap();
+        pixels
+            .iter()
+            .enumerate()
+            .find_map(|(y, row)| row.iter().position(|pixel| pixel.is_some()).map(|x| (x, y)))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_diff_identical() {
+        let (animation, palette) = test_animation();
+        let diff = diff_animations(&animation, &palette, &animation, &palette).unwrap();
+        assert!(diff.is_unchanged());
+        assert_eq!(diff.frames.len(), animation.frames.len());
+        let image = &diff.frames[0].image;
+        assert!(image.pixels.chunks(4).all(|pixel| pixel[3] == 0 || pixel[3] == UNCHANGED_ALPHA));
+        assert_eq!(diff.to_string(), format!("0 of {} frames changed\n", animation.frames.len()));
+    }
+
+    #[test]
+    fn test_diff_header() {
+        let (animation, palette) = test_animation();
+        let mut new = animation.clone();
+        new.set_animation_speed(animation.animation_speed + 100);
+        new.set_palette_filename("other.pal".to_string());
+        let diff = diff_animations(&animation, &palette, &new, &palette).unwrap();
+        assert_eq!(diff.header_changes.len(), 2);
+        assert!(diff.header_changes[0].starts_with("animation speed"));
+        assert!(diff.header_changes[1].ends_with("-> \"other.pal\""));
+        assert!(diff.frames.iter().all(|frame| frame.is_unchanged()));
+    }
+
+    #[test]
+    fn test_diff_pixels() {
+        let (animation, palette) = test_animation();
+        let (x, y) = first_pixel(&animation);
+        let mut new = animation.clone();
+        let mut pixels = new.frames[0].to_pixels().unwrap();
+        let old_index = pixels[y][x].unwrap();
+        pixels[y][x] = Some(if old_index == 1 { 2 } else { 1 });
+        new.frames[0].set_pixels(&pixels).unwrap();
+
+        let diff = diff_animations(&animation, &palette, &new, &palette).unwrap();
+        let frame = &diff.frames[0];
+        assert_eq!((frame.added_pixels, frame.removed_pixels, frame.changed_pixels), (0, 0, 1));
+        assert_eq!(frame.image.get_pixel(x as u32, y as u32), Some(CHANGED_COLOR));
+        assert!(diff.frames[1..].iter().all(|frame| frame.is_unchanged()));
+    }
+
+    #[test]
+    fn test_diff_offsets() {
+        let (animation, palette) = test_animation();
+        let mut new = animation.clone();
+        new.shift_offsets(1, 0).unwrap();
+        let diff = diff_animations(&animation, &palette, &new, &palette).unwrap();
+        let frame = &diff.frames[0];
+        assert_ne!(frame.old_bounds, frame.new_bounds);
+        assert_eq!(frame.image.width, animation.frames[0].pixel_width as u32 + 1);
+        assert!(frame.added_pixels > 0 && frame.removed_pixels > 0);
+        assert!(frame.image.pixels.chunks(4).any(|pixel| pixel == ADDED_COLOR));
+        assert!(frame.image.pixels.chunks(4).any(|pixel| pixel == REMOVED_COLOR));
+    }
+
+    #[test]
+    fn test_diff_frame_count() {
+        let (animation, palette) = test_animation();
+        let mut new = animation.clone();
+        new.append_frames(&animation).unwrap();
+        let diff = diff_animations(&animation, &palette, &new, &palette).unwrap();
+        assert!(diff.header_changes.iter().any(|change| change.starts_with("frame count")));
+        assert_eq!(diff.frames.len(), new.frames.len());
+        let last = diff.frames.last().unwrap();
+        assert_eq!(last.old_bounds, None);
+        assert_eq!(last.removed_pixels, 0);
+        assert!(last.added_pixels > 0);
+        assert!(diff.to_string().contains(&format!("frame {}: missing -> ", last.index)));
+    }
+}
@@ -0,0 +1,17 @@
+//! Zoo Tycoon file formats, shared by the dll and the standalone animation and image tools
+#![feature(let_chains)]
+
+pub mod parsing;
+
+pub mod animation;
+
+pub mod animation_diff;
+
+pub mod palette;
+
+pub mod sprite;
+
+pub mod ui_image;
+
+#[cfg(test)]
+mod test_fixtures;
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+
+use crate::parsing::{read_le_primitive, write_le_primitive};
+
+/// Zoo Tycoon palettes (*.pal) are a u32 color count followed by 4 bytes per color
+#[derive(Clone, PartialEq, Debug)]
+pub struct Palette {
+    pub colors: Vec<Color>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(C)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+/// Animations index into their palette with a u8 so a palette can't usefully hold more colors than this
+pub const MAX_PALETTE_COLORS: usize = 256;
+
+impl Color {
+    pub fn new(r: u8, g: u8, b: u8, a: u8) -> Color {
+        Color { r, g, b, a }
+    }
+
+    // Squared distance in RGB space, alpha is ignored as ZT palettes are fully opaque apart from the transparent index
+    fn distance(&self, other: &Color) -> u32 {
+        let dr = self.r as i32 - other.r as i32;
+        let dg = self.g as i32 - other.g as i32;
+        let db = self.b as i32 - other.b as i32;
+        (dr * dr + dg * dg + db * db) as u32
+    }
+}
+
+impl Palette {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Palette> {
+        if data.len() < 4 {
+            return Err(anyhow!("Palette is too short to contain a color count ({} bytes)", data.len()));
+        }
+        let mut index = 0;
+        let num_colors: u32 = read_le_primitive(data, &mut index, "color count")?;
+        // Checked against the data before any size is computed, a corrupt count would overflow on 32 bit
+        if num_colors as usize > (data.len() - 4) / 4 {
+            return Err(anyhow!("Palette has {} colors but only has room for {} ({} bytes)", num_colors, (data.len() - 4) / 4, data.len()));
+        }
+
+        let mut colors = Vec::with_capacity(num_colors as usize);
+        for _ in 0..num_colors {
+            colors.push(Color {
+                r: read_le_primitive(data, &mut index, "color")?,
+                g: read_le_primitive(data, &mut index, "color")?,
+                b: read_le_primitive(data, &mut index, "color")?,
+                a: read_le_primitive(data, &mut index, "color")?,
+            });
+        }
+
+        Ok(Palette { colors })
+    }
+
+    pub fn get(&self, index: u8) -> Option<&Color> {
+        self.colors.get(index as usize)
+    }
+
+    /// Index of the first exact match for color
+    pub fn index_of(&self, color: &Color) -> Option<u8> {
+        self.colors.iter().position(|c| c == color).map(|index| index as u8)
+    }
+
+    /// Index of the closest color, exact matches are always preferred, ties go to the lowest index
+    pub fn nearest(&self, color: &Color) -> Option<u8> {
+        self.colors
+            .iter()
+            .take(MAX_PALETTE_COLORS)
+            .enumerate()
+            .min_by_key(|(_, c)| c.distance(color))
+            .map(|(index, _)| index as u8)
+    }
+
+    /// Maps every index in this palette to the index of the nearest color in target, used to move pixel data between palettes
+    pub fn remap_table(&self, target: &Palette) -> anyhow::Result<Vec<u8>> {
+        if target.colors.is_empty() {
+            return Err(anyhow!("Can't remap to an empty palette"));
+        }
+        Ok(self.colors.iter().map(|color| target.nearest(color).unwrap_or(0)).collect())
+    }
+
+    /// Replaces colors in place, colors not in the map are left alone
+    pub fn replace_colors(&mut self, replacements: &HashMap<Color, Color>) -> usize {
+        let mut replaced = 0;
+        for color in self.colors.iter_mut() {
+            if let Some(replacement) = replacements.get(color) {
+                *color = *replacement;
+                replaced += 1;
+            }
+        }
+        replaced
+    }
+
+    /// Adds the colors from other that aren't already in this palette, returns the new palette and a remap table for pixels using other.
+    /// Once the palette is full remaining colors are mapped to their nearest match
+    pub fn merge(&self, other: &Palette) -> (Palette, Vec<u8>) {
+        let mut merged = self.clone();
+        let mut remap_table = Vec::with_capacity(other.colors.len());
+        for color in other.colors.iter() {
+            let index = match merged.index_of(color) {
+                Some(index) => index,
+                None if merged.colors.len() < MAX_PALETTE_COLORS => {
+                    merged.colors.push(*color);
+                    (merged.colors.len() - 1) as u8
+                }
+                None => merged.nearest(color).unwrap_or(0),
+            };
+            remap_table.push(index);
+        }
+        (merged, remap_table)
+    }
+
+    pub fn write(self) -> (Vec<u8>, usize) {
+        let mut accumulator: usize = 0;
+        let mut bytes = Vec::with_capacity(4 + self.colors.len() * 4);
+        write_le_primitive(&mut bytes, self.colors.len() as u32, &mut accumulator);
+        for color in self.colors {
+            write_le_primitive(&mut bytes, color.r, &mut accumulator);
+            write_le_primitive(&mut bytes, color.g, &mut accumulator);
+            write_le_primitive(&mut bytes, color.b, &mut accumulator);
+            write_le_primitive(&mut bytes, color.a, &mut accumulator);
+        }
+        (bytes, accumulator)
+    }
+}
+
+#[cfg(test)]
+mod parsing_tests {
+    use std::collections::HashMap;
+
+    use super::{Color, Palette};
+
+    #[test]
+    fn test_parse_palette() {
+        let palette = Palette::parse(include_bytes!("../../../resources/test/ltb.pal")).unwrap();
+        assert_eq!(palette.colors.len(), 256);
+        assert_eq!(palette.colors[0].a, 0xff);
+    }
+
+    #[test]
+    fn test_parse_and_write() {
+        let bytes = include_bytes!("../../../resources/test/ltb.pal");
+        let palette = Palette::parse(bytes).unwrap();
+        let (palette_bytes, size) = palette.write();
+        assert_eq!(size, bytes.len());
+        assert_eq!(&palette_bytes[..], &bytes[..]);
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let bytes = include_bytes!("../../../resources/test/ltb.pal");
+        assert!(Palette::parse(&bytes[..100]).is_err());
+        assert!(Palette::parse(&bytes[..2]).is_err());
+    }
+
+    #[test]
+    fn test_parse_huge_color_count() {
+        let mut bytes = u32::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0xff; 8]);
+        assert!(Palette::parse(&bytes).is_err());
+        assert!(Palette::parse(&0x4000_0001u32.to_le_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let palette = Palette::parse(include_bytes!("../../../resources/test/ltb.pal")).unwrap();
+        let color = *palette.get(3).unwrap();
+        assert_eq!(color, Color::new(53, 102, 46, 255));
+        assert_eq!(palette.index_of(&color), Some(3));
+        assert_eq!(palette.index_of(&Color::new(1, 2, 3, 4)), None);
+        assert!(palette.get(255).is_some());
+    }
+
+    #[test]
+    fn test_nearest() {
+        let palette = Palette::parse(include_bytes!("../../../resources/test/ltb.pal")).unwrap();
+        for (index, color) in palette.colors.iter().enumerate() {
+            assert_eq!(palette.nearest(color), Some(index as u8));
+        }
+        let near_first = Color::new(45, 53, 42, 255);
+        assert_eq!(palette.nearest(&near_first), Some(0));
+        assert_eq!(Palette { colors: Vec::new() }.nearest(&near_first), None);
+    }
+
+    #[test]
+    fn test_remap_table() {
+        let palette = Palette::parse(include_bytes!("../../../resources/test/ltb.pal")).unwrap();
+        let table = palette.remap_table(&palette).unwrap();
+        assert_eq!(table, (0..=255).collect::<Vec<u8>>());
+
+        let small = Palette { colors: vec![palette.colors[10], palette.colors[20]] };
+        let table = small.remap_table(&palette).unwrap();
+        assert_eq!(table, vec![10, 20]);
+        assert!(palette.remap_table(&Palette { colors: Vec::new() }).is_err());
+    }
+
+    #[test]
+    fn test_replace_colors() {
+        let mut palette = Palette::parse(include_bytes!("../../../resources/test/ltb.pal")).unwrap();
+        let replacement = Color::new(255, 0, 255, 255);
+        let replacements = HashMap::from([(palette.colors[5], replacement)]);
+        assert_eq!(palette.replace_colors(&replacements), 1);
+        assert_eq!(palette.colors[5], replacement);
+    }
+
+    #[test]
+    fn test_merge() {
+        let palette = Palette::parse(include_bytes!("../../../resources/test/ltb.pal")).unwrap();
+        let base = Palette { colors: palette.colors[..4].to_vec() };
+        let other = Palette { colors: vec![palette.colors[2], palette.colors[100]] };
+        let (merged, table) = base.merge(&other);
+        assert_eq!(merged.colors.len(), 5);
+        assert_eq!(table, vec![2, 4]);
+        assert_eq!(merged.colors[4], palette.colors[100]);
+
+        // Full palettes map new colors to the nearest existing one
+        let (merged, table) = palette.merge(&Palette { colors: vec![Color::new(45, 53, 42, 255)] });
+        assert_eq!(merged.colors.len(), 256);
+        assert_eq!(table, vec![0]);
+    }
+}
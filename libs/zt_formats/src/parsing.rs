@@ -0,0 +1,163 @@
+use std::{error::Error, ffi::CString, fmt, mem};
+
+trait TypeSize {
+    const SIZE: usize;
+}
+
+macro_rules! impl_TypeSize (( $($int:ident),* ) => {
+    $(
+        impl TypeSize for $int {
+            const SIZE: usize = std::mem::size_of::<$int>();
+        }
+    )*
+});
+
+impl_TypeSize!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+pub trait EndianRead<TArray> {
+    fn from_le_bytes(bytes: TArray) -> Self;
+    fn from_be_bytes(bytes: TArray) -> Self;
+}
+
+pub trait EndianWrite {
+    fn to_le_bytes(self) -> Vec<u8>;
+    fn to_be_bytes(self) -> Vec<u8>;
+}
+
+macro_rules! impl_EndianRead_for_ints (( $($int:ident),* ) => {
+    $(
+        impl EndianRead<[u8; <$int as TypeSize>::SIZE]> for $int {
+            fn from_le_bytes(bytes: [u8; <$int as TypeSize>::SIZE]) -> Self { Self::from_le_bytes(bytes) }
+            fn from_be_bytes(bytes: [u8; <$int as TypeSize>::SIZE]) -> Self { Self::from_be_bytes(bytes) }
+        }
+    )*
+});
+
+macro_rules! impl_EndianWrite_for_ints (( $($int:ident),* ) => {
+    $(
+        impl EndianWrite for $int {
+            fn to_le_bytes(self) -> Vec<u8> { self.to_le_bytes().to_vec() }
+            fn to_be_bytes(self) -> Vec<u8> { self.to_be_bytes().to_vec() }
+        }
+    )*
+});
+
+impl_EndianRead_for_ints!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl_EndianWrite_for_ints!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl EndianRead<[u8; 1]> for bool {
+    fn from_le_bytes(bytes: [u8; 1]) -> Self {
+        bytes[0] != 0
+    }
+    fn from_be_bytes(bytes: [u8; 1]) -> Self {
+        bytes[0] != 0
+    }
+}
+
+impl EndianWrite for bool {
+    fn to_le_bytes(self) -> Vec<u8> {
+        vec![self as u8]
+    }
+    fn to_be_bytes(self) -> Vec<u8> {
+        vec![self as u8]
+    }
+}
+
+/// Where and why reading a binary file failed, offsets are from the start of the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl ParseError {
+    pub fn new(offset: usize, field: &str, expected: String, actual: String) -> ParseError {
+        ParseError {
+            offset,
+            field: field.to_string(),
+            expected,
+            actual,
+        }
+    }
+
+    // Fields are read with short names, callers add where in the file they were e.g. "frame 2 line 5 colors"
+    pub fn within(mut self, context: &str) -> ParseError {
+        self.field = format!("{} {}", context, self.field);
+        self
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Error reading {} at offset {:#x}: expected {}, found {}",
+            self.field, self.offset, self.expected, self.actual
+        )
+    }
+}
+
+impl Error for ParseError {}
+
+fn check_remaining(bytes: &[u8], index: usize, length: usize, field: &str) -> Result<(), ParseError> {
+    let remaining = bytes.len().saturating_sub(index);
+    if remaining < length {
+        return Err(ParseError::new(
+            index,
+            field,
+            format!("{} bytes", length),
+            format!("{} bytes before the end of the data", remaining),
+        ));
+    }
+    Ok(())
+}
+
+pub fn read_le_primitive<'a, T, TArray>(bytes: &'a [u8], index: &mut usize, field: &str) -> Result<T, ParseError>
+where
+    T: EndianRead<TArray>,
+    TArray: TryFrom<&'a [u8]>,
+    <TArray as TryFrom<&'a [u8]>>::Error: fmt::Debug,
+{
+    let value_bytes = read_bytes(bytes, index, mem::size_of::<T>(), field)?;
+    // The slice is always the size of T so this can't fail
+    Ok(T::from_le_bytes(value_bytes.try_into().unwrap()))
+}
+
+pub fn read_bytes<'a>(bytes: &'a [u8], index: &mut usize, length: usize, field: &str) -> Result<&'a [u8], ParseError> {
+    check_remaining(bytes, *index, length, field)?;
+    let value_bytes = &bytes[*index..*index + length];
+    *index += length;
+    Ok(value_bytes)
+}
+
+pub fn write_le_primitive<T: EndianWrite>(vec: &mut Vec<u8>, value: T, accumulator: &mut usize) {
+    *accumulator += mem::size_of::<T>();
+    vec.extend(value.to_le_bytes());
+}
+
+// Strings are nul terminated and length includes the nul
+pub fn read_string(bytes: &[u8], index: &mut usize, length: usize, field: &str) -> Result<String, ParseError> {
+    if length == 0 {
+        return Err(ParseError::new(
+            *index,
+            field,
+            "a length of at least 1 for the nul terminator".to_string(),
+            "0".to_string(),
+        ));
+    }
+    let start = *index;
+    let string_bytes = read_bytes(bytes, index, length, field)?;
+    String::from_utf8(string_bytes[..length - 1].to_vec())
+        .map_err(|e| ParseError::new(start, field, "a utf-8 string".to_string(), e.to_string()))
+}
+
+pub fn write_string(vec: &mut Vec<u8>, string: &str, accumulator: &mut usize) {
+    let length = string.len() + 1;
+    let c_string = CString::new(string).unwrap();
+    *accumulator += length;
+    write_le_primitive(vec, (length) as u32, accumulator);
+    vec.extend_from_slice(c_string.as_bytes_with_nul());
+}
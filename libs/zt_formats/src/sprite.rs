@@ -0,0 +1,649 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+
+use crate::{
+    animation::{Animation, Frame, Header, Line},
+    palette::{Color, Palette, MAX_PALETTE_COLORS},
+};
+
+const ANIMATION_HEADER: u32 = 0x5A544146;
+
+/// Pixels with less alpha than this are left out of imported frames, ZT has no partial transparency
+pub const ALPHA_THRESHOLD: u8 = 128;
+
+/// Animation speed used for imported animations when none is given
+pub const DEFAULT_ANIMATION_SPEED: u32 = 1000;
+
+/// 8 bit RGBA image, pixels are stored row by row with 4 bytes per pixel
+#[derive(Clone, PartialEq, Debug)]
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl RgbaImage {
+    /// Fully transparent image
+    pub fn new(width: u32, height: u32) -> RgbaImage {
+        RgbaImage {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize * 4],
+        }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Option<[u8; 4]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let index = (y as usize * self.width as usize + x as usize) * 4;
+        Some([self.pixels[index], self.pixels[index + 1], self.pixels[index + 2], self.pixels[index + 3]])
+    }
+
+    /// Pixels outside the image are ignored
+    pub fn put_pixel(&mut self, x: u32, y: u32, pixel: [u8; 4]) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let index = (y as usize * self.width as usize + x as usize) * 4;
+        self.pixels[index..index + 4].copy_from_slice(&pixel);
+    }
+
+    /// Copies other onto this image with its top left corner at (x, y), transparent pixels in other are skipped
+    pub fn blit(&mut self, other: &RgbaImage, x: u32, y: u32) {
+        for other_y in 0..other.height {
+            for other_x in 0..other.width {
+                if let Some(pixel) = other.get_pixel(other_x, other_y)
+                    && pixel[3] != 0
+                {
+                    self.put_pixel(x + other_x, y + other_y, pixel);
+                }
+            }
+        }
+    }
+
+    /// Decodes any 8 or 16 bit png into RGBA, paletted and greyscale images are expanded
+    pub fn from_png(data: &[u8]) -> anyhow::Result<RgbaImage> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().with_context(|| "Error reading png header")?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).with_context(|| "Error reading png data")?;
+        let bytes = &buffer[..info.buffer_size()];
+        let pixels = match info.color_type {
+            png::ColorType::Rgba => bytes.to_vec(),
+            png::ColorType::Rgb => bytes.chunks(3).flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 0xff]).collect(),
+            png::ColorType::GrayscaleAlpha => bytes.chunks(2).flat_map(|ga| [ga[0], ga[0], ga[0], ga[1]]).collect(),
+            png::ColorType::Grayscale => bytes.iter().flat_map(|g| [*g, *g, *g, 0xff]).collect(),
+            png::ColorType::Indexed => return Err(anyhow!("Paletted png wasn't expanded")),
+        };
+        Ok(RgbaImage {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    }
+
+    fn is_opaque(&self, x: u32, y: u32) -> bool {
+        self.get_pixel(x, y).is_some_and(|pixel| pixel[3] >= ALPHA_THRESHOLD)
+    }
+
+    // Smallest rectangle containing every opaque pixel as (x, y, width, height), None if the image is fully transparent
+    fn opaque_bounds(&self) -> Option<(u32, u32, u32, u32)> {
+        let mut bounds: Option<(u32, u32, u32, u32)> = None;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if !self.is_opaque(x, y) {
+                    continue;
+                }
+                bounds = Some(match bounds {
+                    Some((min_x, min_y, max_x, max_y)) => (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)),
+                    None => (x, y, x, y),
+                });
+            }
+        }
+        bounds.map(|(min_x, min_y, max_x, max_y)| (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+    }
+
+    pub fn to_png(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, self.width, self.height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().with_context(|| "Error writing png header")?;
+            writer.write_image_data(&self.pixels).with_context(|| "Error writing png data")?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Where a frame sits relative to the animation's anchor point, the anchor is what the game positions on screen
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+pub struct FrameBounds {
+    pub left: i32,
+    pub top: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FrameBounds {
+    // Offsets are stored as u16 but are signed, they are the distance from the frame's top left corner to the anchor
+    pub fn of(frame: &Frame) -> FrameBounds {
+        FrameBounds {
+            left: -(frame.horizontal_offset_x as i16 as i32),
+            top: -(frame.vertical_offset_y as i16 as i32),
+            width: frame.pixel_width as u32,
+            height: frame.pixel_height as u32,
+        }
+    }
+
+    /// Bounds covering every frame, None if the animation has no frames
+    pub fn of_animation(animation: &Animation) -> Option<FrameBounds> {
+        animation.frames.iter().map(FrameBounds::of).reduce(|a, b| a.union(&b))
+    }
+
+    pub fn right(&self) -> i32 {
+        self.left + self.width as i32
+    }
+
+    pub fn bottom(&self) -> i32 {
+        self.top + self.height as i32
+    }
+
+    pub fn union(&self, other: &FrameBounds) -> FrameBounds {
+        let left = self.left.min(other.left);
+        let top = self.top.min(other.top);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        FrameBounds {
+            left,
+            top,
+            width: (right - left) as u32,
+            height: (bottom - top) as u32,
+        }
+    }
+}
+
+/// Decodes a frame's draw instructions into an image the size of the frame, pixels not drawn by any instruction are transparent.
+/// Each line is a list of runs, a run skips `offset` pixels and then draws `num_colors` palette indices
+pub fn render_frame(frame: &Frame, palette: &Palette) -> anyhow::Result<RgbaImage> {
+    let mut image = RgbaImage::new(frame.pixel_width as u32, frame.pixel_height as u32);
+    for (y, line) in frame.lines.iter().enumerate() {
+        let mut x = 0u32;
+        for draw_instruction in line.draw_instructions.iter() {
+            x += draw_instruction.offset as u32;
+            for color_index in draw_instruction.colors.iter() {
+                let color = palette
+                    .get(*color_index)
+                    .ok_or_else(|| anyhow!("Color index {} at ({}, {}) is outside the palette ({} colors)", color_index, x, y, palette.colors.len()))?;
+                // Palettes store alpha but the game treats every drawn pixel as opaque
+                image.put_pixel(x, y as u32, [color.r, color.g, color.b, 0xff]);
+                x += 1;
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// Renders every frame onto a canvas big enough for all of them, with each frame placed by its offsets so the frames line up when played back
+pub fn render_animation(animation: &Animation, palette: &Palette) -> anyhow::Result<(FrameBounds, Vec<RgbaImage>)> {
+    let Some(bounds) = FrameBounds::of_animation(animation) else {
+        return Err(anyhow!("Animation has no frames"));
+    };
+    let mut images = Vec::with_capacity(animation.frames.len());
+    for (index, frame) in animation.frames.iter().enumerate() {
+        let frame_image = render_frame(frame, palette).with_context(|| format!("Error rendering frame {}", index))?;
+        let frame_bounds = FrameBounds::of(frame);
+        let mut image = RgbaImage::new(bounds.width, bounds.height);
+        image.blit(&frame_image, (frame_bounds.left - bounds.left) as u32, (frame_bounds.top - bounds.top) as u32);
+        images.push(image);
+    }
+    Ok((bounds, images))
+}
+
+#[derive(Serialize)]
+pub struct SpriteSheetMetadata {
+    pub palette: String,
+    pub animation_speed: u32,
+    pub num_frames: u32,
+    pub has_extra_frame: bool,
+    /// Position of the anchor within each frame cell
+    pub anchor_x: i32,
+    pub anchor_y: i32,
+    pub frame_width: u32,
+    pub frame_height: u32,
+    pub frames: Vec<SpriteSheetFrame>,
+}
+
+#[derive(Serialize)]
+pub struct SpriteSheetFrame {
+    pub index: usize,
+    pub x: u32,
+    pub y: u32,
+    /// The frame's own size and position relative to the anchor as stored in the animation
+    pub bounds: FrameBounds,
+}
+
+/// Lays the aligned frames out left to right in a grid that is as close to square as possible
+pub fn sprite_sheet(animation: &Animation, palette: &Palette) -> anyhow::Result<(RgbaImage, SpriteSheetMetadata)> {
+    let (bounds, images) = render_animation(animation, palette)?;
+    let columns = (images.len() as f64).sqrt().ceil().max(1.0) as u32;
+    let rows = (images.len() as u32).div_ceil(columns);
+    let mut sheet = RgbaImage::new(bounds.width * columns, bounds.height * rows);
+    let mut frames = Vec::with_capacity(images.len());
+    for (index, (image, frame)) in images.iter().zip(animation.frames.iter()).enumerate() {
+        let x = (index as u32 % columns) * bounds.width;
+        let y = (index as u32 / columns) * bounds.height;
+        sheet.blit(image, x, y);
+        frames.push(SpriteSheetFrame {
+            index,
+            x,
+            y,
+            bounds: FrameBounds::of(frame),
+        });
+    }
+    let metadata = SpriteSheetMetadata {
+        palette: animation.palette_filename.clone(),
+        animation_speed: animation.animation_speed,
+        num_frames: animation.num_frames,
+        has_extra_frame: animation.header.as_ref().is_some_and(|header| header.extra_frame),
+        anchor_x: -bounds.left,
+        anchor_y: -bounds.top,
+        frame_width: bounds.width,
+        frame_height: bounds.height,
+        frames,
+    };
+    Ok((sheet, metadata))
+}
+
+/// Builds a palette from the opaque colors used in images, if there are more than max_colors they are reduced with median cut
+pub fn generate_palette(images: &[RgbaImage], max_colors: usize) -> Palette {
+    let mut counts: HashMap<Color, u32> = HashMap::new();
+    for image in images.iter() {
+        for pixel in image.pixels.chunks(4).filter(|pixel| pixel[3] >= ALPHA_THRESHOLD) {
+            *counts.entry(Color::new(pixel[0], pixel[1], pixel[2], 0xff)).or_default() += 1;
+        }
+    }
+    let mut colors = counts.into_iter().collect::<Vec<(Color, u32)>>();
+    // Sorted so the same images always produce the same palette
+    colors.sort_by_key(|(color, _)| (color.r, color.g, color.b));
+    let max_colors = max_colors.clamp(1, MAX_PALETTE_COLORS);
+    if colors.len() <= max_colors {
+        return Palette {
+            colors: colors.into_iter().map(|(color, _)| color).collect(),
+        };
+    }
+
+    let mut boxes = vec![colors];
+    while boxes.len() < max_colors {
+        // Split the box with the widest channel range, boxes with one color can't be split further
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(index, colors)| {
+                let (channel, range) = widest_channel(colors);
+                (index, channel, range)
+            })
+            .max_by_key(|(_, _, range)| *range)
+            .map(|(index, channel, _)| (index, channel))
+        else {
+            break;
+        };
+        let mut colors = boxes.swap_remove(index);
+        colors.sort_by_key(|(color, _)| channel_value(color, channel));
+        let upper = colors.split_off(colors.len() / 2);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    let mut palette_colors = boxes.iter().map(|colors| average_color(colors)).collect::<Vec<Color>>();
+    palette_colors.sort_by_key(|color| (color.r, color.g, color.b));
+    palette_colors.dedup();
+    Palette { colors: palette_colors }
+}
+
+fn channel_value(color: &Color, channel: usize) -> u8 {
+    match channel {
+        0 => color.r,
+        1 => color.g,
+        _ => color.b,
+    }
+}
+
+fn widest_channel(colors: &[(Color, u32)]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let values = colors.iter().map(|(color, _)| channel_value(color, channel));
+            let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+            (channel, range)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+// Weighted by how often each color is used
+fn average_color(colors: &[(Color, u32)]) -> Color {
+    let total = colors.iter().map(|(_, count)| *count as u64).sum::<u64>().max(1);
+    let channel_average = |channel: usize| (colors.iter().map(|(color, count)| channel_value(color, channel) as u64 * *count as u64).sum::<u64>() / total) as u8;
+    Color::new(channel_average(0), channel_average(1), channel_average(2), 0xff)
+}
+
+/// Encodes an image as a frame, transparent borders are cropped and the offsets are set so (anchor_x, anchor_y) in the image is the anchor.
+/// Colors are quantised to the nearest palette entry
+pub fn encode_frame(image: &RgbaImage, palette: &Palette, anchor_x: i32, anchor_y: i32) -> anyhow::Result<Frame> {
+    if palette.colors.is_empty() {
+        return Err(anyhow!("Can't encode a frame with an empty palette"));
+    }
+    let (crop_x, crop_y, width, height) = image.opaque_bounds().unwrap_or((0, 0, 0, 0));
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(anyhow!("Frame is too large ({}x{})", width, height));
+    }
+
+    let mut nearest_cache: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut lines = Vec::with_capacity(height as usize);
+    for y in crop_y..crop_y + height {
+        let row = (crop_x..crop_x + width)
+            .map(|x| {
+                let pixel = image.get_pixel(x, y).unwrap_or_default();
+                if pixel[3] < ALPHA_THRESHOLD {
+                    return None;
+                }
+                Some(
+                    *nearest_cache
+                        .entry([pixel[0], pixel[1], pixel[2]])
+                        .or_insert_with(|| palette.nearest(&Color::new(pixel[0], pixel[1], pixel[2], 0xff)).unwrap_or(0)),
+                )
+            })
+            .collect::<Vec<Option<u8>>>();
+        lines.push(Line::from_pixels(&row).map_err(|e| anyhow!("Error encoding line {}: {}", y - crop_y, e))?);
+    }
+
+    let mut frame = Frame {
+        num_bytes: 0,
+        pixel_height: height as u16,
+        pixel_width: width as u16,
+        vertical_offset_y: (anchor_y - crop_y as i32) as i16 as u16,
+        horizontal_offset_x: (anchor_x - crop_x as i32) as i16 as u16,
+        mystery_u16: 0,
+        lines,
+    };
+    frame.num_bytes = frame.calc_byte_size() as u32;
+    Ok(frame)
+}
+
+/// Builds an animation from frames that share an anchor point, the palette is referenced by palette_filename so it must be added as a resource separately
+pub fn images_to_animation(
+    images: &[RgbaImage],
+    palette: &Palette,
+    palette_filename: String,
+    anchor_x: i32,
+    anchor_y: i32,
+    animation_speed: u32,
+) -> anyhow::Result<Animation> {
+    if images.is_empty() {
+        return Err(anyhow!("Can't create an animation without frames"));
+    }
+    let mut frames = Vec::with_capacity(images.len());
+    for (index, image) in images.iter().enumerate() {
+        frames.push(encode_frame(image, palette, anchor_x, anchor_y).with_context(|| format!("Error encoding frame {}", index))?);
+    }
+    let mut animation = Animation {
+        header: Some(Header {
+            ztaf_string: ANIMATION_HEADER,
+            empty_4_bytes: 0,
+            extra_frame: false,
+        }),
+        animation_speed,
+        palette_filename_length: 0,
+        palette_filename: String::new(),
+        num_frames: frames.len() as u32,
+        frames,
+    };
+    animation.set_palette_filename(palette_filename);
+    Ok(animation)
+}
+
+// The anchor is the centre of the image which is where ZT expects UI icons to be anchored
+fn png_anchor(image: &RgbaImage) -> (i32, i32) {
+    ((image.width / 2) as i32, (image.height / 2) as i32)
+}
+
+/// Converts a single PNG into a one frame animation and a palette generated from its colors
+pub fn png_to_animation(png: &[u8], palette_filename: String) -> anyhow::Result<(Animation, Palette)> {
+    let image = RgbaImage::from_png(png)?;
+    let palette = generate_palette(std::slice::from_ref(&image), MAX_PALETTE_COLORS);
+    let (anchor_x, anchor_y) = png_anchor(&image);
+    let animation = images_to_animation(std::slice::from_ref(&image), &palette, palette_filename, anchor_x, anchor_y, DEFAULT_ANIMATION_SPEED)?;
+    Ok((animation, palette))
+}
+
+/// The bounds of the frame png_to_animation would make, without the cost of generating a palette
+pub fn png_bounds(png: &[u8]) -> anyhow::Result<FrameBounds> {
+    let image = RgbaImage::from_png(png)?;
+    let (anchor_x, anchor_y) = png_anchor(&image);
+    let (crop_x, crop_y, width, height) = image.opaque_bounds().unwrap_or((0, 0, 0, 0));
+    Ok(FrameBounds {
+        left: crop_x as i32 - anchor_x,
+        top: crop_y as i32 - anchor_y,
+        width,
+        height,
+    })
+}
+
+/// Writes each aligned frame as name_<index>.png in directory, returns the paths written
+pub fn export_frames(animation: &Animation, palette: &Palette, directory: &Path, name: &str) -> anyhow::Result<Vec<PathBuf>> {
+    fs::create_dir_all(directory).with_context(|| format!("Error creating {}", directory.display()))?;
+    let (_, images) = render_animation(animation, palette)?;
+    let mut paths = Vec::with_capacity(images.len());
+    for (index, image) in images.iter().enumerate() {
+        let path = directory.join(format!("{}_{}.png", name, index));
+        fs::write(&path, image.to_png()?).with_context(|| format!("Error writing {}", path.display()))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Writes the sprite sheet as name.png along with its metadata as name.json, returns the paths written
+pub fn export_sprite_sheet(animation: &Animation, palette: &Palette, directory: &Path, name: &str) -> anyhow::Result<Vec<PathBuf>> {
+    fs::create_dir_all(directory).with_context(|| format!("Error creating {}", directory.display()))?;
+    let (sheet, metadata) = sprite_sheet(animation, palette)?;
+    let png_path = directory.join(format!("{}.png", name));
+    fs::write(&png_path, sheet.to_png()?).with_context(|| format!("Error writing {}", png_path.display()))?;
+    let json_path = directory.join(format!("{}.json", name));
+    fs::write(&json_path, serde_json::to_string_pretty(&metadata)?).with_context(|| format!("Error writing {}", json_path.display()))?;
+    Ok(vec![png_path, json_path])
+}
+
+/// Resource paths can't be used as file names directly, e.g. animals/lion/m/walk/n becomes animals_lion_m_walk_n
+pub fn export_name(resource_path: &str) -> String {
+    resource_path.replace(['/', '\\'], "_").replace('.', "_")
+}
+
+#[cfg(test)]
+mod parsing_tests {
+    use super::{
+        encode_frame, generate_palette, images_to_animation, png_bounds, png_to_animation, render_animation, render_frame, sprite_sheet, FrameBounds,
+        RgbaImage,
+    };
+    use crate::{animation::Animation, palette::Palette, test_fixtures::test_animation};
+
+    #[test]
+    fn test_render_frame() {
+        let (animation, palette) = test_animation();
+        let frame = &animation.frames[0];
+        let image = render_frame(frame, &palette).unwrap();
+        assert_eq!(image.width, frame.pixel_width as u32);
+        assert_eq!(image.height, frame.pixel_height as u32);
+
+        // Every drawn pixel is opaque and the rest are transparent
+        let drawn = frame
+            .lines
+            .iter()
+            .flat_map(|line| line.draw_instructions.iter())
+            .map(|draw_instruction| draw_instruction.colors.len())
+            .sum::<usize>();
+        let opaque = image.pixels.chunks(4).filter(|pixel| pixel[3] == 0xff).count();
+        assert_eq!(opaque, drawn);
+    }
+
+    #[test]
+    fn test_render_frame_first_run() {
+        let (animation, palette) = test_animation();
+        let frame = &animation.frames[0];
+        let image = render_frame(frame, &palette).unwrap();
+        let (y, line) = frame.lines.iter().enumerate().find(|(_, line)| !line.draw_instructions.is_empty()).unwrap();
+        let draw_instruction = &line.draw_instructions[0];
+        let color = palette.get(draw_instruction.colors[0]).unwrap();
+        assert_eq!(
+            image.get_pixel(draw_instruction.offset as u32, y as u32),
+            Some([color.r, color.g, color.b, 0xff])
+        );
+    }
+
+    #[test]
+    fn test_render_frame_small_palette() {
+        let (animation, _) = test_animation();
+        assert!(render_frame(&animation.frames[0], &Palette { colors: Vec::new() }).is_err());
+    }
+
+    #[test]
+    fn test_render_animation_alignment() {
+        let (mut animation, palette) = test_animation();
+        let mut second_frame = animation.frames[0].clone();
+        second_frame.horizontal_offset_x += 10;
+        animation.frames.push(second_frame);
+        animation.num_frames = 2;
+
+        let (bounds, images) = render_animation(&animation, &palette).unwrap();
+        assert_eq!(bounds.width, animation.frames[0].pixel_width as u32 + 10);
+        assert_eq!(bounds.left, FrameBounds::of(&animation.frames[1]).left);
+        assert!(images.iter().all(|image| image.width == bounds.width && image.height == bounds.height));
+    }
+
+    #[test]
+    fn test_sprite_sheet() {
+        let (animation, palette) = test_animation();
+        let (sheet, metadata) = sprite_sheet(&animation, &palette).unwrap();
+        assert_eq!(metadata.frames.len(), 1);
+        assert_eq!(sheet.width, metadata.frame_width);
+        assert_eq!(metadata.anchor_x, animation.frames[0].horizontal_offset_x as i32);
+        assert_eq!(metadata.anchor_y, animation.frames[0].vertical_offset_y as i32);
+
+        let png = sheet.to_png().unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+        assert!(serde_json::to_string(&metadata).unwrap().contains("\"palette\":\"ui/sharedui/listbk/ltb.pal\""));
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let (animation, palette) = test_animation();
+        let image = render_frame(&animation.frames[0], &palette).unwrap();
+        let decoded = RgbaImage::from_png(&image.to_png().unwrap()).unwrap();
+        assert_eq!(image, decoded);
+    }
+
+    #[test]
+    fn test_encode_frame_round_trip() {
+        let (animation, palette) = test_animation();
+        let frame = &animation.frames[0];
+        let image = render_frame(frame, &palette).unwrap();
+        let encoded = encode_frame(&image, &palette, frame.horizontal_offset_x as i32, frame.vertical_offset_y as i32).unwrap();
+        assert_eq!(encoded.horizontal_offset_x, frame.horizontal_offset_x);
+        assert_eq!(encoded.vertical_offset_y, frame.vertical_offset_y);
+        assert_eq!(encoded.num_bytes, encoded.calc_byte_size() as u32);
+        assert_eq!(render_frame(&encoded, &palette).unwrap(), image);
+    }
+
+    #[test]
+    fn test_encode_frame_crops_and_splits_runs() {
+        let palette = Palette {
+            colors: vec![crate::palette::Color::new(255, 0, 0, 255)],
+        };
+        let mut image = RgbaImage::new(700, 3);
+        for x in 300..600 {
+            image.put_pixel(x, 1, [250, 0, 0, 255]);
+        }
+        let frame = encode_frame(&image, &palette, 350, 2).unwrap();
+        assert_eq!(frame.pixel_width, 300);
+        assert_eq!(frame.pixel_height, 1);
+        assert_eq!(frame.horizontal_offset_x, 50);
+        assert_eq!(frame.vertical_offset_y, 1);
+        let runs = &frame.lines[0].draw_instructions;
+        assert_eq!(runs.iter().map(|run| run.colors.len()).collect::<Vec<usize>>(), vec![255, 45]);
+
+        // Gaps longer than a run can skip
+        let mut image = RgbaImage::new(400, 1);
+        image.put_pixel(0, 0, [255, 0, 0, 255]);
+        image.put_pixel(399, 0, [255, 0, 0, 255]);
+        let frame = encode_frame(&image, &palette, 0, 0).unwrap();
+        let runs = &frame.lines[0].draw_instructions;
+        assert_eq!(runs.iter().map(|run| (run.offset, run.colors.len())).collect::<Vec<(u8, usize)>>(), vec![(0, 1), (255, 0), (143, 1)]);
+        let decoded = render_frame(&frame, &palette).unwrap();
+        assert_eq!(decoded.get_pixel(399, 0), Some([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_images_to_animation() {
+        let (animation, palette) = test_animation();
+        let (_, images) = render_animation(&animation, &palette).unwrap();
+        let frame = &animation.frames[0];
+        let imported = images_to_animation(
+            &images,
+            &palette,
+            animation.palette_filename.clone(),
+            frame.horizontal_offset_x as i32,
+            frame.vertical_offset_y as i32,
+            animation.animation_speed,
+        )
+        .unwrap();
+        let (bytes, _) = imported.clone().write();
+        let parsed = Animation::parse(&bytes).unwrap();
+        assert_eq!(parsed, imported);
+        assert_eq!(parsed.palette_filename, animation.palette_filename);
+        assert_eq!(render_animation(&parsed, &palette).unwrap().1, images);
+        assert!(images_to_animation(&[], &palette, String::new(), 0, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_png_to_animation() {
+        let mut image = RgbaImage::new(10, 8);
+        for x in 3..7 {
+            image.put_pixel(x, 5, [0xff, 0, 0, 0xff]);
+            image.put_pixel(x, 6, [0, 0, 0xff, 0xff]);
+        }
+        let (converted, palette) = png_to_animation(&image.to_png().unwrap(), "generated.pal".to_string()).unwrap();
+        assert_eq!(converted.palette_filename, "generated.pal");
+        assert_eq!(converted.frames.len(), 1);
+        assert_eq!(palette.colors.len(), 2);
+
+        // Cropped to the opaque pixels and anchored at the centre of the whole image
+        let frame = &converted.frames[0];
+        assert_eq!(FrameBounds::of(frame), FrameBounds { left: -2, top: 1, width: 4, height: 2 });
+        let rendered = render_frame(frame, &palette).unwrap();
+        assert_eq!(rendered.get_pixel(0, 0), Some([0xff, 0, 0, 0xff]));
+        assert_eq!(rendered.get_pixel(3, 1), Some([0, 0, 0xff, 0xff]));
+        assert_eq!(png_bounds(&image.to_png().unwrap()).unwrap(), FrameBounds::of(frame));
+        assert!(png_to_animation(b"not a png", String::new()).is_err());
+    }
+
+    #[test]
+    fn test_generate_palette() {
+        let (animation, palette) = test_animation();
+        let image = render_frame(&animation.frames[0], &palette).unwrap();
+        let generated = generate_palette(std::slice::from_ref(&image), 256);
+        assert!(!generated.colors.is_empty() && generated.colors.len() <= 256);
+
+        let reduced = generate_palette(std::slice::from_ref(&image), 8);
+        assert!(reduced.colors.len() <= 8);
+        let encoded = encode_frame(&image, &reduced, 0, 0).unwrap();
+        assert_eq!(encoded.pixel_width as u32, image.width);
+    }
+}
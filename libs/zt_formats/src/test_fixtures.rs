@@ -0,0 +1,9 @@
+use crate::{animation::Animation, palette::Palette};
+
+/// An animation from resources/test with the palette it uses
+pub fn test_animation() -> (Animation, Palette) {
+    (
+        Animation::parse(include_bytes!("../../../resources/test/N-noheader")).unwrap(),
+        Palette::parse(include_bytes!("../../../resources/test/ltb.pal")).unwrap(),
+    )
+}
@@ -0,0 +1,621 @@
+use std::{collections::HashMap, fmt};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    parsing::{read_bytes, read_le_primitive, write_le_primitive},
+    sprite::RgbaImage,
+};
+
+const TGA_HEADER_SIZE: usize = 18;
+// Image descriptor bits
+const TGA_ALPHA_BITS_MASK: u8 = 0x0f;
+const TGA_RIGHT_TO_LEFT: u8 = 0x10;
+const TGA_TOP_TO_BOTTOM: u8 = 0x20;
+// RLE packet header bit, the rest of the byte is the pixel count - 1
+const TGA_RLE_RUN: u8 = 0x80;
+const TGA_MAX_PACKET_LENGTH: usize = 128;
+
+const BMP_SIGNATURE: &[u8; 2] = b"BM";
+const BMP_FILE_HEADER_SIZE: usize = 14;
+const BMP_INFO_HEADER_SIZE: u32 = 40;
+const BMP_COMPRESSION_RGB: u32 = 0;
+
+/// How a UI image is stored, parsed images remember their format so patched images can be written back the way the game expects
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UiImageFormat {
+    /// Truecolor TGA, 24 or 32 bits per pixel, optionally run length encoded
+    Tga { bits_per_pixel: u8, rle: bool },
+    /// Uncompressed BMP, 8 bit paletted or 24 bit
+    Bmp { bits_per_pixel: u16 },
+}
+
+impl UiImageFormat {
+    /// Format used for new images, 32 bit TGA keeps transparency, BMPs are written as 24 bit so any number of colors fit
+    pub fn default_for(file_name: &str) -> Option<UiImageFormat> {
+        let lowercase = file_name.to_ascii_lowercase();
+        if lowercase.ends_with(".tga") {
+            Some(UiImageFormat::Tga { bits_per_pixel: 32, rle: false })
+        } else if lowercase.ends_with(".bmp") {
+            Some(UiImageFormat::Bmp { bits_per_pixel: 24 })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for UiImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UiImageFormat::Tga { bits_per_pixel, rle: true } => write!(f, "{} bit RLE TGA", bits_per_pixel),
+            UiImageFormat::Tga { bits_per_pixel, rle: false } => write!(f, "{} bit TGA", bits_per_pixel),
+            UiImageFormat::Bmp { bits_per_pixel } => write!(f, "{} bit BMP", bits_per_pixel),
+        }
+    }
+}
+
+/// A TGA or BMP used for UI art, decoded to RGBA
+#[derive(Clone, PartialEq, Debug)]
+pub struct UiImage {
+    pub image: RgbaImage,
+    pub format: UiImageFormat,
+}
+
+impl UiImage {
+    /// Reads TGA types 1, 2, 3 and their RLE versions (9, 10, 11) with 8, 16, 24 or 32 bits per pixel
+    pub fn parse_tga(data: &[u8]) -> anyhow::Result<UiImage> {
+        let mut index = 0;
+        let id_length: u8 = read_le_primitive(data, &mut index, "id length")?;
+        let color_map_type: u8 = read_le_primitive(data, &mut index, "color map type")?;
+        let image_type: u8 = read_le_primitive(data, &mut index, "image type")?;
+        let color_map_first: u16 = read_le_primitive(data, &mut index, "color map start")?;
+        let color_map_length: u16 = read_le_primitive(data, &mut index, "color map length")?;
+        let color_map_entry_size: u8 = read_le_primitive(data, &mut index, "color map entry size")?;
+        index += 4; // x and y origin, only used for positioning on screen
+        let width: u16 = read_le_primitive(data, &mut index, "width")?;
+        let height: u16 = read_le_primitive(data, &mut index, "height")?;
+        let bits_per_pixel: u8 = read_le_primitive(data, &mut index, "bits per pixel")?;
+        let descriptor: u8 = read_le_primitive(data, &mut index, "image descriptor")?;
+        read_bytes(data, &mut index, id_length as usize, "image id")?;
+
+        let rle = image_type & 0x08 != 0;
+        let has_alpha = descriptor & TGA_ALPHA_BITS_MASK != 0;
+        let color_map = match color_map_type {
+            0 => Vec::new(),
+            1 => {
+                let entry_bytes = (color_map_entry_size as usize).div_ceil(8);
+                let mut color_map = Vec::with_capacity(color_map_length as usize);
+                for _ in 0..color_map_length {
+                    let entry = read_bytes(data, &mut index, entry_bytes, "color map entry")?;
+                    color_map.push(tga_pixel(entry, true)?);
+                }
+                color_map
+            }
+            _ => return Err(anyhow!("Unsupported TGA color map type {}", color_map_type)),
+        };
+        let pixel_kind = match image_type & !0x08 {
+            1 => TgaPixelKind::ColorMapped,
+            2 => TgaPixelKind::TrueColor,
+            3 => TgaPixelKind::Grey,
+            _ => return Err(anyhow!("Unsupported TGA image type {}", image_type)),
+        };
+        let bytes_per_pixel = (bits_per_pixel as usize).div_ceil(8);
+        if bytes_per_pixel == 0 || bytes_per_pixel > 4 {
+            return Err(anyhow!("Unsupported TGA bits per pixel {}", bits_per_pixel));
+        }
+
+        // The size comes from the header so it's checked against what the data can hold before allocating,
+        // an RLE packet is at least 1 + bytes_per_pixel bytes and holds at most 128 pixels
+        let remaining = data.len().saturating_sub(index);
+        let max_pixels = if rle { remaining / (1 + bytes_per_pixel) * TGA_MAX_PACKET_LENGTH } else { remaining / bytes_per_pixel };
+        let pixel_count = (width as usize)
+            .checked_mul(height as usize)
+            .filter(|pixel_count| *pixel_count <= max_pixels)
+            .ok_or_else(|| anyhow!("TGA size {}x{} is larger than its {} bytes of pixel data allow", width, height, remaining))?;
+        let raw_pixels = if rle {
+            let mut raw_pixels = Vec::with_capacity(pixel_count * bytes_per_pixel);
+            while raw_pixels.len() < pixel_count * bytes_per_pixel {
+                let packet: u8 = read_le_primitive(data, &mut index, "rle packet")?;
+                let count = (packet & !TGA_RLE_RUN) as usize + 1;
+                if packet & TGA_RLE_RUN != 0 {
+                    let pixel = read_bytes(data, &mut index, bytes_per_pixel, "rle pixel")?;
+                    for _ in 0..count {
+                        raw_pixels.extend_from_slice(pixel);
+                    }
+                } else {
+                    raw_pixels.extend_from_slice(read_bytes(data, &mut index, count * bytes_per_pixel, "rle pixels")?);
+                }
+            }
+            raw_pixels.truncate(pixel_count * bytes_per_pixel);
+            raw_pixels
+        } else {
+            read_bytes(data, &mut index, pixel_count * bytes_per_pixel, "pixels")?.to_vec()
+        };
+
+        let mut image = RgbaImage::new(width as u32, height as u32);
+        for (pixel_index, raw_pixel) in raw_pixels.chunks(bytes_per_pixel).enumerate() {
+            let pixel = match pixel_kind {
+                TgaPixelKind::TrueColor => tga_pixel(raw_pixel, has_alpha)?,
+                TgaPixelKind::Grey => [raw_pixel[0], raw_pixel[0], raw_pixel[0], 0xff],
+                TgaPixelKind::ColorMapped => {
+                    let color_index = raw_pixel.iter().rev().fold(0usize, |value, byte| (value << 8) | *byte as usize);
+                    *color_index
+                        .checked_sub(color_map_first as usize)
+                        .and_then(|color_index| color_map.get(color_index))
+                        .ok_or_else(|| anyhow!("TGA color index {} is outside the color map", color_index))?
+                }
+            };
+            let mut x = (pixel_index % width as usize) as u32;
+            let mut y = (pixel_index / width as usize) as u32;
+            if descriptor & TGA_RIGHT_TO_LEFT != 0 {
+                x = width as u32 - 1 - x;
+            }
+            if descriptor & TGA_TOP_TO_BOTTOM == 0 {
+                y = height as u32 - 1 - y;
+            }
+            image.put_pixel(x, y, pixel);
+        }
+
+        // Paletted and grey images are written back as truecolor
+        let bits_per_pixel = if bits_per_pixel == 32 || has_alpha { 32 } else { 24 };
+        Ok(UiImage {
+            image,
+            format: UiImageFormat::Tga { bits_per_pixel, rle },
+        })
+    }
+
+    /// Reads uncompressed BMPs with a BITMAPINFOHEADER (or one of its later versions) and 1, 4, 8, 24 or 32 bits per pixel
+    pub fn parse_bmp(data: &[u8]) -> anyhow::Result<UiImage> {
+        let mut index = 0;
+        if read_bytes(data, &mut index, 2, "signature")? != BMP_SIGNATURE {
+            return Err(anyhow!("Not a BMP, missing BM signature"));
+        }
+        index += 8; // file size and reserved
+        let pixel_offset: u32 = read_le_primitive(data, &mut index, "pixel offset")?;
+        let info_header_size: u32 = read_le_primitive(data, &mut index, "info header size")?;
+        if info_header_size < BMP_INFO_HEADER_SIZE {
+            return Err(anyhow!("Unsupported BMP info header size {}", info_header_size));
+        }
+        let width: i32 = read_le_primitive(data, &mut index, "width")?;
+        let height: i32 = read_le_primitive(data, &mut index, "height")?;
+        index += 2; // planes
+        let bits_per_pixel: u16 = read_le_primitive(data, &mut index, "bits per pixel")?;
+        let compression: u32 = read_le_primitive(data, &mut index, "compression")?;
+        index += 12; // image size and resolution
+        let colors_used: u32 = read_le_primitive(data, &mut index, "colors used")?;
+        if compression != BMP_COMPRESSION_RGB {
+            return Err(anyhow!("Unsupported BMP compression {}", compression));
+        }
+        if width <= 0 || height == 0 {
+            return Err(anyhow!("Invalid BMP size {}x{}", width, height));
+        }
+
+        let mut index = BMP_FILE_HEADER_SIZE.saturating_add(info_header_size as usize);
+        let palette = if bits_per_pixel <= 8 {
+            let palette_length = if colors_used == 0 { 1usize << bits_per_pixel } else { colors_used as usize };
+            // colors_used * 4 can overflow on 32 bit so the length is checked against the data first
+            if palette_length > data.len().saturating_sub(index) / 4 {
+                return Err(anyhow!("BMP palette of {} colors is larger than the file", palette_length));
+            }
+            let palette_bytes = read_bytes(data, &mut index, palette_length * 4, "palette")?;
+            palette_bytes.chunks(4).map(|color| [color[2], color[1], color[0], 0xff]).collect::<Vec<[u8; 4]>>()
+        } else {
+            Vec::new()
+        };
+
+        let (width, top_down, height) = (width as u32, height < 0, height.unsigned_abs());
+        let pixel_data_size = bmp_row_size(width, bits_per_pixel)
+            .and_then(|row_size| row_size.checked_mul(height as usize).map(|size| (row_size, size)))
+            .filter(|(_, size)| *size <= data.len());
+        let Some((row_size, pixel_data_size)) = pixel_data_size else {
+            return Err(anyhow!("BMP size {}x{} is larger than the file", width, height));
+        };
+        let mut index = pixel_offset as usize;
+        let pixel_data = read_bytes(data, &mut index, pixel_data_size, "pixels")?;
+        let mut image = RgbaImage::new(width, height);
+        for (row_index, row) in pixel_data.chunks(row_size).enumerate() {
+            let y = if top_down { row_index as u32 } else { height - 1 - row_index as u32 };
+            for x in 0..width as usize {
+                let pixel = match bits_per_pixel {
+                    1 | 4 | 8 => {
+                        let bit_offset = x * bits_per_pixel as usize;
+                        let byte = row[bit_offset / 8];
+                        let shift = 8 - bits_per_pixel as usize - bit_offset % 8;
+                        let color_index = (byte >> shift) & ((1u16 << bits_per_pixel) - 1) as u8;
+                        *palette
+                            .get(color_index as usize)
+                            .ok_or_else(|| anyhow!("BMP color index {} is outside the palette", color_index))?
+                    }
+                    // 32 bit BI_RGB has an unused fourth byte rather than alpha
+                    24 | 32 => {
+                        let offset = x * bits_per_pixel as usize / 8;
+                        [row[offset + 2], row[offset + 1], row[offset], 0xff]
+                    }
+                    _ => return Err(anyhow!("Unsupported BMP bits per pixel {}", bits_per_pixel)),
+                };
+                image.put_pixel(x as u32, y, pixel);
+            }
+        }
+
+        // Images with a palette are written back as 8 bit, everything else as 24 bit
+        let bits_per_pixel = if bits_per_pixel <= 8 { 8 } else { 24 };
+        Ok(UiImage {
+            image,
+            format: UiImageFormat::Bmp { bits_per_pixel },
+        })
+    }
+
+    /// Picks the parser from the file's extension
+    pub fn parse(file_name: &str, data: &[u8]) -> anyhow::Result<UiImage> {
+        let lowercase = file_name.to_ascii_lowercase();
+        if lowercase.ends_with(".tga") {
+            UiImage::parse_tga(data).with_context(|| format!("Error reading TGA {}", file_name))
+        } else if lowercase.ends_with(".bmp") {
+            UiImage::parse_bmp(data).with_context(|| format!("Error reading BMP {}", file_name))
+        } else {
+            Err(anyhow!("{} isn't a TGA or BMP", file_name))
+        }
+    }
+
+    /// Writes the image in its format, 8 bit BMPs fail if the image has more than 256 colors
+    pub fn write(&self) -> anyhow::Result<Vec<u8>> {
+        match self.format {
+            UiImageFormat::Tga { bits_per_pixel, rle } => write_tga(&self.image, bits_per_pixel, rle),
+            UiImageFormat::Bmp { bits_per_pixel } => write_bmp(&self.image, bits_per_pixel),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TgaPixelKind {
+    ColorMapped,
+    TrueColor,
+    Grey,
+}
+
+// TGA stores pixels as BGR(A), 16 bit pixels are 5 bits per channel with the top bit as alpha
+fn tga_pixel(bytes: &[u8], has_alpha: bool) -> anyhow::Result<[u8; 4]> {
+    match bytes.len() {
+        2 => {
+            let value = u16::from_le_bytes([bytes[0], bytes[1]]);
+            let channel = |shift: u16| (((value >> shift) & 0x1f) as u32 * 255 / 31) as u8;
+            let alpha = if !has_alpha || value & 0x8000 != 0 { 0xff } else { 0 };
+            Ok([channel(10), channel(5), channel(0), alpha])
+        }
+        3 => Ok([bytes[2], bytes[1], bytes[0], 0xff]),
+        4 => Ok([bytes[2], bytes[1], bytes[0], if has_alpha { bytes[3] } else { 0xff }]),
+        length => Err(anyhow!("Unsupported TGA pixel size {} bytes", length)),
+    }
+}
+
+fn write_tga(image: &RgbaImage, bits_per_pixel: u8, rle: bool) -> anyhow::Result<Vec<u8>> {
+    if bits_per_pixel != 24 && bits_per_pixel != 32 {
+        return Err(anyhow!("TGAs can only be written with 24 or 32 bits per pixel, not {}", bits_per_pixel));
+    }
+    let width = u16::try_from(image.width).map_err(|_| anyhow!("Image is too wide for a TGA ({})", image.width))?;
+    let height = u16::try_from(image.height).map_err(|_| anyhow!("Image is too tall for a TGA ({})", image.height))?;
+    let alpha_bits = if bits_per_pixel == 32 { 8 } else { 0 };
+
+    let mut accumulator = 0;
+    let mut bytes = Vec::with_capacity(TGA_HEADER_SIZE + image.pixels.len());
+    write_le_primitive(&mut bytes, 0u8, &mut accumulator); // id length
+    write_le_primitive(&mut bytes, 0u8, &mut accumulator); // no color map
+    write_le_primitive(&mut bytes, if rle { 10u8 } else { 2u8 }, &mut accumulator);
+    for _ in 0..5 {
+        write_le_primitive(&mut bytes, 0u8, &mut accumulator); // color map spec
+    }
+    write_le_primitive(&mut bytes, 0u16, &mut accumulator);
+    write_le_primitive(&mut bytes, 0u16, &mut accumulator);
+    write_le_primitive(&mut bytes, width, &mut accumulator);
+    write_le_primitive(&mut bytes, height, &mut accumulator);
+    write_le_primitive(&mut bytes, bits_per_pixel, &mut accumulator);
+    write_le_primitive(&mut bytes, alpha_bits | TGA_TOP_TO_BOTTOM, &mut accumulator);
+
+    let pixels = image
+        .pixels
+        .chunks(4)
+        .map(|pixel| match bits_per_pixel {
+            32 => vec![pixel[2], pixel[1], pixel[0], pixel[3]],
+            _ => vec![pixel[2], pixel[1], pixel[0]],
+        })
+        .collect::<Vec<Vec<u8>>>();
+    if !rle {
+        bytes.extend(pixels.iter().flatten());
+        return Ok(bytes);
+    }
+    // Packets can't cross rows
+    for row in pixels.chunks(image.width.max(1) as usize) {
+        let mut start = 0;
+        while start < row.len() {
+            let run_length = row[start..].iter().take(TGA_MAX_PACKET_LENGTH).take_while(|pixel| **pixel == row[start]).count();
+            if run_length > 1 {
+                bytes.push(TGA_RLE_RUN | (run_length - 1) as u8);
+                bytes.extend(&row[start]);
+                start += run_length;
+                continue;
+            }
+            // Raw packets end where the next run starts
+            let mut end = start + 1;
+            while end < row.len() && end - start < TGA_MAX_PACKET_LENGTH && (end + 1 >= row.len() || row[end] != row[end + 1]) {
+                end += 1;
+            }
+            bytes.push((end - start - 1) as u8);
+            bytes.extend(row[start..end].iter().flatten());
+            start = end;
+        }
+    }
+    Ok(bytes)
+}
+
+// None if the row doesn't fit in a usize
+fn bmp_row_size(width: u32, bits_per_pixel: u16) -> Option<usize> {
+    Some((width as usize).checked_mul(bits_per_pixel as usize)?.div_ceil(32) * 4)
+}
+
+fn write_bmp(image: &RgbaImage, bits_per_pixel: u16) -> anyhow::Result<Vec<u8>> {
+    // BMPs have no transparency, transparent pixels keep their color
+    let colors = image.pixels.chunks(4).map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect::<Vec<[u8; 3]>>();
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let pixel_data = match bits_per_pixel {
+        8 => {
+            let mut palette_indices = HashMap::new();
+            let mut indices = Vec::with_capacity(colors.len());
+            for color in colors.iter() {
+                let next_index = palette.len();
+                let index = *palette_indices.entry(*color).or_insert_with(|| {
+                    palette.push(*color);
+                    next_index
+                });
+                if index > u8::MAX as usize {
+                    return Err(anyhow!("Image has more than 256 colors so can't be written as an 8 bit BMP"));
+                }
+                indices.push(index as u8);
+            }
+            indices
+        }
+        24 => colors.iter().flat_map(|color| [color[2], color[1], color[0]]).collect(),
+        _ => return Err(anyhow!("BMPs can only be written with 8 or 24 bits per pixel, not {}", bits_per_pixel)),
+    };
+
+    let bytes_per_pixel = bits_per_pixel as usize / 8;
+    let row_size = bmp_row_size(image.width, bits_per_pixel).ok_or_else(|| anyhow!("Image is too wide to write as a BMP ({})", image.width))?;
+    let pixel_offset = BMP_FILE_HEADER_SIZE + BMP_INFO_HEADER_SIZE as usize + palette.len() * 4;
+    let file_size = pixel_offset + row_size * image.height as usize;
+
+    let mut accumulator = 0;
+    let mut bytes = Vec::with_capacity(file_size);
+    bytes.extend(BMP_SIGNATURE);
+    write_le_primitive(&mut bytes, file_size as u32, &mut accumulator);
+    write_le_primitive(&mut bytes, 0u32, &mut accumulator);
+    write_le_primitive(&mut bytes, pixel_offset as u32, &mut accumulator);
+    write_le_primitive(&mut bytes, BMP_INFO_HEADER_SIZE, &mut accumulator);
+    write_le_primitive(&mut bytes, image.width as i32, &mut accumulator);
+    // Positive height means rows are stored bottom up, which is what most tools expect
+    write_le_primitive(&mut bytes, image.height as i32, &mut accumulator);
+    write_le_primitive(&mut bytes, 1u16, &mut accumulator);
+    write_le_primitive(&mut bytes, bits_per_pixel, &mut accumulator);
+    write_le_primitive(&mut bytes, BMP_COMPRESSION_RGB, &mut accumulator);
+    write_le_primitive(&mut bytes, (row_size * image.height as usize) as u32, &mut accumulator);
+    write_le_primitive(&mut bytes, 0u32, &mut accumulator);
+    write_le_primitive(&mut bytes, 0u32, &mut accumulator);
+    write_le_primitive(&mut bytes, palette.len() as u32, &mut accumulator);
+    write_le_primitive(&mut bytes, 0u32, &mut accumulator);
+    for color in palette.iter() {
+        bytes.extend([color[2], color[1], color[0], 0]);
+    }
+    for y in (0..image.height as usize).rev() {
+        let row_start = y * image.width as usize * bytes_per_pixel;
+        let row = &pixel_data[row_start..row_start + image.width as usize * bytes_per_pixel];
+        bytes.extend(row);
+        bytes.resize(bytes.len() + row_size - row.len(), 0);
+    }
+    Ok(bytes)
+}
+
+/// Joins images into one strip, e.g. adding a new state to a button whose states are stacked vertically.
+/// Images are aligned to the top left, any space left over is transparent
+pub fn stack_images(images: &[RgbaImage], vertical: bool) -> RgbaImage {
+    let (width, height) = if vertical {
+        (images.iter().map(|image| image.width).max().unwrap_or(0), images.iter().map(|image| image.height).sum())
+    } else {
+        (images.iter().map(|image| image.width).sum(), images.iter().map(|image| image.height).max().unwrap_or(0))
+    };
+    let mut stacked = RgbaImage::new(width, height);
+    let mut position = 0;
+    for image in images.iter() {
+        let (x, y) = if vertical { (0, position) } else { (position, 0) };
+        copy_image(&mut stacked, image, x, y);
+        position += if vertical { image.height } else { image.width };
+    }
+    stacked
+}
+
+/// Copies part of an image, the region is clipped to the image
+pub fn crop_image(image: &RgbaImage, x: u32, y: u32, width: u32, height: u32) -> RgbaImage {
+    let width = width.min(image.width.saturating_sub(x));
+    let height = height.min(image.height.saturating_sub(y));
+    let mut cropped = RgbaImage::new(width, height);
+    for cropped_y in 0..height {
+        for cropped_x in 0..width {
+            if let Some(pixel) = image.get_pixel(x + cropped_x, y + cropped_y) {
+                cropped.put_pixel(cropped_x, cropped_y, pixel);
+            }
+        }
+    }
+    cropped
+}
+
+// Unlike RgbaImage::blit transparent pixels are copied too
+fn copy_image(target: &mut RgbaImage, source: &RgbaImage, x: u32, y: u32) {
+    for source_y in 0..source.height {
+        for source_x in 0..source.width {
+            if let Some(pixel) = source.get_pixel(source_x, source_y) {
+                target.put_pixel(x + source_x, y + source_y, pixel);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod parsing_tests {
+    use super::{crop_image, stack_images, UiImage, UiImageFormat};
+    use crate::sprite::RgbaImage;
+
+    // 3x2 with a gradient and a transparent pixel so row order, channel order and alpha are all checked
+    fn test_image() -> RgbaImage {
+        let mut image = RgbaImage::new(3, 2);
+        image.put_pixel(0, 0, [255, 0, 0, 255]);
+        image.put_pixel(1, 0, [0, 255, 0, 255]);
+        image.put_pixel(2, 0, [0, 0, 255, 255]);
+        image.put_pixel(0, 1, [10, 20, 30, 255]);
+        image.put_pixel(1, 1, [10, 20, 30, 255]);
+        image.put_pixel(2, 1, [40, 50, 60, 0]);
+        image
+    }
+
+    fn opaque(mut image: RgbaImage) -> RgbaImage {
+        for pixel in image.pixels.chunks_mut(4) {
+            pixel[3] = 255;
+        }
+        image
+    }
+
+    #[test]
+    fn test_tga_round_trip() {
+        for (bits_per_pixel, rle) in [(24, false), (32, false), (24, true), (32, true)] {
+            let ui_image = UiImage {
+                image: test_image(),
+                format: UiImageFormat::Tga { bits_per_pixel, rle },
+            };
+            let parsed = UiImage::parse_tga(&ui_image.write().unwrap()).unwrap();
+            assert_eq!(parsed.format, ui_image.format);
+            let expected = if bits_per_pixel == 32 { test_image() } else { opaque(test_image()) };
+            assert_eq!(parsed.image, expected, "{} bit rle {}", bits_per_pixel, rle);
+        }
+    }
+
+    #[test]
+    fn test_tga_bottom_up() {
+        // 1x2 24 bit, stored bottom row first
+        let mut data = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 2, 0, 24, 0];
+        data.extend([0, 0, 255, 255, 0, 0]);
+        let parsed = UiImage::parse_tga(&data).unwrap();
+        assert_eq!(parsed.image.get_pixel(0, 0), Some([0, 0, 255, 255]));
+        assert_eq!(parsed.image.get_pixel(0, 1), Some([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_tga_rle_run_across_rows() {
+        // 2x2 32 bit with alpha, top down, a single run packet covers every pixel
+        let mut data = vec![0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 32, 0x28];
+        data.extend([0x83, 1, 2, 3, 4]);
+        let parsed = UiImage::parse_tga(&data).unwrap();
+        assert!(parsed.image.pixels.chunks(4).all(|pixel| pixel == [3, 2, 1, 4]));
+    }
+
+    #[test]
+    fn test_tga_truncated() {
+        let data = UiImage {
+            image: test_image(),
+            format: UiImageFormat::Tga { bits_per_pixel: 32, rle: false },
+        }
+        .write()
+        .unwrap();
+        assert!(UiImage::parse_tga(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_tga_huge_size_truncated() {
+        // Uncompressed and RLE 65535x65535 32 bit headers with no pixel data
+        for image_type in [2, 10] {
+            let data = [0, 0, image_type, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 32, 0x28];
+            assert!(UiImage::parse_tga(&data).is_err());
+        }
+    }
+
+    fn bmp_header(width: i32, height: i32, bits_per_pixel: u16, colors_used: u32) -> Vec<u8> {
+        let mut data = b"BM".to_vec();
+        data.extend([0; 8]);
+        data.extend(54u32.to_le_bytes());
+        data.extend(40u32.to_le_bytes());
+        data.extend(width.to_le_bytes());
+        data.extend(height.to_le_bytes());
+        data.extend(1u16.to_le_bytes());
+        data.extend(bits_per_pixel.to_le_bytes());
+        data.extend([0; 16]);
+        data.extend(colors_used.to_le_bytes());
+        data.extend([0; 4]);
+        data
+    }
+
+    #[test]
+    fn test_bmp_huge_size_truncated() {
+        assert!(UiImage::parse_bmp(&bmp_header(i32::MAX, i32::MAX, 32, 0)).is_err());
+        assert!(UiImage::parse_bmp(&bmp_header(i32::MAX, i32::MIN + 1, 24, 0)).is_err());
+    }
+
+    #[test]
+    fn test_bmp_huge_palette_truncated() {
+        assert!(UiImage::parse_bmp(&bmp_header(1, 1, 8, u32::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_bmp_round_trip() {
+        for bits_per_pixel in [8, 24] {
+            let ui_image = UiImage {
+                image: opaque(test_image()),
+                format: UiImageFormat::Bmp { bits_per_pixel },
+            };
+            let data = ui_image.write().unwrap();
+            // Headers, 5 palette colors for 8 bit, then 2 rows padded to 4 bytes
+            assert_eq!(data.len(), if bits_per_pixel == 8 { 54 + 5 * 4 + 2 * 4 } else { 54 + 2 * 12 });
+            assert_eq!(UiImage::parse_bmp(&data).unwrap(), ui_image);
+        }
+    }
+
+    #[test]
+    fn test_bmp_too_many_colors() {
+        let mut image = RgbaImage::new(257, 1);
+        for x in 0..257 {
+            image.put_pixel(x, 0, [(x % 256) as u8, (x / 256) as u8, 0, 255]);
+        }
+        let ui_image = UiImage {
+            image,
+            format: UiImageFormat::Bmp { bits_per_pixel: 8 },
+        };
+        assert!(ui_image.write().is_err());
+    }
+
+    #[test]
+    fn test_parse_by_extension() {
+        let data = UiImage {
+            image: opaque(test_image()),
+            format: UiImageFormat::Bmp { bits_per_pixel: 24 },
+        }
+        .write()
+        .unwrap();
+        assert!(UiImage::parse("ui/button.BMP", &data).is_ok());
+        assert!(UiImage::parse("ui/button.tga", &data).is_err());
+        assert!(UiImage::parse("ui/button.pal", &data).is_err());
+    }
+
+    #[test]
+    fn test_default_format() {
+        assert_eq!(UiImageFormat::default_for("ui/button.TGA"), Some(UiImageFormat::Tga { bits_per_pixel: 32, rle: false }));
+        assert_eq!(UiImageFormat::default_for("ui/background.bmp"), Some(UiImageFormat::Bmp { bits_per_pixel: 24 }));
+        assert_eq!(UiImageFormat::default_for("ui/button.png"), None);
+        assert_eq!(UiImageFormat::Tga { bits_per_pixel: 24, rle: true }.to_string(), "24 bit RLE TGA");
+    }
+
+    #[test]
+    fn test_stack_and_crop() {
+        let image = test_image();
+        let stacked = stack_images(&[image.clone(), image.clone()], true);
+        assert_eq!((stacked.width, stacked.height), (3, 4));
+        assert_eq!(crop_image(&stacked, 0, 2, 3, 2), image);
+        let side_by_side = stack_images(&[image.clone(), crop_image(&image, 0, 0, 1, 1)], false);
+        assert_eq!((side_by_side.width, side_by_side.height), (4, 2));
+        assert_eq!(side_by_side.get_pixel(3, 0), Some([255, 0, 0, 255]));
+        assert_eq!(side_by_side.get_pixel(3, 1), Some([0, 0, 0, 0]));
+    }
+}
@@ -0,0 +1,137 @@
+use std::{ffi::CString, fmt};
+
+use anyhow::{anyhow, Context};
+use zt_formats::{animation::Animation, sprite::FrameBounds};
+
+use crate::resource_manager::{ZTFile, OPENZT_DIR0};
+
+// OpenZT resources are only looked up by dir1 (see parse_openzt_resource_string), the game still appends a view name to the path
+const OPENZT_RESOURCE_VIEW: &str = "animation";
+
+/// The contents of a .ani file. The game loads `dir0/dir1/.../<view>` for each view,
+/// x0/y0/x1/y1 is the bounding box relative to the anchor and is used for drawing and selection
+#[derive(Debug, Clone, PartialEq)]
+pub struct AniFile {
+    pub dirs: Vec<String>,
+    pub views: Vec<String>,
+    pub bounds: FrameBounds,
+}
+
+impl AniFile {
+    /// Bounding box covers every frame of every view
+    pub fn from_animations(dirs: Vec<String>, views: &[(&str, &Animation)]) -> anyhow::Result<AniFile> {
+        let bounds = views
+            .iter()
+            .filter_map(|(_, animation)| FrameBounds::of_animation(animation))
+            .reduce(|a, b| a.union(&b))
+            .ok_or_else(|| anyhow!("Cannot generate .ani, animations have no frames"))?;
+        Ok(AniFile {
+            dirs,
+            views: views.iter().map(|(view, _)| view.to_string()).collect(),
+            bounds,
+        })
+    }
+
+    /// .ani for a single animation registered in the resource map under `resource_name`, bounds as returned by
+    /// FrameBounds::of_animation or sprite::png_bounds for PNGs that haven't been converted yet
+    pub fn for_openzt_resource(resource_name: &str, bounds: FrameBounds) -> AniFile {
+        AniFile {
+            dirs: vec![OPENZT_DIR0.to_string(), resource_name.to_string()],
+            views: vec![OPENZT_RESOURCE_VIEW.to_string()],
+            bounds,
+        }
+    }
+
+    pub fn to_ztfile(&self, file_name: &str) -> anyhow::Result<ZTFile> {
+        let ani_string = self.to_string();
+        let file_size = ani_string.len() as u32;
+        let c_string = CString::new(ani_string).with_context(|| format!("Error converting {} to CString", file_name))?;
+        ZTFile::new_text(file_name.to_string(), file_size, c_string)
+    }
+}
+
+impl fmt::Display for AniFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "[animation]")?;
+        for (index, dir) in self.dirs.iter().enumerate() {
+            writeln!(f, "dir{} = {}", index, dir)?;
+        }
+        for view in self.views.iter() {
+            writeln!(f, "animation = {}", view)?;
+        }
+        writeln!(f, "x0 = {}", self.bounds.left)?;
+        writeln!(f, "y0 = {}", self.bounds.top)?;
+        writeln!(f, "x1 = {}", self.bounds.right())?;
+        writeln!(f, "y1 = {}", self.bounds.bottom())
+    }
+}
+
+#[cfg(test)]
+mod parsing_tests {
+    use std::fs;
+
+    use zt_formats::{animation::Animation, sprite::FrameBounds};
+
+    use super::AniFile;
+
+    #[test]
+    fn test_ani_bounds_from_animation() {
+        let animation = Animation::parse(&fs::read("resources/test/N").unwrap()).unwrap();
+        let ani = AniFile::from_animations(vec!["objects".to_string(), "rock".to_string()], &[("N", &animation)]).unwrap();
+        assert_eq!(Some(ani.bounds), FrameBounds::of_animation(&animation));
+        assert_eq!(ani.dirs, vec!["objects", "rock"]);
+        assert_eq!(ani.views, vec!["N"]);
+    }
+
+    #[test]
+    fn test_ani_bounds_cover_all_views() {
+        let animation = Animation::parse(&fs::read("resources/test/N").unwrap()).unwrap();
+        let mut shifted = animation.clone();
+        shifted.shift_offsets(100, -50).unwrap();
+        let ani = AniFile::from_animations(vec!["objects".to_string(), "rock".to_string()], &[("N", &animation), ("S", &shifted)]).unwrap();
+        let bounds = FrameBounds::of_animation(&animation).unwrap();
+        assert_eq!(ani.bounds.left, bounds.left);
+        assert_eq!(ani.bounds.top, bounds.top - 50);
+        assert_eq!(ani.bounds.right(), bounds.right() + 100);
+        assert_eq!(ani.bounds.bottom(), bounds.bottom());
+    }
+
+    #[test]
+    fn test_ani_openzt_resource() {
+        let bounds = FrameBounds {
+            left: -30,
+            top: -36,
+            width: 67,
+            height: 72,
+        };
+        assert_eq!(
+            AniFile::for_openzt_resource("openzt.mods.test.habitat.swamp.animation", bounds).to_string(),
+            "[animation]\ndir0 = openzt_resource\ndir1 = openzt.mods.test.habitat.swamp.animation\nanimation = animation\nx0 = -30\ny0 = -36\nx1 = 37\ny1 = 36\n"
+        );
+    }
+
+    #[test]
+    fn test_ani_display() {
+        let ani = AniFile {
+            dirs: vec!["objects".to_string(), "rock".to_string(), "idle".to_string()],
+            views: vec!["N".to_string(), "S".to_string()],
+            bounds: FrameBounds {
+                left: -21,
+                top: -15,
+                width: 43,
+                height: 30,
+            },
+        };
+        assert_eq!(
+            ani.to_string(),
+            "[animation]\ndir0 = objects\ndir1 = rock\ndir2 = idle\nanimation = N\nanimation = S\nx0 = -21\ny0 = -15\nx1 = 22\ny1 = 15\n"
+        );
+    }
+
+    #[test]
+    fn test_ani_no_frames() {
+        let mut animation = Animation::parse(&fs::read("resources/test/N").unwrap()).unwrap();
+        animation.frames.clear();
+        assert!(AniFile::from_animations(vec!["objects".to_string()], &[("N", &animation)]).is_err());
+    }
+}
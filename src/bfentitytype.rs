@@ -8,9 +8,11 @@ use field_accessor_as_string::FieldAccessorAsString;
 use field_accessor_as_string_trait::FieldAccessorAsStringTrait;
 
 use crate::{
+    colorrep::{get_color_variants, ColorRep},
     console::{add_to_command_register, CommandError},
     debug_dll::{get_from_memory, get_string_from_memory, map_from_memory},
     expansions::is_member,
+    resource_manager::{find_entity_file, peek_file, OPENZT_DIR0},
     ztui::get_selected_entity_type_address,
     ztworldmgr,
 };
@@ -102,16 +104,28 @@ impl BFEntityType {
         get_string_from_memory(get_from_memory::<u32>(obj_ptr + 0x148))
     }
 
-    // prints [colorrep] section of the configuration
-    fn print_colorrep(&self) -> String {
-        // NOTE: ncolors is part of a separate structure in memory withn BFEntityType, so we need to grab the pointer to it first
-        // this is temporary until the struct can be fully implemented
-        let entity_type_address = get_selected_entity_type_address(); // grab the address of the selected entity type
-        let entity_type_print = get_from_memory::<u32>(entity_type_address); // convert the address to a u32 ptr for printing
-        let ncolors_ptr = get_from_memory::<u32>(entity_type_print + 0x038);
-        let ncolors = get_from_memory::<u32>(ncolors_ptr);
-
-        format!("\n\n[colorrep]\nncolors: {}\n", ncolors)
+    // prints [colorrep] section of the configuration, along with any colour variants added by OpenZT mods
+    pub fn print_colorrep(&self) -> String {
+        // NOTE: ncolors is the first field of a separate colour replacement structure, BFEntityType only holds a pointer to it
+        let ncolors = if self.ncolors == 0 { 0 } else { get_from_memory::<u32>(self.ncolors) };
+        let mut colorrep = format!("\n[colorrep]\nncolors: {}\n", ncolors);
+        let names = [self.get_type_name(), self.get_codename()];
+        // The rest of the structure isn't mapped, so the replacement palettes are read from the entity's config
+        match find_entity_file(&names) {
+            Some(entity_file) => match peek_file(&entity_file).and_then(|file| ColorRep::parse(String::from_utf8_lossy(&file).trim_end_matches('\0'))) {
+                Some(entity_colorrep) => {
+                    for (key, palette) in entity_colorrep.palettes.iter() {
+                        colorrep.push_str(&format!("{}: {}\n", key, palette));
+                    }
+                }
+                None => colorrep.push_str(&format!("no [colorrep] section in {}\n", entity_file)),
+            },
+            None => colorrep.push_str("config file not found\n"),
+        }
+        for variant in get_color_variants(&names) {
+            colorrep.push_str(&format!("{} ({}): {}/{}\n", variant.name, variant.mod_id, OPENZT_DIR0, variant.palette_resource));
+        }
+        colorrep
     }
 }
 
@@ -1580,8 +1594,9 @@ fn command_sel_type(args: Vec<&str>) -> Result<String, CommandError> {
             "Printing configuration for entity type at address {:#x}",
             entity_type_address as u32
         );
-        // print the entity type configuration for the selected entity type
-        Ok(entity_type.print_config())
+        // print the entity type configuration for the selected entity type, every entity type starts with a BFEntityType
+        let bfentitytype = map_from_memory::<BFEntityType>(entity_type_address);
+        Ok(format!("{}{}", entity_type.print_config(), bfentitytype.print_colorrep()))
     } else if args.len() == 2 {
         // parse the subargs for the entity type
         Ok(entity_type.set_config(args[0], args[1])?)
@@ -0,0 +1,206 @@
+use std::{fmt, sync::Mutex};
+
+use once_cell::sync::Lazy;
+
+const COLORREP_SECTION: &str = "colorrep";
+const NCOLORS_KEY: &str = "ncolors";
+// Used when a config has no replacement palettes to copy the key from
+const DEFAULT_PALETTE_KEY: &str = "pal";
+
+/// The [colorrep] section of an entity config, the palettes the game can draw the entity with instead of its own (e.g. guest clothing, animal variants).
+/// ncolors is the number of replacement palettes
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ColorRep {
+    pub ncolors: u32,
+    // (key, palette path) in the order they appear
+    pub palettes: Vec<(String, String)>,
+}
+
+impl ColorRep {
+    /// None if the config doesn't have a [colorrep] section
+    pub fn parse(cfg: &str) -> Option<ColorRep> {
+        let mut colorrep = None;
+        let mut in_section = false;
+        for line in cfg.lines() {
+            if let Some(section) = section_name(line) {
+                in_section = section.eq_ignore_ascii_case(COLORREP_SECTION);
+                if in_section {
+                    colorrep.get_or_insert_with(ColorRep::default);
+                }
+                continue;
+            }
+            let (true, Some((key, value)), Some(colorrep)) = (in_section, key_value(line), colorrep.as_mut()) else {
+                continue;
+            };
+            if key.eq_ignore_ascii_case(NCOLORS_KEY) {
+                colorrep.ncolors = value.parse().unwrap_or(0);
+            } else if value.to_ascii_lowercase().ends_with(".pal") || value.to_ascii_lowercase().ends_with(".palette") {
+                colorrep.palettes.push((key.to_string(), value.to_string()));
+            }
+        }
+        colorrep
+    }
+
+    // Follows the existing entries, either repeating the same key or continuing the numbering (e.g. pal0, pal1 -> pal2)
+    fn next_palette_key(&self) -> String {
+        let Some((key, _)) = self.palettes.last() else {
+            return DEFAULT_PALETTE_KEY.to_string();
+        };
+        let prefix = key.trim_end_matches(|c: char| c.is_ascii_digit());
+        if prefix.len() == key.len() {
+            key.clone()
+        } else {
+            format!("{}{}", prefix, self.palettes.len())
+        }
+    }
+}
+
+impl fmt::Display for ColorRep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "ncolors: {}", self.ncolors)?;
+        for (key, palette) in self.palettes.iter() {
+            writeln!(f, "{}: {}", key, palette)?;
+        }
+        Ok(())
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find([';', '#', ':']) {
+        Some(index) => &line[..index],
+        None => line,
+    }
+}
+
+fn section_name(line: &str) -> Option<&str> {
+    let line = strip_comment(line).trim();
+    line.strip_prefix('[')?.strip_suffix(']').map(str::trim)
+}
+
+fn key_value(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = strip_comment(line).split_once('=')?;
+    Some((key.trim(), value.trim()))
+}
+
+/// Adds a replacement palette to the config's [colorrep] section and bumps ncolors, the section is added if the config doesn't have one.
+/// Everything else in the config is left as it was
+pub fn add_colorrep_palette(cfg: &str, palette: &str) -> String {
+    let Some(colorrep) = ColorRep::parse(cfg) else {
+        let separator = if cfg.is_empty() || cfg.ends_with('\n') { "" } else { "\n" };
+        return format!("{}{}\n[{}]\n{} = 1\n{} = {}\n", cfg, separator, COLORREP_SECTION, NCOLORS_KEY, DEFAULT_PALETTE_KEY, palette);
+    };
+    let ncolors = colorrep.ncolors.max(colorrep.palettes.len() as u32) + 1;
+    let palette_line = format!("{} = {}", colorrep.next_palette_key(), palette);
+
+    let lines = cfg.lines().collect::<Vec<&str>>();
+    let mut output = Vec::with_capacity(lines.len() + 2);
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        index += 1;
+        if !section_name(line).is_some_and(|section| section.eq_ignore_ascii_case(COLORREP_SECTION)) {
+            output.push(line.to_string());
+            continue;
+        }
+        output.push(line.to_string());
+        output.push(format!("{} = {}", NCOLORS_KEY, ncolors));
+        // Copies the rest of the section without the old ncolors, the new palette goes after the last non blank line
+        let mut section = Vec::new();
+        while index < lines.len() && section_name(lines[index]).is_none() {
+            if !key_value(lines[index]).is_some_and(|(key, _)| key.eq_ignore_ascii_case(NCOLORS_KEY)) {
+                section.push(lines[index].to_string());
+            }
+            index += 1;
+        }
+        let insert_at = section.iter().rposition(|line| !line.trim().is_empty()).map_or(0, |position| position + 1);
+        section.insert(insert_at, palette_line.clone());
+        output.extend(section);
+        // Only the first [colorrep] section is used
+        output.extend(lines[index..].iter().map(|line| line.to_string()));
+        break;
+    }
+    let mut new_cfg = output.join("\n");
+    if cfg.ends_with('\n') {
+        new_cfg.push('\n');
+    }
+    new_cfg
+}
+
+/// A colour variant added by an OpenZT mod
+#[derive(Debug, Clone)]
+pub struct ColorVariant {
+    pub mod_id: String,
+    pub name: String,
+    // Type name or codename as shown by sel_type
+    pub entity_type: String,
+    pub palette_resource: String,
+}
+
+static COLOR_VARIANTS: Lazy<Mutex<Vec<ColorVariant>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub fn add_color_variant(variant: ColorVariant) {
+    COLOR_VARIANTS.lock().unwrap().push(variant);
+}
+
+/// Variants added by mods for an entity type, matched against any of the given names
+pub fn get_color_variants(entity_type_names: &[String]) -> Vec<ColorVariant> {
+    COLOR_VARIANTS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|variant| entity_type_names.iter().any(|name| name.eq_ignore_ascii_case(&variant.entity_type)))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod parsing_tests {
+    use super::{add_colorrep_palette, ColorRep};
+
+    const GUEST_CFG: &str = include_str!("../resources/test/colorrep.cfg");
+
+    #[test]
+    fn test_parse_colorrep() {
+        let colorrep = ColorRep::parse(GUEST_CFG).unwrap();
+        assert_eq!(colorrep.ncolors, 2);
+        assert_eq!(
+            colorrep.palettes,
+            vec![
+                ("pal".to_string(), "guests/shirts/red.pal".to_string()),
+                ("pal".to_string(), "guests/shirts/blue.pal".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_no_colorrep() {
+        assert_eq!(ColorRep::parse("[Characteristics/Integers]\ncHeight = 2\n"), None);
+    }
+
+    #[test]
+    fn test_add_colorrep_palette() {
+        let new_cfg = add_colorrep_palette(GUEST_CFG, "openzt_resource/openzt.mods.test.colorrep.green.palette");
+        let colorrep = ColorRep::parse(&new_cfg).unwrap();
+        assert_eq!(colorrep.ncolors, 3);
+        assert_eq!(colorrep.palettes.len(), 3);
+        assert_eq!(colorrep.palettes[2], ("pal".to_string(), "openzt_resource/openzt.mods.test.colorrep.green.palette".to_string()));
+        // Other sections are untouched
+        assert!(new_cfg.starts_with("[Characteristics/Integers]\ncHeight = 2\n"));
+        assert!(new_cfg.ends_with("[m/Walk]\nanimation = walk\n"));
+        assert_eq!(new_cfg.lines().filter(|line| line.starts_with("ncolors")).count(), 1);
+    }
+
+    #[test]
+    fn test_add_colorrep_palette_numbered() {
+        let cfg = "[colorrep]\nncolors = 2\npal0 = a.pal\npal1 = b.pal\n";
+        assert_eq!(add_colorrep_palette(cfg, "c.pal"), "[colorrep]\nncolors = 3\npal0 = a.pal\npal1 = b.pal\npal2 = c.pal\n");
+    }
+
+    #[test]
+    fn test_add_colorrep_section() {
+        assert_eq!(
+            add_colorrep_palette("[Characteristics/Integers]\ncHeight = 2", "a.pal"),
+            "[Characteristics/Integers]\ncHeight = 2\n\n[colorrep]\nncolors = 1\npal = a.pal\n"
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use std::{env, fs, process};
+
+use zt_formats::{
+    sprite::RgbaImage,
+    ui_image::{UiImage, UiImageFormat},
+};
+
+const USAGE: &str = "Usage: convert_ui_image <input> <output> [-b bits_per_pixel] [-r]\n\
+                     Converts between png, tga and bmp by file extension. tga and bmp outputs keep the input's format when converting between the same type,\n\
+                     -b sets the bits per pixel (tga 24 or 32, bmp 8 or 24) and -r writes run length encoded tga";
+
+fn main() {
+    if let Err(e) = run(env::args().skip(1).collect()) {
+        eprintln!("{:#}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> anyhow::Result<()> {
+    let mut bits_per_pixel = None;
+    let mut rle = false;
+    let mut positional = Vec::new();
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "-b" => bits_per_pixel = Some(args_iter.next().ok_or_else(|| anyhow::anyhow!(USAGE))?.parse::<u16>()?),
+            "-r" => rle = true,
+            _ => positional.push(arg),
+        }
+    }
+    let [input, output] = positional[..] else {
+        return Err(anyhow::anyhow!(USAGE));
+    };
+
+    let data = fs::read(input)?;
+    let (image, input_format) = if input.to_ascii_lowercase().ends_with(".png") {
+        (RgbaImage::from_png(&data)?, None)
+    } else {
+        let ui_image = UiImage::parse(input, &data)?;
+        println!("{}: {}x{} {}", input, ui_image.image.width, ui_image.image.height, ui_image.format);
+        (ui_image.image, Some(ui_image.format))
+    };
+
+    if output.to_ascii_lowercase().ends_with(".png") {
+        fs::write(output, image.to_png()?)?;
+        return Ok(());
+    }
+    let default_format = UiImageFormat::default_for(output).ok_or_else(|| anyhow::anyhow!("{} isn't a png, tga or bmp", output))?;
+    let format = match (input_format, default_format) {
+        (Some(UiImageFormat::Tga { bits_per_pixel, rle }), UiImageFormat::Tga { .. }) => UiImageFormat::Tga { bits_per_pixel, rle },
+        (Some(UiImageFormat::Bmp { bits_per_pixel }), UiImageFormat::Bmp { .. }) => UiImageFormat::Bmp { bits_per_pixel },
+        _ => default_format,
+    };
+    let format = match format {
+        UiImageFormat::Tga { bits_per_pixel: tga_bits, rle: tga_rle } => UiImageFormat::Tga {
+            bits_per_pixel: bits_per_pixel.map_or(tga_bits, |bits| bits as u8),
+            rle: tga_rle || rle,
+        },
+        UiImageFormat::Bmp { bits_per_pixel: bmp_bits } => UiImageFormat::Bmp {
+            bits_per_pixel: bits_per_pixel.unwrap_or(bmp_bits),
+        },
+    };
+    let ui_image = UiImage { image, format };
+    fs::write(output, ui_image.write()?)?;
+    println!("{}: {}x{} {}", output, ui_image.image.width, ui_image.image.height, ui_image.format);
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use std::{env, fs, path::Path, process};
+
+use zt_formats::{animation::Animation, animation_diff, palette::Palette};
+
+const USAGE: &str = "Usage: diff_animation <old_animation> <old_palette> <new_animation> <new_palette> [output_dir]\n\
+                     Diff images are only written when an output_dir is given";
+
+fn main() {
+    match run(env::args().skip(1).collect()) {
+        Ok(unchanged) => process::exit(if unchanged { 0 } else { 1 }),
+        Err(e) => {
+            eprintln!("{:#}", e);
+            process::exit(2);
+        }
+    }
+}
+
+// Exits with 1 when the animations differ so the tool can be used in scripts, like diff
+fn run(args: Vec<String>) -> anyhow::Result<bool> {
+    let (old_path, old_palette_path, new_path, new_palette_path, directory) = match &args[..] {
+        [old_path, old_palette_path, new_path, new_palette_path] => (old_path, old_palette_path, new_path, new_palette_path, None),
+        [old_path, old_palette_path, new_path, new_palette_path, directory] => (old_path, old_palette_path, new_path, new_palette_path, Some(directory)),
+        _ => return Err(anyhow::anyhow!(USAGE)),
+    };
+
+    let old = Animation::parse(&fs::read(old_path)?)?;
+    let old_palette = Palette::parse(&fs::read(old_palette_path)?)?;
+    let new = Animation::parse(&fs::read(new_path)?)?;
+    let new_palette = Palette::parse(&fs::read(new_palette_path)?)?;
+
+    let diff = animation_diff::diff_animations(&old, &old_palette, &new, &new_palette)?;
+    print!("{}", diff);
+    if let Some(directory) = directory {
+        let name = Path::new(new_path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or("animation".to_string());
+        for path in animation_diff::export_diff(&diff, Path::new(directory), &name)? {
+            println!("{}", path.display());
+        }
+    }
+    Ok(diff.is_unchanged())
+}
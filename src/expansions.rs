@@ -9,121 +9,19 @@ use std::{
 
 use anyhow::{anyhow, Context};
 use bf_configparser::ini::Ini;
-use maplit::hashset;
 use once_cell::sync::Lazy;
 use retour_utils::hook_module;
 use tracing::{error, info};
+use zt_formats::{animation::Animation, sprite::FrameBounds};
 
 use crate::{
-    add_to_command_register, animation::Animation, bfentitytype::{ZTEntityType, ZTEntityTypeClass}, console::CommandError, debug_dll::{
+    add_to_command_register, ani::AniFile, bfentitytype::{ZTEntityType, ZTEntityTypeClass}, console::CommandError, debug_dll::{
         get_from_memory, get_string_from_memory, get_string_from_memory_bounded,  save_to_memory
     }, resource_manager::{
-        add_handler, modify_ztfile_as_animation, modify_ztfile_as_ini, Handler, RunStage, OPENZT_DIR0
+        add_alias, add_handler, add_ztfile, archive_layer, modify_ztfile_as_animation, modify_ztfile_as_ini, peek_file, Handler, HandlerOutcome, ResourceLayer, RunStage, ZTFileType, wait_for_resources
     }, string_registry::add_string_to_registry, ztui::{get_random_sex, get_selected_sex, BuyTab, Sex}
 };
 
-static OFFICIAL_FILESET: Lazy<HashSet<&str>> = Lazy::new(|| {
-    hashset! {"animals8.ztd",
-        "awards5.ztd",
-        "config5.ztd",
-        "global03.ztd",
-        "items5.ztd",
-        "scenari5.ztd",
-        "scenery5.ztd",
-        "staff5.ztd",
-        "ui5.ztd",
-        "animals7.ztd",
-        "scn40.ztd",
-        "scn41.ztd",
-        "scn42.ztd",
-        "swordfsh.ztd",
-        "swordfsh01.ztd",
-        "ui7.ztd",
-        "wilddog.ztd",
-        "yeti.ztd",
-        "animalsa.ztd",
-        "antelope.ztd",
-        "aqtheme1.ztd",
-        "Asian Black Bear.ztd",
-        "Asian Elephant.ztd",
-        "asian_black_bear.ztd",
-        "asian_elephant.ztd",
-        "asian_elephant01.ztd",
-        "asian_elephant02.ztd",
-        "baracuda.ztd",
-        "blackbuck.ztd",
-        "bongo.ztd",
-        "config4.ztd",
-        "fancybld.ztd",
-        "gallim.ztd",
-        "gallim01.ztd",
-        "global06.ztd",
-        "guests7.ztd",
-        "items7.ztd",
-        "kidsdino.ztd",
-        "komodo.ztd",
-        "llama.ztd",
-        "magnet.ztd",
-        "magnet01.ztd",
-        "magnet02.ztd",
-        "megathrm.ztd",
-        "mexipack.ztd",
-        "mountainlion.ztd",
-        "mountainlion01.ztd",
-        "mt_lion.ztd",
-        "na_theme.ztd",
-        "object06.ztd",
-        "plateo.ztd",
-        "plateo01.ztd",
-        "reindeer.ztd",
-        "researm.ztd",
-        "scenari7.ztd",
-        "scenery7.ztd",
-        "scn16.ztd",
-        "scn17.ztd",
-        "scn18.ztd",
-        "ai.ztd",
-        "ambient.ztd",
-        "animals.ztd",
-        "animals2.ztd",
-        "awards.ztd",
-        "config.ztd",
-        "fences.ztd",
-        "freeform.ztd",
-        "fringe.ztd",
-        "global.ztd",
-        "guests.ztd",
-        "items.ztd",
-        "objects.ztd",
-        "paths.ztd",
-        "paths2.ztd",
-        "research.ztd",
-        "scenario.ztd",
-        "scenery.ztd",
-        "select.ztd",
-        "sounds.ztd",
-        "staff.ztd",
-        "terrain.ztd",
-        "tiles.ztd",
-        "ui.ztd",
-        "ztatb00.ztd",
-        "ztatb0a.ztd",
-        "ztatb0b.ztd",
-        "ztatb0d.ztd",
-        "ztatb01.ztd",
-        "ztatb02.ztd",
-        "ztatb03.ztd",
-        "ztatb04.ztd",
-        "ztatb05.ztd",
-        "ztatb06.ztd",
-        "ztatb07.ztd",
-        "ztatb08.ztd",
-        "ztatb09.ztd",
-        "ztatb10.ztd",
-        "zts.ztd",
-    }
-});
-
 const CUSTOM_CONTENT_EXPANSION_STRING_PREFIX: &str = "openzt_";
 const CUSTOM_CONTENT_EXPANSION_STRING_ALL: &str = "all";
 const CUSTOM_CONTENT_EXPANSION_STRING_SUBDIR: &str = "subdir_";
@@ -402,6 +300,8 @@ pub mod custom_expansion {
 }
 
 fn initialise_expansions() {
+    // Expansions and their members are found by handlers on the resource loading thread
+    wait_for_resources();
     add_expansion_with_string_id(0x0, "all".to_string(), 0x5974, false);
     if let Some(member_hash) = get_members(&get_cc_expansion_name_all())
         && !member_hash.is_empty()
@@ -450,29 +350,6 @@ fn resize_expansion_dropdown(number_of_expansions: u32) {
 
     let animation_resource_string = format!("{}.{}", EXPANSION_OPENZT_RESOURCE_PREFIX.to_string(),  EXPANSION_RESOURCE_ANIMATION);
 
-    if let Err(err) = modify_ztfile_as_ini(
-        &format!("{}.{}", EXPANSION_OPENZT_RESOURCE_PREFIX.to_string(), EXPANSION_RESOURCE_ANI),
-        |cfg| {
-            let old_y0 = cfg
-                .get_parse::<i32>("animation", "y0")
-                .unwrap_or(Some(-34))
-                .unwrap_or(-34);
-            let new_y0 = old_y0 - (number_of_additional_expansions * 10);
-            let old_y1 = cfg
-                .get_parse::<i32>("animation", "y1")
-                .unwrap_or(Some(34))
-                .unwrap_or(34);
-            let new_y1 = old_y1 + (number_of_additional_expansions * 10);
-            cfg.set("animation", "y0", Some(new_y0.to_string()));
-            cfg.set("animation", "y1", Some(new_y1.to_string()));
-            cfg.set("animation", "dir0", Some(OPENZT_DIR0.to_string()));
-            cfg.set("animation", "dir1", Some(animation_resource_string.clone()));
-            cfg.remove_key("animation", "dir2");
-        },
-    ) {
-        info!("Error resizing expansion dropdown 'ani' file: {}", err);
-    }
-    info!("Check");
     let animation_result = modify_ztfile_as_animation(
         &animation_resource_string,
         |animation| {
@@ -490,9 +367,25 @@ fn resize_expansion_dropdown(number_of_expansions: u32) {
     );
     if let Err(e) = animation_result {
         info!("Error resizing expansion dropdown animation: {}", e);
+        return;
+    }
+
+    // The .ani's bounding box has to grow with the animation, it's regenerated from the resized frames
+    if let Err(e) = generate_expansion_dropdown_ani(&animation_resource_string) {
+        info!("Error resizing expansion dropdown 'ani' file: {}", e);
     }
 }
 
+fn generate_expansion_dropdown_ani(animation_resource_string: &str) -> anyhow::Result<()> {
+    let animation_file = peek_file(animation_resource_string).with_context(|| format!("Cannot find {}", animation_resource_string))?;
+    let animation = Animation::parse(&animation_file)?;
+    let bounds = FrameBounds::of_animation(&animation).with_context(|| format!("{} has no frames", animation_resource_string))?;
+    let ani_file_name = format!("{}.{}", EXPANSION_OPENZT_RESOURCE_PREFIX, EXPANSION_RESOURCE_ANI);
+    let ztfile = AniFile::for_openzt_resource(animation_resource_string, bounds).to_ztfile(&ani_file_name)?;
+    add_ztfile(Path::new("zip::./openzt.ztd"), ani_file_name, ztfile);
+    Ok(())
+}
+
 fn filter_entity_type(buy_tab: &BuyTab, current_expansion: &Expansion, entity: &ZTEntityType) -> bool {
     match buy_tab {
         BuyTab::Animal => {
@@ -649,18 +542,18 @@ fn add_expansion_with_string_value(expansion_id: u32, name: String, string_value
     }
 }
 
-fn handle_expansion_config(path: &String, _: &String, file: Ini) -> Option<(String, String, Ini)> {
+fn handle_expansion_config(path: &String, _: &String, file: Ini) -> HandlerOutcome<Ini> {
     if let Err(e) = parse_expansion_config(&file) {
         error!("Error parsing expansion config: {} {}", path, e);
     }
-    None
+    HandlerOutcome::unchanged()
 }
 
-fn handle_member_parsing(path: &String, file_name: &String, file: Ini) -> Option<(String, String, Ini)> {
+fn handle_member_parsing(path: &String, file_name: &String, file: Ini) -> HandlerOutcome<Ini> {
     if let Err(e) = parse_member_config(path, file_name, file) {
         error!("Error parsing member config: {} {}", path, e)
     }
-    None
+    HandlerOutcome::unchanged()
 }
 
 fn parse_member_config(path: &String, file_name: &String, file: Ini) -> anyhow::Result<()> {
@@ -690,19 +583,7 @@ fn parse_member_config(path: &String, file_name: &String, file: Ini) -> anyhow::
 }
 
 fn is_cc(archive: &String) -> bool {
-    let path = Path::new(archive);
-    let Some(parent) = path.parent() else {
-        return false;
-    };
-
-    match parent.file_name().unwrap_or_default().to_str().unwrap_or_default() {
-        "zupdate" | "xpack1" | "zupdate1" | "xpack2" => false,
-        "dlupdate" | "dupdate" | "updates" | "" => match path.file_name().unwrap_or_default().to_str().unwrap_or_default() {
-            "" => false,
-            file_name => !OFFICIAL_FILESET.contains(file_name),
-        },
-        _ => true,
-    }
+    archive_layer(archive) == ResourceLayer::LegacyCc
 }
 
 fn parse_expansion_config(expansion_cfg: &Ini) -> anyhow::Result<()> {
@@ -743,34 +624,15 @@ fn parse_expansion_config(expansion_cfg: &Ini) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn handle_expansion_dropdown_ani(path: &String, file_name: &String, file: Ini) -> Option<(String, String, Ini)> {
-    let new_file_string = format!("{}.{}", EXPANSION_OPENZT_RESOURCE_PREFIX, file_name.strip_prefix(EXPANSION_ZT_RESOURCE_PREFIX).unwrap_or(file_name));
-    let file_path = Path::new(&new_file_string);
-    let Some(file_path_string) = file_path.to_str() else {
-        error!("Error converting file path to string");
-        return None
+// The dropdown background is resized by modifying aliases of the original files, aliases share the original data until they are modified
+fn handle_expansion_dropdown(_: &String, file_name: &String) {
+    let new_file_string = match ZTFileType::try_from(Path::new(file_name)) {
+        Ok(ZTFileType::Animation) => format!("{}.{}", EXPANSION_OPENZT_RESOURCE_PREFIX, EXPANSION_RESOURCE_ANIMATION),
+        _ => format!("{}.{}", EXPANSION_OPENZT_RESOURCE_PREFIX, file_name.strip_prefix(EXPANSION_ZT_RESOURCE_PREFIX).unwrap_or(file_name)),
     };
-    Some((path.clone(), file_path_string.to_owned(), file))
-}
-
-fn handle_expansion_dropdown_raw_bytes(path: &String, file_name: &String, file: Box<[u8]>) -> Option<(String, String, Box<[u8]>)> {
-    let new_file_string = format!("{}.{}", EXPANSION_OPENZT_RESOURCE_PREFIX, file_name.strip_prefix(EXPANSION_ZT_RESOURCE_PREFIX).unwrap_or(file_name));
-    let file_path = Path::new(&new_file_string);
-    let Some(file_path_string) = file_path.to_str() else {
-        error!("Error converting file path to string");
-        return None;
-    };
-    Some((path.clone(), file_path_string.to_owned(), file))
-}
-
-fn handle_expansion_dropdown_animation(path: &String, _: &String, file: Animation) -> Option<(String, String, Animation)> {
-    let new_file_string = format!("{}.{}", EXPANSION_OPENZT_RESOURCE_PREFIX, EXPANSION_RESOURCE_ANIMATION);
-    let file_path = Path::new(&new_file_string);
-    let Some(file_path_string) = file_path.to_str() else {
-        error!("Error converting file path to string");
-        return None;
-    };
-    Some((path.clone(), file_path_string.to_owned(), file))
+    if let Err(e) = add_alias(&new_file_string, file_name, None) {
+        error!("Error adding expansion dropdown alias {} -> {}: {}", new_file_string, file_name, e);
+    }
 }
 
 pub fn init() {
@@ -785,9 +647,7 @@ pub fn init() {
     add_handler(Handler::builder()
                     .prefix(EXPANSION_ZT_RESOURCE_PREFIX)
                     .run_stage(RunStage::BeforeOpenZTMods)
-                    .ini_handler(handle_expansion_dropdown_ani)
-                    .raw_bytes_handler(handle_expansion_dropdown_raw_bytes)
-                    .animation_handler(handle_expansion_dropdown_animation)
+                    .path_handler(handle_expansion_dropdown)
                     .build());
     if unsafe { custom_expansion::init_detours() }.is_err() {
         error!("Error initialising custom expansion detours");
@@ -0,0 +1,34 @@
+use std::{env, fs, path::Path, process};
+
+use zt_formats::{animation::Animation, palette::Palette, sprite};
+
+const USAGE: &str = "Usage: export_animation <animation> <palette> <output_dir> [-s]";
+
+fn main() {
+    if let Err(e) = run(env::args().skip(1).collect()) {
+        eprintln!("{:#}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> anyhow::Result<()> {
+    let sheet = args.iter().any(|arg| arg == "-s");
+    let positional = args.iter().filter(|arg| *arg != "-s").collect::<Vec<&String>>();
+    let [animation_path, palette_path, directory] = positional[..] else {
+        return Err(anyhow::anyhow!(USAGE));
+    };
+
+    let animation = Animation::parse(&fs::read(animation_path)?)?;
+    let palette = Palette::parse(&fs::read(palette_path)?)?;
+    let name = Path::new(animation_path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or("animation".to_string());
+
+    let paths = if sheet {
+        sprite::export_sprite_sheet(&animation, &palette, Path::new(directory), &name)?
+    } else {
+        sprite::export_frames(&animation, &palette, Path::new(directory), &name)?
+    };
+    for path in paths {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
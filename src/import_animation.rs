@@ -0,0 +1,61 @@
+use std::{env, fs, path::Path, process};
+
+use zt_formats::{
+    palette::{Palette, MAX_PALETTE_COLORS},
+    sprite::{self, RgbaImage, DEFAULT_ANIMATION_SPEED},
+};
+
+const USAGE: &str = "Usage: import_animation <output> <palette_file> <palette_resource_path> <anchor_x> <anchor_y> <frame.png>... [-t speed]\n\
+                     If palette_file doesn't exist a palette is generated from the frames and written there";
+
+fn main() {
+    if let Err(e) = run(env::args().skip(1).collect()) {
+        eprintln!("{:#}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> anyhow::Result<()> {
+    let mut animation_speed = DEFAULT_ANIMATION_SPEED;
+    let mut positional = Vec::new();
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "-t" => animation_speed = args_iter.next().ok_or_else(|| anyhow::anyhow!(USAGE))?.parse()?,
+            _ => positional.push(arg),
+        }
+    }
+    let [output, palette_path, palette_resource_path, anchor_x, anchor_y, frames @ ..] = &positional[..] else {
+        return Err(anyhow::anyhow!(USAGE));
+    };
+    if frames.is_empty() {
+        return Err(anyhow::anyhow!(USAGE));
+    }
+
+    let mut images = Vec::with_capacity(frames.len());
+    for frame in frames.iter() {
+        images.push(RgbaImage::from_png(&fs::read(frame)?)?);
+    }
+
+    let palette = if Path::new(palette_path).exists() {
+        Palette::parse(&fs::read(palette_path)?)?
+    } else {
+        let palette = sprite::generate_palette(&images, MAX_PALETTE_COLORS);
+        println!("Generated palette with {} colors: {}", palette.colors.len(), palette_path);
+        fs::write(palette_path, palette.clone().write().0)?;
+        palette
+    };
+
+    let animation = sprite::images_to_animation(
+        &images,
+        &palette,
+        palette_resource_path.to_string(),
+        anchor_x.parse()?,
+        anchor_y.parse()?,
+        animation_speed,
+    )?;
+    let (bytes, _) = animation.write();
+    fs::write(output, bytes)?;
+    println!("Wrote {} frames to {}", frames.len(), output);
+    Ok(())
+}
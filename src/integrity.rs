@@ -0,0 +1,363 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::Context;
+use bf_configparser::ini::Ini;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tracing::{error, info, warn};
+
+use crate::{
+    console::{add_to_command_register, CommandError},
+    debug_dll::get_base_path,
+    resource_manager::{get_official_resources_overridden_by_cc, is_official_archive},
+};
+
+// Shipped with OpenZT so an install that is already corrupted can't be used as the reference
+const EMBEDDED_MANIFEST: &str = include_str!("../resources/vanilla-manifest.toml");
+// Default output of generate_vanilla_manifest
+const VANILLA_MANIFEST_FILE: &str = "openzt_vanilla_manifest.toml";
+
+// zoo.ini's [resource] path, official archives are found and hashed from these directories directly.
+// The resource loader skips ztat* archives so the loaded archive list can't be used
+static RESOURCE_PATHS: Lazy<Mutex<Vec<PathBuf>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Set from [integrity] verify_on_startup in zoo.ini, hashing every official archive takes a few seconds so it's off by default
+static VERIFY_ON_STARTUP: AtomicBool = AtomicBool::new(false);
+
+/// Known good hashes of the official archives, keyed by archive file name. Each archive can have several known good versions
+#[derive(Serialize, Deserialize, Default)]
+struct VanillaManifest {
+    // Archives that aren't part of every install (expansions, downloads, languages), not reported when missing
+    #[serde(default)]
+    optional: Vec<String>,
+    // Groups of names the same archive has shipped under
+    #[serde(default)]
+    aliases: Vec<Vec<String>>,
+    archives: BTreeMap<String, Vec<ManifestEntry>>,
+}
+
+impl VanillaManifest {
+    // Every name the archive has shipped under, including its own. Archive names are compared case-insensitively like Windows does
+    fn names_of<'a>(&'a self, file_name: &'a str) -> Vec<&'a str> {
+        match self.aliases.iter().find(|group| group.iter().any(|alias| alias.eq_ignore_ascii_case(file_name))) {
+            Some(group) => group.iter().map(String::as_str).collect(),
+            None => vec![file_name],
+        }
+    }
+
+    fn is_optional(&self, file_name: &str) -> bool {
+        self.names_of(file_name)
+            .iter()
+            .any(|name| self.optional.iter().any(|optional| optional.eq_ignore_ascii_case(name)))
+    }
+
+    // Required archives that aren't installed under any of their names, an alias group is reported once by its first name
+    fn missing_archives(&self, installed: &[&str]) -> Vec<String> {
+        let mut missing: Vec<String> = Vec::new();
+        for file_name in self.archives.keys() {
+            let names = self.names_of(file_name);
+            if self.is_optional(file_name)
+                || installed.iter().any(|archive| names.iter().any(|name| name.eq_ignore_ascii_case(archive)))
+                || missing.iter().any(|archive| names.contains(&archive.as_str()))
+            {
+                continue;
+            }
+            missing.push(names[0].to_string());
+        }
+        missing
+    }
+
+    // Known good versions of the archive under any of its names, None if the manifest doesn't know the archive
+    fn known_good(&self, file_name: &str) -> Option<Vec<&ManifestEntry>> {
+        let mut known = None;
+        for name in self.names_of(file_name) {
+            for (_, entries) in self.archives.iter().filter(|(archive, _)| archive.eq_ignore_ascii_case(name)) {
+                known.get_or_insert_with(Vec::new).extend(entries.iter());
+            }
+        }
+        known
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct ManifestEntry {
+    size: u64,
+    sha1: String,
+}
+
+#[derive(Default)]
+struct VerificationReport {
+    verified: Vec<String>,
+    // Official archives whose size or hash doesn't match any known good version
+    modified: Vec<String>,
+    // Required by the manifest but not found in any resource path under any of its names
+    missing: Vec<String>,
+    // Official archives on disk that the manifest doesn't have any hashes for yet
+    unverified: Vec<String>,
+    // Archives on disk with an official name that the manifest doesn't know about
+    unknown: Vec<String>,
+    // Archives that couldn't be read at all
+    unreadable: Vec<(String, String)>,
+}
+
+impl VerificationReport {
+    fn is_clean(&self) -> bool {
+        self.modified.is_empty() && self.missing.is_empty() && self.unreadable.is_empty()
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} verified, {} modified, {} missing, {} unverified, {} unknown, {} unreadable",
+            self.verified.len(),
+            self.modified.len(),
+            self.missing.len(),
+            self.unverified.len(),
+            self.unknown.len(),
+            self.unreadable.len()
+        )
+    }
+}
+
+impl std::fmt::Display for VerificationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for archive in self.modified.iter() {
+            writeln!(f, "modified: {}", archive)?;
+        }
+        for archive in self.missing.iter() {
+            writeln!(f, "missing: {}", archive)?;
+        }
+        for archive in self.unverified.iter() {
+            writeln!(f, "unverified: {}", archive)?;
+        }
+        for archive in self.unknown.iter() {
+            writeln!(f, "unknown: {}", archive)?;
+        }
+        for (archive, e) in self.unreadable.iter() {
+            writeln!(f, "unreadable: {} ({})", archive, e)?;
+        }
+        writeln!(f, "{}", self.summary())
+    }
+}
+
+pub fn load_integrity_settings(zoo_ini: &Ini) {
+    let verify = zoo_ini
+        .get("integrity", "verify_on_startup")
+        .is_some_and(|value| matches!(value.trim().to_lowercase().as_str(), "true" | "yes" | "1"));
+    VERIFY_ON_STARTUP.store(verify, Ordering::Relaxed);
+    if let Some(paths) = zoo_ini.get("resource", "path") {
+        *RESOURCE_PATHS.lock().unwrap() = paths.split(';').map(|path| PathBuf::from(path.trim())).collect();
+    }
+}
+
+/// Run once resources are loaded, warns about CC overriding official files and verifies official archives if enabled in zoo.ini
+pub fn run_startup_checks() {
+    let overrides = get_official_resources_overridden_by_cc();
+    if !overrides.is_empty() {
+        warn!(
+            "{} official resources are overridden by custom content, run list_cc_overrides for details",
+            overrides.len()
+        );
+    }
+
+    if !VERIFY_ON_STARTUP.load(Ordering::Relaxed) {
+        return;
+    }
+    match load_manifest(None).map(|manifest| verify_vanilla(&manifest)) {
+        Ok(report) if report.is_clean() => info!("Official archives verified: {}", report.summary()),
+        Ok(report) => warn!("Official archives don't match the manifest, the install may be corrupted:\n{}", report),
+        Err(e) => error!("Error verifying official archives: {}", e),
+    }
+}
+
+fn hash_archive(path: &Path) -> anyhow::Result<ManifestEntry> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha1::new();
+    let size = io::copy(&mut reader, &mut hasher)?;
+    Ok(ManifestEntry {
+        size,
+        sha1: format!("{:x}", hasher.finalize()),
+    })
+}
+
+// Every official archive in the resource paths, the same name can be in more than one path (e.g. an update replacing an archive)
+fn official_archives_on_disk() -> Vec<(String, PathBuf)> {
+    let mut archives = Vec::new();
+    for dir in RESOURCE_PATHS.lock().unwrap().iter() {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let Some(file_name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if is_official_archive(&file_name) && entry.path().is_file() {
+                archives.push((file_name, entry.path()));
+            }
+        }
+    }
+    archives.sort();
+    archives
+}
+
+// The embedded manifest unless a path is given
+fn load_manifest(manifest_path: Option<&Path>) -> anyhow::Result<VanillaManifest> {
+    match manifest_path {
+        Some(manifest_path) => {
+            let manifest_string = fs::read_to_string(manifest_path).with_context(|| format!("Failed to read {}", manifest_path.display()))?;
+            toml::from_str::<VanillaManifest>(&manifest_string).with_context(|| format!("Failed to parse {}", manifest_path.display()))
+        }
+        None => toml::from_str::<VanillaManifest>(EMBEDDED_MANIFEST).with_context(|| "Failed to parse embedded vanilla manifest"),
+    }
+}
+
+fn verify_vanilla(manifest: &VanillaManifest) -> VerificationReport {
+    let archives = official_archives_on_disk();
+
+    let mut report = VerificationReport::default();
+    let installed = archives.iter().map(|(archive_name, _)| archive_name.as_str()).collect::<Vec<&str>>();
+    report.missing = manifest.missing_archives(&installed);
+    for (file_name, path) in archives.iter() {
+        let Some(known_good) = manifest.known_good(file_name) else {
+            report.unknown.push(path.display().to_string());
+            continue;
+        };
+        if known_good.is_empty() {
+            report.unverified.push(path.display().to_string());
+            continue;
+        }
+        match hash_archive(path) {
+            Ok(actual) if known_good.contains(&&actual) => report.verified.push(path.display().to_string()),
+            Ok(_) => report.modified.push(path.display().to_string()),
+            Err(e) => report.unreadable.push((path.display().to_string(), e.to_string())),
+        }
+    }
+    report
+}
+
+// Writes the hashes of the official archives in this install in the manifest format, to be checked and added to the embedded manifest.
+// Optional archives and aliases are kept from the embedded manifest
+fn generate_vanilla_manifest(manifest_path: &Path) -> anyhow::Result<usize> {
+    let embedded = load_manifest(None)?;
+    let mut manifest = VanillaManifest {
+        optional: embedded.optional,
+        aliases: embedded.aliases,
+        archives: BTreeMap::new(),
+    };
+    for (file_name, path) in official_archives_on_disk() {
+        let entry = hash_archive(&path).with_context(|| format!("Failed to hash {}", path.display()))?;
+        let known_good = manifest.archives.entry(file_name).or_default();
+        if !known_good.contains(&entry) {
+            known_good.push(entry);
+        }
+    }
+    fs::write(manifest_path, toml::to_string(&manifest)?).with_context(|| format!("Failed to write {}", manifest_path.display()))?;
+    Ok(manifest.archives.len())
+}
+
+
+fn command_verify_vanilla(args: Vec<&str>) -> Result<String, CommandError> {
+    if args.len() > 1 {
+        return Err(Into::into("Usage: verify_vanilla [manifest_path]"));
+    }
+    let manifest = load_manifest(args.first().map(Path::new)).map_err(|e| CommandError::from(format!("{:#}", e)))?;
+    Ok(verify_vanilla(&manifest).to_string())
+}
+
+fn command_generate_vanilla_manifest(args: Vec<&str>) -> Result<String, CommandError> {
+    if args.len() > 1 {
+        return Err(Into::into("Usage: generate_vanilla_manifest [manifest_path]"));
+    }
+    let manifest_path = match args.first() {
+        Some(path) => PathBuf::from(path),
+        None => get_base_path().join(VANILLA_MANIFEST_FILE),
+    };
+    let count = generate_vanilla_manifest(&manifest_path).map_err(|e| CommandError::from(format!("{:#}", e)))?;
+    Ok(format!("Wrote hashes of {} official archives to {}", count, manifest_path.display()))
+}
+
+fn command_list_cc_overrides(args: Vec<&str>) -> Result<String, CommandError> {
+    if args.len() > 1 {
+        return Err(Into::into("Usage: list_cc_overrides [filter]"));
+    }
+    let filter = args.first().map(|filter| filter.to_lowercase());
+    let mut result = String::new();
+    let mut count = 0;
+    for (file_name, providers) in get_official_resources_overridden_by_cc() {
+        if filter.as_ref().is_some_and(|filter| !file_name.contains(filter)) {
+            continue;
+        }
+        count += 1;
+        let providers = providers.iter().map(|(archive_name, layer)| format!("{} ({})", archive_name, layer)).collect::<Vec<String>>();
+        result.push_str(&format!("{}: {}\n", file_name, providers.join(" -> ")));
+    }
+    result.push_str(&format!("{} official resources overridden by custom content\n", count));
+    Ok(result)
+}
+
+pub fn init() {
+    add_to_command_register("verify_vanilla".to_string(), command_verify_vanilla);
+    add_to_command_register("generate_vanilla_manifest".to_string(), command_generate_vanilla_manifest);
+    add_to_command_register("list_cc_overrides".to_string(), command_list_cc_overrides);
+}
+
+#[cfg(test)]
+mod parsing_tests {
+    use super::{load_manifest, ManifestEntry};
+    use crate::resource_manager::is_official_archive;
+
+    #[test]
+    fn test_embedded_manifest() {
+        let manifest = load_manifest(None).unwrap();
+        assert!(!manifest.archives.is_empty());
+        assert!(manifest.archives.keys().all(|archive| is_official_archive(archive)));
+        assert!(manifest.archives.contains_key("ztatb00.ztd"));
+        assert!(manifest.optional.iter().all(|archive| manifest.archives.contains_key(archive)));
+        assert!(manifest.aliases.iter().flatten().all(|archive| manifest.archives.contains_key(archive)));
+        assert!(is_official_archive("ZTATB00.ZTD"));
+        assert!(!manifest.missing_archives(&["animals.ztd"]).contains(&"yeti.ztd".to_string()));
+        assert!(manifest.missing_archives(&["animals.ztd"]).contains(&"ui.ztd".to_string()));
+        assert!(!manifest.missing_archives(&["ANIMALS.ZTD"]).contains(&"animals.ztd".to_string()));
+    }
+
+    #[test]
+    fn test_manifest_aliases() {
+        let manifest = toml::from_str::<super::VanillaManifest>(
+            "optional = [\"yeti.ztd\"]\naliases = [[\"Asian Elephant.ztd\", \"asian_elephant.ztd\"]]\n[archives]\n\"Asian Elephant.ztd\" = []\n\"asian_elephant.ztd\" = [{ size = 10, sha1 = \"a\" }]\n\"yeti.ztd\" = []\n",
+        )
+        .unwrap();
+        assert_eq!(manifest.missing_archives(&[]), vec!["Asian Elephant.ztd".to_string()]);
+        assert!(manifest.missing_archives(&["ASIAN_ELEPHANT.ZTD"]).is_empty());
+        assert!(manifest.missing_archives(&["Asian Elephant.ztd"]).is_empty());
+        let known_good = manifest.known_good("asian elephant.ztd").unwrap();
+        assert!(known_good.contains(&&ManifestEntry {
+            size: 10,
+            sha1: "a".to_string()
+        }));
+        assert!(manifest.known_good("zebra.ztd").is_none());
+        assert_eq!(manifest.known_good("yeti.ztd").map(|entries| entries.len()), Some(0));
+    }
+
+    #[test]
+    fn test_manifest_versions() {
+        let manifest = toml::from_str::<super::VanillaManifest>(
+            "[archives]\n\"ui5.ztd\" = [{ size = 10, sha1 = \"a\" }, { size = 12, sha1 = \"b\" }]\n\"config5.ztd\" = []\n",
+        )
+        .unwrap();
+        let known_good = &manifest.archives["ui5.ztd"];
+        assert!(known_good.contains(&ManifestEntry {
+            size: 12,
+            sha1: "b".to_string()
+        }));
+        assert!(manifest.archives["config5.ztd"].is_empty());
+    }
+}
@@ -30,9 +30,15 @@ mod string_registry;
 
 mod common;
 
-mod parsing;
+mod colorrep;
 
-mod animation;
+mod ani;
+
+mod missing_resources;
+
+mod resource_graph;
+
+mod integrity;
 
 mod bfentitytype;
 
@@ -148,6 +154,9 @@ extern "system" fn DllMain(module: u8, reason: u32, _reserved: u8) -> i32 {
 
             // Initialize stable modules
             resource_manager::init();
+            missing_resources::init();
+            resource_graph::init();
+            integrity::init();
             expansions::init();
             string_registry::init();
             bugfix::init();
@@ -0,0 +1,135 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use tracing::info;
+
+use crate::{
+    console::{add_to_command_register, CommandError},
+    debug_dll::get_base_path,
+    resource_graph::with_built_graph,
+};
+
+const MISSING_RESOURCES_REPORT_FILE: &str = "openzt_missing_resources.txt";
+
+// Times are recorded relative to when OpenZT was loaded
+static START_TIME: Lazy<Instant> = Lazy::new(Instant::now);
+
+static MISSING_RESOURCES: Lazy<Mutex<HashMap<String, MissingResource>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone)]
+struct MissingResource {
+    file_name: String,
+    count: u32,
+    first_requested: Duration,
+    last_requested: Duration,
+    // Cfgs, ai files, animations etc. that reference the file, filled in from the resource graph when reporting
+    // as the game doesn't tell us who asked for it
+    requesters: Option<BTreeSet<String>>,
+}
+
+impl fmt::Display for MissingResource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let requesters = match &self.requesters {
+            Some(requesters) if requesters.is_empty() => "nothing references it".to_string(),
+            Some(requesters) => format!("referenced by {}", requesters.iter().cloned().collect::<Vec<String>>().join(", ")),
+            None => "references unknown, the resource graph is still being built".to_string(),
+        };
+        write!(
+            f,
+            "{} requested {} times (first: {:.1?}, last: {:.1?}), {}",
+            self.file_name, self.count, self.first_requested, self.last_requested, requesters
+        )
+    }
+}
+
+/// Records a resource that neither OpenZT nor the vanilla resource manager could provide when the game prepared it.
+/// ztat* files are skipped as they always have been when logging misses
+pub fn record_missing_resource(file_name: &str) {
+    let now = START_TIME.elapsed();
+    let key = file_name.to_lowercase();
+    if key.starts_with("ztat") {
+        return;
+    }
+    let mut data_mutex = MISSING_RESOURCES.lock().unwrap();
+    let entry = data_mutex.entry(key.clone()).or_insert_with(|| {
+        info!("Missing resource: {}", key);
+        MissingResource {
+            file_name: key,
+            count: 0,
+            first_requested: now,
+            last_requested: now,
+            requesters: None,
+        }
+    });
+    entry.count += 1;
+    entry.last_requested = now;
+}
+
+// Most requested first, ties are sorted by name so reports are stable
+fn get_missing_resources(filter: Option<&str>) -> Vec<MissingResource> {
+    let data_mutex = MISSING_RESOURCES.lock().unwrap();
+    let mut missing_resources = data_mutex
+        .values()
+        .filter(|missing_resource| filter.is_none_or(|filter| missing_resource.file_name.contains(filter)))
+        .cloned()
+        .collect::<Vec<MissingResource>>();
+    drop(data_mutex);
+    missing_resources.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.file_name.cmp(&b.file_name)));
+    with_built_graph(|graph| {
+        for missing_resource in missing_resources.iter_mut() {
+            missing_resource.requesters = Some(graph.missing_dependents(&missing_resource.file_name).into_iter().cloned().collect());
+        }
+    });
+    missing_resources
+}
+
+fn missing_resources_report(filter: Option<&str>) -> String {
+    let missing_resources = get_missing_resources(filter);
+    let mut result = String::new();
+    for missing_resource in missing_resources.iter() {
+        result.push_str(&format!("{}\n", missing_resource));
+    }
+    result.push_str(&format!("{} missing resources\n", missing_resources.len()));
+    result
+}
+
+fn command_list_missing_resources(args: Vec<&str>) -> Result<String, CommandError> {
+    if args.len() > 1 {
+        return Err(Into::into("Usage: list_missing_resources [filter]"));
+    }
+    let filter = args.first().map(|filter| filter.to_lowercase());
+    Ok(missing_resources_report(filter.as_deref()))
+}
+
+fn command_dump_missing_resources(args: Vec<&str>) -> Result<String, CommandError> {
+    if args.len() > 1 {
+        return Err(Into::into("Usage: dump_missing_resources [path]"));
+    }
+    let path = match args.first() {
+        Some(path) => PathBuf::from(path),
+        None => get_base_path().join(MISSING_RESOURCES_REPORT_FILE),
+    };
+    fs::write(&path, missing_resources_report(None)).map_err(|e| CommandError::from(format!("Failed to write {}: {}", path.display(), e)))?;
+    Ok(format!("Wrote missing resources to {}", path.display()))
+}
+
+fn command_clear_missing_resources(_args: Vec<&str>) -> Result<String, CommandError> {
+    let mut data_mutex = MISSING_RESOURCES.lock().unwrap();
+    let count = data_mutex.len();
+    data_mutex.clear();
+    Ok(format!("Cleared {} missing resources", count))
+}
+
+pub fn init() {
+    Lazy::force(&START_TIME);
+    add_to_command_register("list_missing_resources".to_string(), command_list_missing_resources);
+    add_to_command_register("dump_missing_resources".to_string(), command_dump_missing_resources);
+    add_to_command_register("clear_missing_resources".to_string(), command_clear_missing_resources);
+}
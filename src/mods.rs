@@ -5,6 +5,7 @@ use serde::{
     de::{self, Deserializer, Visitor},
     Deserialize,
 };
+use zt_formats::palette::Color;
 
 #[derive(Debug)]
 pub struct ParseError {
@@ -175,6 +176,8 @@ pub enum Ordering {
 pub struct ModDefinition {
     habitats: Option<HashMap<String, IconDefinition>>,
     locations: Option<HashMap<String, IconDefinition>>,
+    color_variants: Option<HashMap<String, ColorVariantDefinition>>,
+    ui_images: Option<HashMap<String, UiImageDefinition>>,
 }
 
 impl ModDefinition {
@@ -186,6 +189,12 @@ impl ModDefinition {
         if let Some(locations) = &self.locations {
             len += locations.len();
         }
+        if let Some(color_variants) = &self.color_variants {
+            len += color_variants.len();
+        }
+        if let Some(ui_images) = &self.ui_images {
+            len += ui_images.len();
+        }
         len
     }
 }
@@ -194,13 +203,95 @@ impl ModDefinition {
 #[get = "pub"]
 pub struct IconDefinition {
     name: String,
+    // Either a legacy animation or a PNG, PNGs are converted with a generated palette when the icon is first drawn
     icon_path: String,
-    icon_palette_path: String,
+    // Only needed for legacy animations
+    icon_palette_path: Option<String>,
+}
+
+/// A new [colorrep] palette for an existing entity type, generated by replacing colors in one of its palettes
+#[derive(Deserialize, Debug, Getters)]
+#[get = "pub"]
+pub struct ColorVariantDefinition {
+    name: String,
+    // Type name or codename of the entity type, as shown by sel_type
+    entity_type: String,
+    // Config file with the entity's [colorrep] section, e.g. guests/man.cfg
+    config_file: String,
+    // Either a file in the mod or an existing resource
+    base_palette: String,
+    replacements: Vec<ColorReplacement>,
+}
+
+#[derive(Deserialize, Debug, Getters, Clone, Copy)]
+#[get = "pub"]
+pub struct ColorReplacement {
+    #[serde(deserialize_with = "deserialize_color")]
+    from: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    to: Color,
+}
+
+// Hex rgb e.g. "#3050c0" or "3050c0", palettes colors are opaque
+fn parse_hex_color(s: &str) -> Result<Color, ParseError> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(ParseError::new(format!("Invalid color: {} (expected hex rgb e.g. '#3050c0')", s)));
+    }
+    let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16);
+    Ok(Color::new(channel(0)?, channel(2)?, channel(4)?, 0xff))
+}
+
+fn deserialize_color<'de, D>(deserializer: D) -> Result<Color, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct ColorVisitor;
+
+    impl<'de> Visitor<'de> for ColorVisitor {
+        type Value = Color;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a hex color string e.g. '#3050c0'")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            parse_hex_color(value).map_err(de::Error::custom)
+        }
+    }
+
+    deserializer.deserialize_str(ColorVisitor)
+}
+
+/// Replaces or extends a TGA or BMP used by the UI with a PNG from the mod, the result is written in the same format as the original
+#[derive(Deserialize, Debug, Getters)]
+#[get = "pub"]
+pub struct UiImageDefinition {
+    // Resource path of the image, e.g. ui/sharedui/button.tga
+    target: String,
+    image_path: String,
+    #[serde(default)]
+    mode: UiImageMode,
+}
+
+#[derive(Deserialize, Default, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum UiImageMode {
+    #[default]
+    Replace,
+    // Adds the PNG to the bottom or right of the original, e.g. a new state for a button whose states are stacked
+    AppendBelow,
+    AppendRight,
 }
 
 #[cfg(test)]
 mod mod_loading_tests {
-    use crate::mods::Version;
+    use zt_formats::palette::Color;
+
+    use crate::mods::{parse_hex_color, Version};
 
     #[test]
     fn test_parse_meta() {
@@ -240,13 +331,13 @@ mod mod_loading_tests {
     fn check_moon_location(location: &super::IconDefinition) {
         assert_eq!(location.name, "Moon");
         assert_eq!(location.icon_path, "resources/moon/N");
-        assert_eq!(location.icon_palette_path, "resources/moon/moon.pal");
+        assert_eq!(location.icon_palette_path, Some("resources/moon/moon.pal".to_string()));
     }
 
     fn check_swamp_habitat(habitat: &super::IconDefinition) {
         assert_eq!(habitat.name, "Swamp");
         assert_eq!(habitat.icon_path, "resources/swamp/N");
-        assert_eq!(habitat.icon_palette_path, "resources/swamp/swamp.pal");
+        assert_eq!(habitat.icon_palette_path, Some("resources/swamp/swamp.pal".to_string()));
     }
 
     #[test]
@@ -265,6 +356,16 @@ mod mod_loading_tests {
         check_swamp_habitat(habitat);
     }
 
+    #[test]
+    fn test_parse_png_icon() {
+        let mods: super::ModDefinition = toml::from_str(include_str!("../resources/test/example-png-habitat.toml")).unwrap();
+        let habitats = mods.habitats.unwrap();
+        let habitat = habitats.get("swamp").unwrap();
+        assert_eq!(habitat.name, "Swamp");
+        assert_eq!(habitat.icon_path, "resources/swamp/icon.png");
+        assert_eq!(habitat.icon_palette_path, None);
+    }
+
     #[test]
     fn test_parse_combined() {
         let mods: super::ModDefinition = toml::from_str(include_str!("../resources/test/example-combined.toml")).unwrap();
@@ -277,4 +378,41 @@ mod mod_loading_tests {
         let habitat = habitats.get("swamp").unwrap();
         check_swamp_habitat(habitat);
     }
+
+    #[test]
+    fn test_parse_color_variant() {
+        let mods: super::ModDefinition = toml::from_str(include_str!("../resources/test/example-color-variant.toml")).unwrap();
+        assert_eq!(mods.len(), 1);
+        let color_variants = mods.color_variants.unwrap();
+        let variant = color_variants.get("green_shirt").unwrap();
+        assert_eq!(variant.name, "Green Shirt");
+        assert_eq!(variant.entity_type, "man");
+        assert_eq!(variant.config_file, "guests/man.cfg");
+        assert_eq!(variant.base_palette, "guests/shirts/red.pal");
+        assert_eq!(variant.replacements.len(), 2);
+        assert_eq!(variant.replacements[0].from, Color::new(0xc0, 0x20, 0x20, 0xff));
+        assert_eq!(variant.replacements[0].to, Color::new(0x20, 0xc0, 0x20, 0xff));
+        assert_eq!(variant.replacements[1].from, Color::new(0x80, 0x10, 0x10, 0xff));
+    }
+
+    #[test]
+    fn test_parse_invalid_color() {
+        assert!(parse_hex_color("#12345").is_err());
+        assert!(parse_hex_color("zz0000").is_err());
+        assert_eq!(parse_hex_color("#0a0B0c").unwrap(), Color::new(0x0a, 0x0b, 0x0c, 0xff));
+    }
+
+    #[test]
+    fn test_parse_ui_images() {
+        let mods: super::ModDefinition = toml::from_str(include_str!("../resources/test/example-ui-images.toml")).unwrap();
+        assert_eq!(mods.len(), 2);
+        let ui_images = mods.ui_images.unwrap();
+        let button = ui_images.get("button").unwrap();
+        assert_eq!(button.target, "ui/sharedui/button.tga");
+        assert_eq!(button.image_path, "resources/ui/button.png");
+        assert_eq!(button.mode, super::UiImageMode::AppendBelow);
+        let background = ui_images.get("background").unwrap();
+        assert_eq!(background.target, "ui/startup/background.bmp");
+        assert_eq!(background.mode, super::UiImageMode::Replace);
+    }
 }
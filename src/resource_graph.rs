@@ -0,0 +1,378 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use bf_configparser::ini::Ini;
+use once_cell::sync::Lazy;
+use tracing::{error, info};
+use zt_formats::animation::Animation;
+
+use crate::{
+    console::{add_to_command_register, CommandError},
+    resource_manager::{
+        cfg_path_references, get_resource_names, legacy_cfg_references, normalise_cfg_path, peek_file, read_ini, wait_for_resources, ZTFileType,
+    },
+};
+
+// Built in the background the first time a graph command is run, rebuild_resource_graph is needed to pick up changes after that
+static RESOURCE_GRAPH: Lazy<Mutex<Option<ResourceGraph>>> = Lazy::new(|| Mutex::new(None));
+
+// Set while a build thread is running so commands run during a build don't start another one
+static RESOURCE_GRAPH_BUILDING: AtomicBool = AtomicBool::new(false);
+
+/// Which resources reference which, keys and references are lowercase resource paths.
+/// References that don't exist in the resource map are dangling, note that files in ztat* archives are never indexed so references to them will show up as dangling
+#[derive(Default)]
+pub struct ResourceGraph {
+    dependencies: HashMap<String, BTreeSet<String>>,
+    dependents: HashMap<String, BTreeSet<String>>,
+    dangling: HashMap<String, BTreeSet<String>>,
+    files: BTreeSet<String>,
+}
+
+impl ResourceGraph {
+    /// Reads every file that can reference another file, this can take a while with a lot of mods installed so use build_in_background from the game thread
+    pub fn build() -> ResourceGraph {
+        let mut graph = ResourceGraph {
+            files: get_resource_names().into_iter().collect(),
+            ..Default::default()
+        };
+        let referencing_files = graph.files.iter().filter(|file_name| holds_references(file_name)).cloned().collect::<Vec<String>>();
+        for file_name in referencing_files {
+            for reference in find_references(&file_name) {
+                graph.add_reference(&file_name, reference);
+            }
+        }
+        info!(
+            "Built resource graph with {} files, {} referencing files and {} files with dangling references",
+            graph.files.len(),
+            graph.dependencies.len(),
+            graph.dangling.len()
+        );
+        graph
+    }
+
+    fn add_reference(&mut self, from: &str, to: String) {
+        if to == from {
+            return;
+        }
+        if !self.files.contains(&to) {
+            self.dangling.entry(from.to_string()).or_default().insert(to);
+            return;
+        }
+        self.dependents.entry(to.clone()).or_default().insert(from.to_string());
+        self.dependencies.entry(from.to_string()).or_default().insert(to);
+    }
+
+    /// Files that reference file_name
+    pub fn dependents(&self, file_name: &str) -> Option<&BTreeSet<String>> {
+        self.dependents.get(file_name)
+    }
+
+    /// Files that file_name references
+    pub fn dependencies(&self, file_name: &str) -> Option<&BTreeSet<String>> {
+        self.dependencies.get(file_name)
+    }
+
+    /// Files that would normally be referenced by something (ai, ani, animation, palette, ...) but nothing references
+    pub fn orphans(&self) -> Vec<&String> {
+        self.files
+            .iter()
+            .filter(|file_name| is_referenced_type(file_name) && !self.dependents.contains_key(*file_name))
+            .collect()
+    }
+
+    /// Files that reference file_name when it doesn't exist
+    pub fn missing_dependents(&self, file_name: &str) -> Vec<&String> {
+        let mut dependents = self.dangling.iter().filter(|(_, missing)| missing.contains(file_name)).map(|(from, _)| from).collect::<Vec<&String>>();
+        dependents.sort();
+        dependents
+    }
+
+    /// Files with references to resources that don't exist, along with the missing references
+    pub fn dangling(&self) -> Vec<(&String, &BTreeSet<String>)> {
+        let mut dangling = self.dangling.iter().collect::<Vec<_>>();
+        dangling.sort_by(|a, b| a.0.cmp(b.0));
+        dangling
+    }
+}
+
+/// Builds the graph on its own thread once resources are loaded, returns false if a build is already running
+pub fn build_in_background() -> bool {
+    if RESOURCE_GRAPH_BUILDING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    let spawn_result = std::thread::Builder::new().name("openzt-resource-graph".to_string()).spawn(|| {
+        wait_for_resources();
+        let graph = ResourceGraph::build();
+        *RESOURCE_GRAPH.lock().unwrap() = Some(graph);
+        RESOURCE_GRAPH_BUILDING.store(false, Ordering::SeqCst);
+    });
+    if let Err(e) = spawn_result {
+        error!("Failed to start resource graph thread: {}", e);
+        RESOURCE_GRAPH_BUILDING.store(false, Ordering::SeqCst);
+        return false;
+    }
+    true
+}
+
+// Only these are read when building the graph, every other file type is a leaf
+fn holds_references(file_name: &str) -> bool {
+    matches!(
+        ZTFileType::try_from(Path::new(file_name)),
+        Ok(ZTFileType::Cfg
+            | ZTFileType::Ani
+            | ZTFileType::Ai
+            | ZTFileType::Uca
+            | ZTFileType::Ucs
+            | ZTFileType::Ucb
+            | ZTFileType::Scn
+            | ZTFileType::Lyt
+            | ZTFileType::Animation)
+    )
+}
+
+// Top level files (cfgs, layouts, scenarios, ...) are loaded by the game directly so they are never orphans
+fn is_referenced_type(file_name: &str) -> bool {
+    matches!(
+        ZTFileType::try_from(Path::new(file_name)),
+        Ok(ZTFileType::Ai | ZTFileType::Ani | ZTFileType::Uca | ZTFileType::Ucs | ZTFileType::Ucb | ZTFileType::Animation | ZTFileType::Palette)
+    )
+}
+
+fn find_references(file_name: &String) -> Vec<String> {
+    let Ok(file_type) = ZTFileType::try_from(Path::new(file_name)) else {
+        return Vec::new();
+    };
+    match file_type {
+        ZTFileType::Cfg => {
+            let Some(ini) = peek_ini(file_name) else {
+                return Vec::new();
+            };
+            match legacy_cfg_references(file_name, &ini) {
+                Some(files) => files.into_iter().map(|file| normalise_cfg_path(&file)).collect(),
+                None => cfg_path_references(&ini),
+            }
+        }
+        ZTFileType::Ani => {
+            let Some(ini) = peek_ini(file_name) else {
+                return Vec::new();
+            };
+            ani_animation_paths(&ini)
+        }
+        ZTFileType::Ai | ZTFileType::Uca | ZTFileType::Ucs | ZTFileType::Ucb | ZTFileType::Scn | ZTFileType::Lyt => {
+            let Some(ini) = peek_ini(file_name) else {
+                return Vec::new();
+            };
+            cfg_path_references(&ini)
+        }
+        ZTFileType::Animation => {
+            let Some(file) = peek_file(file_name) else {
+                return Vec::new();
+            };
+            match Animation::parse(&file) {
+                Ok(animation) if !animation.palette_filename.is_empty() => vec![normalise_cfg_path(&animation.palette_filename)],
+                Ok(_) => Vec::new(),
+                Err(e) => {
+                    info!("Error reading animation {} for the resource graph: {}", file_name, e);
+                    Vec::new()
+                }
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn peek_ini(file_name: &str) -> Option<Ini> {
+    let file = peek_file(file_name)?;
+    read_ini(file_name, &file)
+}
+
+/// An .ani file points at its animations via dir0..dirN and one or more animation values, e.g. dir0/dir1/animation
+pub fn ani_animation_paths(ini: &Ini) -> Vec<String> {
+    let map = ini.get_map().unwrap_or_default();
+    let Some(section) = map.get("animation") else {
+        return Vec::new();
+    };
+    let mut dirs = Vec::new();
+    while let Some(Some(values)) = section.get(&format!("dir{}", dirs.len())) {
+        let Some(dir) = values.first() else {
+            break;
+        };
+        dirs.push(dir.trim().to_string());
+    }
+    let Some(Some(animations)) = section.get("animation") else {
+        return Vec::new();
+    };
+    animations
+        .iter()
+        .map(|animation| {
+            let mut parts = dirs.clone();
+            parts.push(animation.trim().to_string());
+            normalise_cfg_path(&parts.join("/"))
+        })
+        .collect()
+}
+
+/// Runs function on the graph if it has been built, otherwise starts building it and returns None
+pub fn with_built_graph<F, T>(function: F) -> Option<T>
+where
+    F: FnOnce(&ResourceGraph) -> T,
+{
+    let result = RESOURCE_GRAPH.lock().unwrap().as_ref().map(function);
+    if result.is_none() {
+        build_in_background();
+    }
+    result
+}
+
+fn with_graph<F>(function: F) -> String
+where
+    F: FnOnce(&ResourceGraph) -> String,
+{
+    with_built_graph(function).unwrap_or_else(|| "Building the resource graph in the background, run the command again once it's done".to_string())
+}
+
+fn format_file_list<'a>(header: String, files: impl Iterator<Item = &'a String>) -> String {
+    let mut result = header;
+    result.push('\n');
+    for file in files {
+        result.push_str(&format!("{}\n", file));
+    }
+    result
+}
+
+fn command_resource_dependents(args: Vec<&str>) -> Result<String, CommandError> {
+    if args.len() != 1 {
+        return Err(Into::into("Usage: resource_dependents <path>"));
+    }
+    let file_name = args[0].to_lowercase();
+    Ok(with_graph(|graph| match graph.dependents(&file_name) {
+        Some(dependents) => format_file_list(format!("{} is referenced by {} files:", file_name, dependents.len()), dependents.iter()),
+        None => format!("Nothing references {}", file_name),
+    }))
+}
+
+fn command_resource_dependencies(args: Vec<&str>) -> Result<String, CommandError> {
+    if args.len() != 1 {
+        return Err(Into::into("Usage: resource_dependencies <path>"));
+    }
+    let file_name = args[0].to_lowercase();
+    Ok(with_graph(|graph| {
+        let mut result = match graph.dependencies(&file_name) {
+            Some(dependencies) => format_file_list(format!("{} references {} files:", file_name, dependencies.len()), dependencies.iter()),
+            None => format!("{} doesn't reference any files\n", file_name),
+        };
+        if let Some(missing) = graph.dangling.get(&file_name) {
+            result.push_str(&format_file_list(format!("{} missing references:", missing.len()), missing.iter()));
+        }
+        result
+    }))
+}
+
+fn command_resource_orphans(args: Vec<&str>) -> Result<String, CommandError> {
+    let filter = args.first().map(|filter| filter.to_lowercase());
+    Ok(with_graph(|graph| {
+        let orphans = graph
+            .orphans()
+            .into_iter()
+            .filter(|file_name| filter.as_ref().is_none_or(|filter| file_name.contains(filter)))
+            .collect::<Vec<&String>>();
+        format_file_list(format!("{} orphaned resources:", orphans.len()), orphans.into_iter())
+    }))
+}
+
+fn command_resource_dangling(args: Vec<&str>) -> Result<String, CommandError> {
+    let filter = args.first().map(|filter| filter.to_lowercase());
+    Ok(with_graph(|graph| {
+        let mut result = String::new();
+        let mut count = 0;
+        for (file_name, missing) in graph.dangling() {
+            if filter.as_ref().is_some_and(|filter| !file_name.contains(filter)) {
+                continue;
+            }
+            count += 1;
+            result.push_str(&format!("{} -> {}\n", file_name, missing.iter().cloned().collect::<Vec<String>>().join(", ")));
+        }
+        result.push_str(&format!("{} files with dangling references\n", count));
+        result
+    }))
+}
+
+// The old graph is kept until the new one is ready
+fn command_rebuild_resource_graph(_args: Vec<&str>) -> Result<String, CommandError> {
+    if build_in_background() {
+        Ok("Rebuilding the resource graph in the background".to_string())
+    } else {
+        Err(Into::into("The resource graph is already being built"))
+    }
+}
+
+pub fn init() {
+    add_to_command_register("resource_dependents".to_string(), command_resource_dependents);
+    add_to_command_register("resource_dependencies".to_string(), command_resource_dependencies);
+    add_to_command_register("resource_orphans".to_string(), command_resource_orphans);
+    add_to_command_register("resource_dangling".to_string(), command_resource_dangling);
+    add_to_command_register("rebuild_resource_graph".to_string(), command_rebuild_resource_graph);
+}
+
+#[cfg(test)]
+mod resource_graph_tests {
+    use super::{ani_animation_paths, ResourceGraph};
+    use crate::resource_manager::read_ini;
+
+    fn test_graph(files: &[&str], references: &[(&str, &str)]) -> ResourceGraph {
+        let mut graph = ResourceGraph {
+            files: files.iter().map(|file| file.to_string()).collect(),
+            ..Default::default()
+        };
+        for (from, to) in references {
+            graph.add_reference(from, to.to_string());
+        }
+        graph
+    }
+
+    #[test]
+    fn test_ani_animation_paths() {
+        let ini = read_ini("walk.ani", include_bytes!("../resources/test/walk.ani")).unwrap();
+        assert_eq!(
+            ani_animation_paths(&ini),
+            vec!["animals/aardvark/m/walk/n", "animals/aardvark/m/walk/ne", "animals/aardvark/m/walk/e"]
+        );
+    }
+
+    #[test]
+    fn test_ani_animation_paths_no_animation() {
+        let ini = read_ini("walk.ani", b"[animation]\ndir0 = animals\n").unwrap();
+        assert!(ani_animation_paths(&ini).is_empty());
+    }
+
+    #[test]
+    fn test_dangling_and_orphans() {
+        let graph = test_graph(
+            &["animals/aardvark.cfg", "animals/aardvark.ai", "animals/aardvark/m/walk/walk.ani", "animals/okapi.ai", "animals/okapi.cfg"],
+            &[
+                ("animals/aardvark.cfg", "animals/aardvark.ai"),
+                ("animals/aardvark.ai", "animals/aardvark/m/walk/walk.ani"),
+                ("animals/aardvark.ai", "animals/aardvark/m/run/run.ani"),
+                ("animals/okapi.cfg", "animals/aardvark/m/run/run.ani"),
+                // References to yourself are ignored
+                ("animals/okapi.ai", "animals/okapi.ai"),
+            ],
+        );
+        assert_eq!(graph.dependents("animals/aardvark.ai").unwrap().len(), 1);
+        assert_eq!(graph.dependencies("animals/aardvark.ai").unwrap().len(), 1);
+        let dangling = graph.dangling();
+        assert_eq!(dangling.len(), 2);
+        assert_eq!(dangling[0].0, "animals/aardvark.ai");
+        assert!(dangling[0].1.contains("animals/aardvark/m/run/run.ani"));
+        assert_eq!(graph.missing_dependents("animals/aardvark/m/run/run.ani"), vec!["animals/aardvark.ai", "animals/okapi.cfg"]);
+        // cfgs are loaded by the game directly so only the unreferenced ai is an orphan
+        assert_eq!(graph.orphans(), vec!["animals/okapi.ai"]);
+    }
+}
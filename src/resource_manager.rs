@@ -1,36 +1,266 @@
-use std::{fmt::Display, slice, str};
+use std::{fmt::Display, slice, str, str::FromStr};
 use std::{
-    collections::{HashMap, HashSet}, ffi::CString, fmt, fs::File, io::{self, BufReader, Read}, path::{Path, PathBuf}, sync::{Mutex, Arc},
+    collections::{HashMap, HashSet}, ffi::CString, fmt, fs::{self, File}, io::{self, BufReader, Read}, path::{Path, PathBuf}, sync::{Arc, Condvar, Mutex},
 };
 
 use anyhow::{anyhow, Context};
 
 use bf_configparser::ini::{Ini, WriteOptions};
+use maplit::hashset;
 use once_cell::sync::Lazy;
 use retour_utils::hook_module;
 use tracing::{error, info};
 use walkdir::WalkDir;
 use zip::read::{ZipArchive, ZipFile};
+use zt_formats::{
+    animation::Animation,
+    animation_diff::{diff_animations, export_diff},
+    palette::{Color, Palette},
+    sprite::{self, FrameBounds, RgbaImage},
+    ui_image::{stack_images, UiImage, UiImageFormat},
+};
 
 use regex::Regex;
 
 use crate::{
-    animation::Animation,
+    ani::AniFile,
+    colorrep::{add_color_variant, add_colorrep_palette, ColorVariant},
     console::{add_to_command_register, CommandError},
-    debug_dll::{get_from_memory, get_string_from_memory, save_to_memory},
+    debug_dll::{get_base_path, get_from_memory, get_string_from_memory, save_to_memory},
+    integrity::run_startup_checks,
     mods,
     string_registry::{add_string_to_registry, get_string_from_registry},
 };
 
 const GLOBAL_BFRESOURCEMGR_ADDRESS: u32 = 0x006380C0;
 
+/// BFResourcePtrs created by OpenZT start with this many refs, the game adds and removes its own refs on top of this
+/// so it never drops them to zero and frees memory it didn't allocate. Anything above this value is a game reference,
+/// OpenZT frees (or unloads back to the archive) resources it has given to the game once the game is back to none
+pub const OPENZT_PINNED_REFS: u32 = 0x1000_0000;
+
+// Resources given to the game are checked for released refs every this many acquires, see LazyResourceMap::release_unreferenced
+const RELEASE_CHECK_INTERVAL: usize = 256;
+
+static OFFICIAL_FILESET: Lazy<HashSet<&str>> = Lazy::new(|| {
+    hashset! {"animals8.ztd",
+        "awards5.ztd",
+        "config5.ztd",
+        "global03.ztd",
+        "items5.ztd",
+        "scenari5.ztd",
+        "scenery5.ztd",
+        "staff5.ztd",
+        "ui5.ztd",
+        "animals7.ztd",
+        "scn40.ztd",
+        "scn41.ztd",
+        "scn42.ztd",
+        "swordfsh.ztd",
+        "swordfsh01.ztd",
+        "ui7.ztd",
+        "wilddog.ztd",
+        "yeti.ztd",
+        "animalsa.ztd",
+        "antelope.ztd",
+        "aqtheme1.ztd",
+        "Asian Black Bear.ztd",
+        "Asian Elephant.ztd",
+        "asian_black_bear.ztd",
+        "asian_elephant.ztd",
+        "asian_elephant01.ztd",
+        "asian_elephant02.ztd",
+        "baracuda.ztd",
+        "blackbuck.ztd",
+        "bongo.ztd",
+        "config4.ztd",
+        "fancybld.ztd",
+        "gallim.ztd",
+        "gallim01.ztd",
+        "global06.ztd",
+        "guests7.ztd",
+        "items7.ztd",
+        "kidsdino.ztd",
+        "komodo.ztd",
+        "llama.ztd",
+        "magnet.ztd",
+        "magnet01.ztd",
+        "magnet02.ztd",
+        "megathrm.ztd",
+        "mexipack.ztd",
+        "mountainlion.ztd",
+        "mountainlion01.ztd",
+        "mt_lion.ztd",
+        "na_theme.ztd",
+        "object06.ztd",
+        "plateo.ztd",
+        "plateo01.ztd",
+        "reindeer.ztd",
+        "researm.ztd",
+        "scenari7.ztd",
+        "scenery7.ztd",
+        "scn16.ztd",
+        "scn17.ztd",
+        "scn18.ztd",
+        "ai.ztd",
+        "ambient.ztd",
+        "animals.ztd",
+        "animals2.ztd",
+        "awards.ztd",
+        "config.ztd",
+        "fences.ztd",
+        "freeform.ztd",
+        "fringe.ztd",
+        "global.ztd",
+        "guests.ztd",
+        "items.ztd",
+        "objects.ztd",
+        "paths.ztd",
+        "paths2.ztd",
+        "research.ztd",
+        "scenario.ztd",
+        "scenery.ztd",
+        "select.ztd",
+        "sounds.ztd",
+        "staff.ztd",
+        "terrain.ztd",
+        "tiles.ztd",
+        "ui.ztd",
+        "ztatb00.ztd",
+        "ztatb0a.ztd",
+        "ztatb0b.ztd",
+        "ztatb0d.ztd",
+        "ztatb01.ztd",
+        "ztatb02.ztd",
+        "ztatb03.ztd",
+        "ztatb04.ztd",
+        "ztatb05.ztd",
+        "ztatb06.ztd",
+        "ztatb07.ztd",
+        "ztatb08.ztd",
+        "ztatb09.ztd",
+        "ztatb10.ztd",
+        "zts.ztd",
+    }
+});
+
+/// Archives are loaded in layer order (lowest first) and resources from later archives override earlier ones,
+/// within a layer archives keep the order given by the [resource] path entries in zoo.ini
+/// Vanilla: the base game archives
+/// OfficialUpdate: official updates and expansion packs (zupdate, xpack1, ...)
+/// LegacyCc: any other ztd, this includes all custom content that isn't an OpenZT mod
+/// OpenZTMod: ztds with a meta.toml
+/// UserOverride: only assigned via the [resource_layers] section in zoo.ini
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ResourceLayer {
+    Vanilla,
+    OfficialUpdate,
+    LegacyCc,
+    OpenZTMod,
+    UserOverride,
+}
+
+impl FromStr for ResourceLayer {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_ascii_lowercase().as_str() {
+            "vanilla" => ResourceLayer::Vanilla,
+            "official_update" => ResourceLayer::OfficialUpdate,
+            "legacy_cc" => ResourceLayer::LegacyCc,
+            "openzt_mod" => ResourceLayer::OpenZTMod,
+            "user_override" => ResourceLayer::UserOverride,
+            _ => return Err("Invalid resource layer, expected vanilla, official_update, legacy_cc, openzt_mod or user_override"),
+        })
+    }
+}
+
+impl fmt::Display for ResourceLayer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ResourceLayer::Vanilla => "vanilla",
+            ResourceLayer::OfficialUpdate => "official_update",
+            ResourceLayer::LegacyCc => "legacy_cc",
+            ResourceLayer::OpenZTMod => "openzt_mod",
+            ResourceLayer::UserOverride => "user_override",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Default layer for an archive based on where it is and whether it is one of the official archives
+pub fn archive_layer(archive: &str) -> ResourceLayer {
+    let path = Path::new(archive);
+    let Some(parent) = path.parent() else {
+        return ResourceLayer::Vanilla;
+    };
+
+    let parent_name = parent.file_name().unwrap_or_default().to_str().unwrap_or_default();
+    match parent_name {
+        "zupdate" | "xpack1" | "zupdate1" | "xpack2" => ResourceLayer::OfficialUpdate,
+        "dlupdate" | "dupdate" | "updates" | "" => match path.file_name().unwrap_or_default().to_str().unwrap_or_default() {
+            "" => ResourceLayer::Vanilla,
+            file_name if is_official_archive(file_name) => {
+                if parent_name.is_empty() {
+                    ResourceLayer::Vanilla
+                } else {
+                    ResourceLayer::OfficialUpdate
+                }
+            }
+            _ => ResourceLayer::LegacyCc,
+        },
+        _ => ResourceLayer::LegacyCc,
+    }
+}
+
+// Overrides from the [resource_layers] section of zoo.ini, keyed by lowercase archive file name or mod id
+static RESOURCE_LAYER_OVERRIDES: Lazy<Mutex<HashMap<String, ResourceLayer>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Archive path, layer and mod id for OpenZT mods
+type LoadedArchive = (String, ResourceLayer, Option<String>);
+
+// Archives in the order they were loaded
+static LOADED_ARCHIVES: Lazy<Mutex<Vec<LoadedArchive>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Existing resources rewritten by OpenZT mods (or the console) keyed by lowercase file name, the value is the mod id that rewrote them
+static PATCHED_RESOURCES: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn load_resource_layer_overrides(zoo_ini: &Ini) {
+    let Some(section) = zoo_ini.get_map().unwrap_or_default().get("resource_layers").cloned() else {
+        return;
+    };
+    let mut overrides = RESOURCE_LAYER_OVERRIDES.lock().unwrap();
+    for (key, value) in section.iter() {
+        match value.as_ref().and_then(|values| values.last()).map(|value| value.parse::<ResourceLayer>()) {
+            Some(Ok(layer)) => {
+                info!("Using layer {} for {}", layer, key);
+                overrides.insert(key.to_lowercase(), layer);
+            }
+            Some(Err(e)) => error!("Error parsing [resource_layers] {}: {}", key, e),
+            None => error!("Missing value for [resource_layers] {}", key),
+        }
+    }
+}
+
+fn resolve_archive_layer(archive: &str, mod_id: Option<&str>) -> ResourceLayer {
+    let overrides = RESOURCE_LAYER_OVERRIDES.lock().unwrap();
+    let archive_file_name = Path::new(archive).file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+    if let Some(layer) = overrides.get(&archive_file_name) {
+        return *layer;
+    }
+    if let Some(mod_id) = mod_id {
+        return overrides.get(&mod_id.to_lowercase()).copied().unwrap_or(ResourceLayer::OpenZTMod);
+    }
+    archive_layer(archive)
+}
+
 #[derive(Debug, Clone)]
 pub enum ZTFile {
     Text(CString, ZTFileType, u32),
     RawBytes(Box<[u8]>, ZTFileType, u32),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ZTFileType {
     Ai,
     Ani,
@@ -81,6 +311,61 @@ impl TryFrom<&Path> for ZTFileType {
     }
 }
 
+// Parses the names used to refer to file types in console commands, either the file extension or "animation" for extensionless animation files
+impl FromStr for ZTFileType {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().trim_start_matches('.') {
+            "ai" => ZTFileType::Ai,
+            "ani" => ZTFileType::Ani,
+            "cfg" => ZTFileType::Cfg,
+            "lyt" => ZTFileType::Lyt,
+            "scn" => ZTFileType::Scn,
+            "uca" => ZTFileType::Uca,
+            "ucs" => ZTFileType::Ucs,
+            "ucb" => ZTFileType::Ucb,
+            "ini" => ZTFileType::Ini,
+            "txt" => ZTFileType::Txt,
+            "toml" => ZTFileType::Toml,
+            "tga" => ZTFileType::TGA,
+            "wav" => ZTFileType::Wav,
+            "lle" => ZTFileType::Lle,
+            "bmp" => ZTFileType::Bmp,
+            "pal" => ZTFileType::Palette,
+            "zoo" => ZTFileType::Zoo,
+            "animation" => ZTFileType::Animation,
+            _ => return Err("Invalid file type"),
+        })
+    }
+}
+
+impl fmt::Display for ZTFileType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ZTFileType::Ai => "ai",
+            ZTFileType::Ani => "ani",
+            ZTFileType::Cfg => "cfg",
+            ZTFileType::Lyt => "lyt",
+            ZTFileType::Scn => "scn",
+            ZTFileType::Uca => "uca",
+            ZTFileType::Ucs => "ucs",
+            ZTFileType::Ucb => "ucb",
+            ZTFileType::Ini => "ini",
+            ZTFileType::Txt => "txt",
+            ZTFileType::Toml => "toml",
+            ZTFileType::Animation => "animation",
+            ZTFileType::Palette => "pal",
+            ZTFileType::TGA => "tga",
+            ZTFileType::Wav => "wav",
+            ZTFileType::Lle => "lle",
+            ZTFileType::Bmp => "bmp",
+            ZTFileType::Zoo => "zoo",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 impl From<BFResourcePtr> for ZTFile {
     fn from(bf_resource_ptr: BFResourcePtr) -> Self {
         let filename = get_string_from_memory(bf_resource_ptr.bf_resource_name_ptr);
@@ -264,6 +549,106 @@ fn command_list_resource_strings(args: Vec<&str>) -> Result<String, CommandError
     Ok(result_string)
 }
 
+const SEARCH_RESOURCES_DEFAULT_LIMIT: usize = 100;
+
+const SEARCH_RESOURCES_USAGE: &str = "Usage: search_resources <glob|regex> [-r] [-t <file type>] [-a <archive>] [-n <limit>]";
+
+// Searches the resource map by glob (default) or regex (-r), optionally filtered by file type (-t) and a substring of the source archive (-a)
+fn command_search_resources(args: Vec<&str>) -> Result<String, CommandError> {
+    let mut pattern = None;
+    let mut use_regex = false;
+    let mut file_type = None;
+    let mut archive = None;
+    let mut limit = SEARCH_RESOURCES_DEFAULT_LIMIT;
+
+    let mut args_iter = args.into_iter();
+    while let Some(arg) = args_iter.next() {
+        match arg {
+            "-r" => use_regex = true,
+            "-t" => {
+                let Some(type_arg) = args_iter.next() else {
+                    return Err(CommandError::new(SEARCH_RESOURCES_USAGE.to_string()));
+                };
+                file_type = Some(ZTFileType::from_str(type_arg).map_err(|e| format!("{}: {}", e, type_arg))?);
+            }
+            "-a" => {
+                let Some(archive_arg) = args_iter.next() else {
+                    return Err(CommandError::new(SEARCH_RESOURCES_USAGE.to_string()));
+                };
+                archive = Some(archive_arg.to_lowercase());
+            }
+            "-n" => {
+                let Some(limit_arg) = args_iter.next() else {
+                    return Err(CommandError::new(SEARCH_RESOURCES_USAGE.to_string()));
+                };
+                limit = limit_arg.parse::<usize>()?;
+            }
+            _ if pattern.is_none() => pattern = Some(arg),
+            _ => return Err(CommandError::new(SEARCH_RESOURCES_USAGE.to_string())),
+        }
+    }
+
+    let Some(pattern) = pattern else {
+        return Err(CommandError::new(SEARCH_RESOURCES_USAGE.to_string()));
+    };
+
+    let matcher = if use_regex {
+        Regex::new(&format!("(?i){}", pattern))
+    } else {
+        glob_to_regex(pattern)
+    }
+    .map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?;
+
+    let results = LAZY_RESOURCE_MAP.lock().unwrap().search(&matcher, file_type.as_ref(), archive.as_deref());
+
+    let mut result_string = String::new();
+    for result in results.iter().take(limit) {
+        result_string.push_str(&format!("{}\n", result));
+    }
+    result_string.push_str(&format!("{} of {} matches shown\n", results.len().min(limit), results.len()));
+    Ok(result_string)
+}
+
+/// Converts a glob into a case insensitive regex matching the whole resource path.
+/// `*` matches within a single directory, `**` matches across directories, `?` matches a single character and `[...]` (or `[!...]`) matches a character class
+pub fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut regex_string = String::from("(?i)^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => {
+                if chars.peek() == Some(&'*') {
+                    chars.next();
+                    regex_string.push_str(".*");
+                } else {
+                    regex_string.push_str("[^/]*");
+                }
+            }
+            '?' => regex_string.push_str("[^/]"),
+            '[' => {
+                regex_string.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex_string.push('^');
+                }
+                for class_char in chars.by_ref() {
+                    if class_char == ']' {
+                        break;
+                    }
+                    if class_char == '\\' || class_char == '[' {
+                        regex_string.push('\\');
+                    }
+                    regex_string.push(class_char);
+                }
+                regex_string.push(']');
+            }
+            _ => regex_string.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex_string.push('$');
+    Regex::new(&regex_string)
+}
+
 fn command_list_openzt_resource_strings(_args: Vec<&str>) -> Result<String, CommandError> {
     let binding = LAZY_RESOURCE_MAP.lock().unwrap();
     let mut result_string = String::new();
@@ -297,7 +682,7 @@ fn ztfile_to_raw_resource(path: &String, file_name: String, ztfile: ZTFile)  ->
         ZTFile::Text(data, _, length) => {
             let ptr = data.into_raw() as u32;
             let resource_ptr = Box::into_raw(Box::new(BFResourcePtr {
-                num_refs: 100, // We set this very high to prevent the game from unloading the resource
+                num_refs: OPENZT_PINNED_REFS,
                 bf_zip_name_ptr,
                 bf_resource_name_ptr,
                 data_ptr: ptr,
@@ -310,7 +695,7 @@ fn ztfile_to_raw_resource(path: &String, file_name: String, ztfile: ZTFile)  ->
             let ptr = data.as_ptr() as u32;
             std::mem::forget(data);
             let resource_ptr = Box::into_raw(Box::new(BFResourcePtr {
-                num_refs: 100, // We set this very high to prevent the game from unloading the resource
+                num_refs: OPENZT_PINNED_REFS,
                 bf_zip_name_ptr,
                 bf_resource_name_ptr,
                 data_ptr: ptr,
@@ -322,65 +707,41 @@ fn ztfile_to_raw_resource(path: &String, file_name: String, ztfile: ZTFile)  ->
     }
 }
 
-fn add_ztfile(path: &Path, file_name: String, ztfile: ZTFile) {
+pub fn add_ztfile(path: &Path, file_name: String, ztfile: ZTFile) {
     let Some(ztd_path) = path.to_str() else {
         error!("Failed to convert path to string: {}", path.display());
         return;
     };
-    let mut ztd_path = ztd_path.to_string();
-    ztd_path = ztd_path.replace("./", "zip::./").replace('\\', "/");
-    let lowercase_filename = file_name.to_lowercase();
-
-    let mut binding = LAZY_RESOURCE_MAP.lock().unwrap();
-
-    let bf_zip_name_ptr = match CString::new(ztd_path.clone()) {
-        Ok(c_string) => c_string.into_raw() as u32,
-        Err(e) => {
-            error!("Error converting zip name to CString: {} -> {}", ztd_path, e);
-            return;
-        }
-    };
-    let bf_resource_name_ptr = match CString::new(lowercase_filename.clone()) {
-        Ok(c_string) => c_string.into_raw() as u32,
-        Err(e) => {
-            error!("Error converting resource name to CString: {} -> {}", lowercase_filename, e);
-            return;
-        }
+    let file_type = match &ztfile {
+        ZTFile::Text(_, file_type, _) | ZTFile::RawBytes(_, file_type, _) => file_type.clone(),
     };
 
-    match ztfile {
-        ZTFile::Text(data, file_type, length) => {
-            let ptr = data.into_raw() as u32;
-            let resource_ptr = Box::into_raw(Box::new(BFResourcePtr {
-                num_refs: 100, // We set this very high to prevent the game from unloading the resource
-                bf_zip_name_ptr,
-                bf_resource_name_ptr,
-                data_ptr: ptr,
-                content_size: length,
-            }));
-
-            binding.insert_custom(file_name.clone(), file_type, resource_ptr as u32);
-        }
-        ZTFile::RawBytes(data, file_type, length) => {
-            let ptr = data.as_ptr() as u32;
-            std::mem::forget(data);
-            let resource_ptr = Box::into_raw(Box::new(BFResourcePtr {
-                num_refs: 100, // We set this very high to prevent the game from unloading the resource
-                bf_zip_name_ptr,
-                bf_resource_name_ptr,
-                data_ptr: ptr,
-                content_size: length,
-            }));
-
-            binding.insert_custom(lowercase_filename.clone(), file_type, resource_ptr as u32);
-        }
+    match ztfile_to_raw_resource(&ztd_path.to_string(), file_name.clone(), ztfile) {
+        Ok(resource_ptr) => LAZY_RESOURCE_MAP.lock().unwrap().insert_custom(file_name.to_lowercase(), file_type, resource_ptr),
+        Err(e) => error!("Error adding file: {} -> {}", file_name, e),
     }
 }
 
+/// Adds a resource at `alias` that points at `target` without copying its data.
+/// If a transform is given it's applied to a copy of the target the first time the alias is loaded
+pub fn add_alias(alias: &str, target: &str, transform: Option<AliasTransform>) -> anyhow::Result<()> {
+    info!("Adding alias {} -> {}", alias, target);
+    LAZY_RESOURCE_MAP.lock().unwrap().insert_alias(alias, target, transform)
+}
+
 pub fn modify_ztfile<F>(file_name: &str, modifier: F) -> Result<(), &'static str>
 where
     F: Fn(&mut BFResourcePtr),
 {
+    // Modifying an alias would modify the resource it points at
+    if let Err(e) = LAZY_RESOURCE_MAP.lock().unwrap().materialise_alias(&file_name.to_lowercase(), 0) {
+        error!("Error copying alias before modifying: {} error: {}", file_name, e);
+        return Err("Error copying alias");
+    }
+    if let Err(e) = LAZY_RESOURCE_MAP.lock().unwrap().copy_if_handed_to_game(&file_name.to_lowercase()) {
+        error!("Error copying resource held by the game before modifying: {} error: {}", file_name, e);
+        return Err("Error copying resource");
+    }
     let Some(bf_resource_ptr_ptr) = get_file_ptr(file_name) else {
         info!("File not found: {}", file_name);
         return Err("File not found");
@@ -391,6 +752,8 @@ where
 
     save_to_memory::<BFResourcePtr>(bf_resource_ptr_ptr, bf_resource_ptr.clone());
 
+    LAZY_RESOURCE_MAP.lock().unwrap().mark_custom(&file_name.to_lowercase());
+
     Ok(())
 }
 
@@ -430,14 +793,23 @@ where
 {
     modify_ztfile(file_name, |file: &mut BFResourcePtr| {
         info!("Modifying animation");
-        let data_vec: Box<[u8]> = unsafe {
+        let data: &[u8] = unsafe { slice::from_raw_parts(file.data_ptr as *const u8, file.content_size as usize) };
+        // Malformed animations are left untouched rather than rewritten from a partial parse
+        let mut animation = match Animation::parse(data) {
+            Ok(animation) => animation,
+            Err(e) => {
+                error!("Error parsing animation {}, leaving it unchanged: {}", file_name, e);
+                return;
+            }
+        };
+        modifier(&mut animation);
+        // Takes ownership of the old data so it's freed once the new data has been written
+        let _old_data: Box<[u8]> = unsafe {
             Box::from_raw(slice::from_raw_parts_mut(
                 file.data_ptr as *mut _,
                 file.content_size as usize,
             ))
         };
-        let mut animation = Animation::parse(&data_vec);
-        modifier(&mut animation);
         let (new_animation_bytes, length) = animation.write();
         let boxed_slice = new_animation_bytes.into_boxed_slice();
         let data_ptr = boxed_slice.as_ptr() as u32;
@@ -633,6 +1005,276 @@ fn command_list_resources(_args: Vec<&str>) -> Result<String, CommandError> {
     Ok(result_string)
 }
 
+const EXPORT_ANIMATION_USAGE: &str = "Usage: export_animation <path> [output_dir] [-s]";
+const EXPORT_DIRECTORY: &str = "openzt_export";
+
+// Exports an animation as one png per frame, or a sprite sheet and json metadata with -s
+fn command_export_animation(args: Vec<&str>) -> Result<String, CommandError> {
+    let mut sheet = false;
+    let mut positional = Vec::new();
+    for arg in args {
+        match arg {
+            "-s" => sheet = true,
+            _ => positional.push(arg),
+        }
+    }
+    let (file_name, directory) = match positional[..] {
+        [file_name] => (file_name.to_lowercase(), get_base_path().join(EXPORT_DIRECTORY)),
+        [file_name, directory] => (file_name.to_lowercase(), PathBuf::from(directory)),
+        _ => return Err(Into::into(EXPORT_ANIMATION_USAGE)),
+    };
+
+    let Some(animation_bytes) = peek_file(&file_name) else {
+        return Err(CommandError::from(format!("Animation not found: {}", file_name)));
+    };
+    let animation = Animation::parse(&animation_bytes).map_err(|e| CommandError::from(format!("Error reading animation {}: {}", file_name, e)))?;
+    let palette_name = animation.palette_filename.to_lowercase();
+    let Some(palette_bytes) = peek_file(&palette_name) else {
+        return Err(CommandError::from(format!("Palette not found: {}", palette_name)));
+    };
+    let palette = Palette::parse(&palette_bytes).map_err(|e| CommandError::from(format!("Error reading palette {}: {}", palette_name, e)))?;
+
+    let name = sprite::export_name(&file_name);
+    let export_result = if sheet {
+        sprite::export_sprite_sheet(&animation, &palette, &directory, &name)
+    } else {
+        sprite::export_frames(&animation, &palette, &directory, &name)
+    };
+    let paths = export_result.map_err(|e| CommandError::from(format!("Error exporting {}: {:#}", file_name, e)))?;
+    Ok(format!("Exported {} files to {}", paths.len(), directory.display()))
+}
+
+const DIFF_ANIMATION_USAGE: &str = "Usage: diff_animation <path> [other_path] [-o output_dir]\n\
+                                    With one path the original version of an overridden animation is compared with the one in use";
+
+fn read_animation_and_palette(file_name: &str, original: bool) -> Result<(Animation, Palette), CommandError> {
+    let peek = |file_name: &str| if original { peek_original_file(file_name) } else { peek_file(file_name) };
+    let Some(animation_bytes) = peek(file_name) else {
+        return Err(CommandError::from(format!("Animation not found: {}", file_name)));
+    };
+    let animation = Animation::parse(&animation_bytes).map_err(|e| CommandError::from(format!("Error reading animation {}: {}", file_name, e)))?;
+    let palette_name = animation.palette_filename.to_lowercase();
+    let Some(palette_bytes) = peek(&palette_name) else {
+        return Err(CommandError::from(format!("Palette not found: {}", palette_name)));
+    };
+    let palette = Palette::parse(&palette_bytes).map_err(|e| CommandError::from(format!("Error reading palette {}: {}", palette_name, e)))?;
+    Ok((animation, palette))
+}
+
+// Prints what changed between two animations and exports a diff image for each changed frame
+fn command_diff_animation(args: Vec<&str>) -> Result<String, CommandError> {
+    let mut directory = get_base_path().join(EXPORT_DIRECTORY);
+    let mut positional = Vec::new();
+    let mut args_iter = args.into_iter();
+    while let Some(arg) = args_iter.next() {
+        match arg {
+            "-o" => directory = PathBuf::from(args_iter.next().ok_or_else(|| CommandError::from(DIFF_ANIMATION_USAGE.to_string()))?),
+            _ => positional.push(arg.to_lowercase()),
+        }
+    }
+    let ((old, old_palette), (new, new_palette), name) = match &positional[..] {
+        [file_name] => {
+            if !LAZY_RESOURCE_MAP.lock().unwrap().overridden.contains_key(file_name) {
+                return Err(CommandError::from(format!("{} isn't overridden, give a second animation to compare with", file_name)));
+            }
+            (read_animation_and_palette(file_name, true)?, read_animation_and_palette(file_name, false)?, sprite::export_name(file_name))
+        }
+        [old_file_name, new_file_name] => (
+            read_animation_and_palette(old_file_name, false)?,
+            read_animation_and_palette(new_file_name, false)?,
+            sprite::export_name(new_file_name),
+        ),
+        _ => return Err(Into::into(DIFF_ANIMATION_USAGE)),
+    };
+
+    let diff = diff_animations(&old, &old_palette, &new, &new_palette).map_err(|e| CommandError::from(format!("Error comparing animations: {:#}", e)))?;
+    if diff.is_unchanged() {
+        return Ok("Animations are identical".to_string());
+    }
+    let paths = export_diff(&diff, &directory, &name).map_err(|e| CommandError::from(format!("Error exporting diff: {:#}", e)))?;
+    Ok(format!("{}Exported {} diff images to {}", diff, paths.len(), directory.display()))
+}
+
+const EXPORT_UI_IMAGE_USAGE: &str = "Usage: export_ui_image <path> [output_dir]";
+
+// Exports a TGA or BMP as a png
+fn command_export_ui_image(args: Vec<&str>) -> Result<String, CommandError> {
+    let (file_name, directory) = match args[..] {
+        [file_name] => (file_name.to_lowercase(), get_base_path().join(EXPORT_DIRECTORY)),
+        [file_name, directory] => (file_name.to_lowercase(), PathBuf::from(directory)),
+        _ => return Err(Into::into(EXPORT_UI_IMAGE_USAGE)),
+    };
+
+    let Some(image_bytes) = peek_file(&file_name) else {
+        return Err(CommandError::from(format!("Image not found: {}", file_name)));
+    };
+    let ui_image = UiImage::parse(&file_name, &image_bytes).map_err(|e| CommandError::from(format!("{:#}", e)))?;
+    let png = ui_image.image.to_png().map_err(|e| CommandError::from(format!("Error converting {} to png: {:#}", file_name, e)))?;
+    fs::create_dir_all(&directory).map_err(|e| CommandError::from(format!("Error creating {}: {}", directory.display(), e)))?;
+    let path = directory.join(format!("{}.png", sprite::export_name(&file_name)));
+    fs::write(&path, png).map_err(|e| CommandError::from(format!("Error writing {}: {}", path.display(), e)))?;
+    Ok(format!(
+        "Exported {}x{} {} to {}",
+        ui_image.image.width,
+        ui_image.image.height,
+        ui_image.format,
+        path.display()
+    ))
+}
+
+const IMPORT_UI_IMAGE_USAGE: &str = "Usage: import_ui_image <path> <png_file> [-b|-r]\n\
+                                     -b and -r add the png below or to the right of the current image instead of replacing it";
+
+// Replaces a TGA or BMP with a png from disk, converted to the format of the image it replaces
+fn command_import_ui_image(args: Vec<&str>) -> Result<String, CommandError> {
+    let mut mode = mods::UiImageMode::Replace;
+    let mut positional = Vec::new();
+    for arg in args {
+        match arg {
+            "-b" => mode = mods::UiImageMode::AppendBelow,
+            "-r" => mode = mods::UiImageMode::AppendRight,
+            _ => positional.push(arg),
+        }
+    }
+    let [file_name, png_path] = positional[..] else {
+        return Err(Into::into(IMPORT_UI_IMAGE_USAGE));
+    };
+    let png = fs::read(png_path).map_err(|e| CommandError::from(format!("Error reading {}: {}", png_path, e)))?;
+    let image = RgbaImage::from_png(&png).map_err(|e| CommandError::from(format!("Error reading {}: {:#}", png_path, e)))?;
+    let file_name = file_name.to_lowercase();
+    let ui_image = patch_ui_image(&file_name, image, mode, "console").map_err(|e| CommandError::from(format!("{:#}", e)))?;
+    Ok(format!(
+        "Replaced {} with {}x{} {}, reload the UI to see the change",
+        file_name, ui_image.image.width, ui_image.image.height, ui_image.format
+    ))
+}
+
+const RESOURCE_MEMORY_USAGE: &str = "Usage: resource_memory [resources|archives|mods] [count]";
+const RESOURCE_MEMORY_DEFAULT_COUNT: usize = 20;
+
+fn command_resource_memory(args: Vec<&str>) -> Result<String, CommandError> {
+    if args.len() > 2 {
+        return Err(Into::into(RESOURCE_MEMORY_USAGE));
+    }
+    let group_by = args.first().copied().unwrap_or("resources");
+    let count = match args.get(1) {
+        Some(count) => count.parse::<usize>()?,
+        None => RESOURCE_MEMORY_DEFAULT_COUNT,
+    };
+
+    let archive_mod_ids = LOADED_ARCHIVES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(archive_name, _, mod_id)| Some((archive_file_name(archive_name), mod_id.clone()?)))
+        .collect::<HashMap<String, String>>();
+    let patched_resources = PATCHED_RESOURCES.lock().unwrap().clone();
+    let binding = LAZY_RESOURCE_MAP.lock().unwrap();
+    let usage = binding.memory_usage();
+    let mut totals: HashMap<String, (usize, usize)> = HashMap::new();
+    for resource in usage.iter() {
+        let key = match group_by {
+            "resources" => resource.file_name.clone(),
+            "archives" => resource.archive_name.clone(),
+            "mods" => resource.owner(&archive_mod_ids, &patched_resources),
+            _ => return Err(Into::into(RESOURCE_MEMORY_USAGE)),
+        };
+        let total = totals.entry(key).or_default();
+        total.0 += resource.bytes;
+        total.1 += 1;
+    }
+    let mut totals = totals.into_iter().collect::<Vec<_>>();
+    totals.sort_by(|a, b| b.1.0.cmp(&a.1.0).then_with(|| a.0.cmp(&b.0)));
+
+    let total_bytes = usage.iter().map(|resource| resource.bytes).sum::<usize>();
+    let mut result = format!(
+        "{} resident in {} loaded resources ({} not loaded, {} retired)\n",
+        format_bytes(total_bytes),
+        binding.loaded_len(),
+        binding.not_loaded_len(),
+        binding.retired.len()
+    );
+    for (name, (bytes, resources)) in totals.iter().take(count) {
+        if group_by == "resources" {
+            result.push_str(&format!("{} {}\n", format_bytes(*bytes), name));
+        } else {
+            result.push_str(&format!("{} {} ({} resources)\n", format_bytes(*bytes), name, resources));
+        }
+    }
+    Ok(result)
+}
+
+fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1048576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1048576.0),
+    }
+}
+
+fn command_resource_refs(args: Vec<&str>) -> Result<String, CommandError> {
+    if args.len() != 1 {
+        return Err(Into::into("Usage: resource_refs <path>"));
+    }
+    let binding = LAZY_RESOURCE_MAP.lock().unwrap();
+    let Some(resource) = binding.map.get(&args[0].to_lowercase()) else {
+        return Err(Into::into("Resource not found"));
+    };
+    match resource.backing {
+        ResourceBacking::LazyZipFile{..} | ResourceBacking::Generated{..} => Ok(format!("{} is not loaded", args[0])),
+        ResourceBacking::LoadedZipFile{data, ..} | ResourceBacking::Custom{data} => {
+            Ok(format!("{} ({}) has {} game references", args[0], resource.backing.state_name(), game_refs(data)))
+        },
+        ResourceBacking::Alias{ref target, ..} => Ok(format!("{} is an alias of {}, references are counted on the target", args[0], target)),
+    }
+}
+
+fn command_resource_providers(args: Vec<&str>) -> Result<String, CommandError> {
+    if args.len() != 1 {
+        return Err(Into::into("Usage: resource_providers <path>"));
+    }
+    let binding = LAZY_RESOURCE_MAP.lock().unwrap();
+    let providers = binding.providers(&args[0].to_lowercase());
+    if providers.is_empty() {
+        return Err(Into::into("Resource not found"));
+    }
+    let mut result = String::new();
+    for (index, (archive_name, layer)) in providers.iter().enumerate() {
+        result.push_str(&format!("{}: {} ({})\n", index, archive_name, layer));
+    }
+    Ok(result)
+}
+
+/// Whether an archive file name is one of the archives shipped with the game or its official updates, ignoring case like Windows does
+pub fn is_official_archive(file_name: &str) -> bool {
+    OFFICIAL_FILESET.iter().any(|official| official.eq_ignore_ascii_case(file_name))
+}
+
+/// Resources shipped by an official archive that a legacy CC archive has replaced, along with every provider in load order
+pub fn get_official_resources_overridden_by_cc() -> Vec<(String, Vec<(String, ResourceLayer)>)> {
+    let binding = LAZY_RESOURCE_MAP.lock().unwrap();
+    let mut overrides = binding
+        .overridden
+        .keys()
+        .map(|key| (key.clone(), binding.providers(key)))
+        .filter(|(_, providers)| {
+            providers.last().is_some_and(|(_, layer)| *layer == ResourceLayer::LegacyCc)
+                && providers.iter().any(|(_, layer)| matches!(layer, ResourceLayer::Vanilla | ResourceLayer::OfficialUpdate))
+        })
+        .collect::<Vec<_>>();
+    overrides.sort_by(|a, b| a.0.cmp(&b.0));
+    overrides
+}
+
+fn command_list_archive_layers(_args: Vec<&str>) -> Result<String, CommandError> {
+    let binding = LOADED_ARCHIVES.lock().unwrap();
+    let mut result = String::new();
+    for (archive_name, layer, _) in binding.iter() {
+        result.push_str(&format!("{} {}\n", layer, archive_name));
+    }
+    Ok(result)
+}
+
 fn command_get_bf_resource_mgr(_args: Vec<&str>) -> Result<String, CommandError> {
     let bf_resource_mgr = read_bf_resource_mgr_from_memory();
     Ok(format!("{}", bf_resource_mgr))
@@ -646,6 +1288,15 @@ pub fn init() {
     };
     add_to_command_register("list_resource_strings".to_string(), command_list_resource_strings);
     add_to_command_register("list_openzt_resource_strings".to_string(), command_list_openzt_resource_strings);
+    add_to_command_register("search_resources".to_string(), command_search_resources);
+    add_to_command_register("resource_refs".to_string(), command_resource_refs);
+    add_to_command_register("resource_memory".to_string(), command_resource_memory);
+    add_to_command_register("export_animation".to_string(), command_export_animation);
+    add_to_command_register("diff_animation".to_string(), command_diff_animation);
+    add_to_command_register("export_ui_image".to_string(), command_export_ui_image);
+    add_to_command_register("import_ui_image".to_string(), command_import_ui_image);
+    add_to_command_register("resource_providers".to_string(), command_resource_providers);
+    add_to_command_register("list_archive_layers".to_string(), command_list_archive_layers);
     add_to_command_register("list_openzt_mods".to_string(), command_list_openzt_mod_ids);
     add_to_command_register("list_openzt_locations_habitats".to_string(), command_list_openzt_locations_habitats);
 }
@@ -657,15 +1308,19 @@ pub mod zoo_resource_mgr {
     use bf_configparser::ini::Ini;
     use tracing::info;
 
-    use super::{check_file, get_file_ptr, get_location_or_habitat_by_id, load_resources, BFResourcePtr};
+    use super::{
+        acquire_file_ptr, apply_on_demand_handlers, check_file, get_location_or_habitat_by_id, load_cfg_merge_modes, load_resource_layer_overrides,
+        load_resources_in_background, resource_ready, set_load_state, wait_for_load_state, wait_for_resources, LoadState,
+    };
     use crate::debug_dll::{get_ini_path, get_string_from_memory, save_to_memory};
-    use crate::resource_manager::OPENZT_DIR0;
+    use crate::{integrity::load_integrity_settings, missing_resources::record_missing_resource, resource_manager::OPENZT_DIR0};
 
     #[hook(unsafe extern "thiscall" BFResource_attempt, offset = 0x00003891)]
     fn zoo_bf_resource_attempt(this_ptr: u32, file_name: u32) -> u8 {
         if bf_resource_inner(this_ptr, file_name) {
             return 1;
         }
+        // Misses aren't recorded here, the game probes for files it can do without through attempt
         unsafe { BFResource_attempt.call(this_ptr, file_name) }
     }
 
@@ -676,6 +1331,9 @@ pub mod zoo_resource_mgr {
         }
 
         let return_value = unsafe { BFResource_prepare.call(this_ptr, file_name) };
+        if return_value == 0 {
+            record_missing_resource(&get_string_from_memory(file_name));
+        }
         return_value
     }
 
@@ -692,23 +1350,18 @@ pub mod zoo_resource_mgr {
                 }
             }
         }
+        if !resource_ready(&file_name_string) {
+            info!("Waiting for OpenZT resources to load before providing: {}", file_name_string);
+            wait_for_resources();
+        }
+        apply_on_demand_handlers(&file_name_string);
         if check_file(&file_name_string)
-            && let Some(ptr) = get_file_ptr(&file_name_string)
+            && let Some(ptr) = acquire_file_ptr(&file_name_string)
         {
-            let mut bfrp = unsafe { Box::from_raw(ptr as *mut BFResourcePtr) };
-
-            if bfrp.num_refs < 100 {
-                bfrp.num_refs = 100;
-            }
-
-            let ptr = Box::into_raw(bfrp) as u32;
-
             save_to_memory(this_ptr, ptr);
             true
         } else {
-            if !file_name_string.starts_with("ztat") {
-                info!("Missing file: {}", file_name_string);
-            }
+            // Files we don't have fall through to the vanilla resource manager, BFResource_prepare misses there are tracked by missing_resources
             false
         }
     }
@@ -740,18 +1393,26 @@ pub mod zoo_resource_mgr {
         zoo_ini.set_comment_symbols(&['#']);
         if let Err(e) = zoo_ini.load(ini_path) {
             info!("Failed to load zoo.ini: {}", e);
+            set_load_state(LoadState::Ready);
             return return_value;
         };
-        if let Some(paths) = zoo_ini.get("resource", "path") {
-            info!("Loading resources from: {}", paths);
-            load_resources(paths.split(';').map(|s| s.to_owned()).collect());
-            info!("Resources loaded");
+        load_cfg_merge_modes(&zoo_ini);
+        load_resource_layer_overrides(&zoo_ini);
+        load_integrity_settings(&zoo_ini);
+        match zoo_ini.get("resource", "path") {
+            Some(paths) => {
+                info!("Loading resources from: {}", paths);
+                load_resources_in_background(paths.split(';').map(|s| s.to_owned()).collect());
+            }
+            None => set_load_state(LoadState::Ready),
         }
         return_value
     }
 
     #[hook(unsafe extern "cdecl" ZTUI_general_getInfoImageName, offset = 0x000f85d2)]
     fn zoo_ui_general_get_info_image_name(id: u32) -> u32 {
+        // Locations and habitats come from OpenZT mod definitions which are loaded while archives are indexed
+        wait_for_load_state(LoadState::Handling);
         let return_value = match get_location_or_habitat_by_id(id) {
             Some(resource_ptr) => resource_ptr,
             None => unsafe { ZTUI_general_getInfoImageName.call(id) },
@@ -764,77 +1425,303 @@ pub mod zoo_resource_mgr {
 /// BeforeOpenZTMods means they are run on any files in but before any OpenZT mods are loaded
 /// AfterOpenZTMods is the same as above but after OpenZT mods are loaded
 /// AfterFiltering is run on files that are referenced in *.cfg files only
+/// OnDemand is run the first time the game requests a file via BFResource_attempt/BFResource_prepare, files that are never requested stay lazy
 #[derive(Clone, PartialEq)]
 pub enum RunStage {
     BeforeOpenZTMods,
     AfterOpenZTMods,
-    AfterFiltering
+    AfterFiltering,
+    OnDemand
+}
+
+/// Handler functions are given the archive the file came from, the path of the file and the parsed file.
+/// Closures can be used to capture configuration or state, they are shared between threads so any mutable state needs to be behind a Mutex or atomic
+pub type IniHandlerFunction = Arc<dyn Fn(&String, &String, Ini) -> HandlerOutcome<Ini> + Send + Sync>;
+pub type AnimationHandlerFunction = Arc<dyn Fn(&String, &String, Animation) -> HandlerOutcome<Animation> + Send + Sync>;
+pub type RawBytesHandlerFunction = Arc<dyn Fn(&String, &String, Box<[u8]>) -> HandlerOutcome<Box<[u8]>> + Send + Sync>;
+pub type TextHandlerFunction = Arc<dyn Fn(&String, &String, String) -> HandlerOutcome<String> + Send + Sync>;
+pub type TomlHandlerFunction = Arc<dyn Fn(&String, &String, toml::Table) -> HandlerOutcome<toml::Table> + Send + Sync>;
+pub type PaletteHandlerFunction = Arc<dyn Fn(&String, &String, Palette) -> HandlerOutcome<Palette> + Send + Sync>;
+pub type ImageHandlerFunction = Arc<dyn Fn(&String, &String, UiImage) -> HandlerOutcome<UiImage> + Send + Sync>;
+/// Path handlers are only given the archive and path, the file isn't loaded so they are suited to things like adding aliases
+pub type PathHandlerFunction = Arc<dyn Fn(&String, &String) + Send + Sync>;
+
+/// Handlers run in ascending priority order, handlers with the same priority run in the order they were added
+pub const DEFAULT_HANDLER_PRIORITY: i32 = 0;
+
+/// A condition a file must meet for a handler to run, a handler only runs if all of its matchers match
+#[derive(Clone)]
+pub enum HandlerMatcher {
+    Prefix(String),
+    Suffix(String),
+    // Original glob is kept for logging
+    Glob(String, Regex),
+    Regex(Regex),
+    FileType(ZTFileType),
+    // Matched against the file name of the archive the resource was loaded from (or "openzt.ztd" for OpenZT generated resources)
+    Archive(String, Regex),
+}
+
+impl HandlerMatcher {
+    // Only checks matchers that depend on the path, FileType and Archive matchers are checked by matches_resource
+    fn matches_path(&self, file_name: &str) -> bool {
+        match self {
+            HandlerMatcher::Prefix(prefix) => file_name.starts_with(prefix),
+            HandlerMatcher::Suffix(suffix) => file_name.ends_with(suffix),
+            HandlerMatcher::Glob(_, regex) | HandlerMatcher::Regex(regex) => regex.is_match(file_name),
+            HandlerMatcher::FileType(_) | HandlerMatcher::Archive(_, _) => true,
+        }
+    }
+
+    fn is_path_matcher(&self) -> bool {
+        !matches!(self, HandlerMatcher::FileType(_) | HandlerMatcher::Archive(_, _))
+    }
+
+    fn matches_resource(&self, file_name: &str, file_type: &ZTFileType) -> bool {
+        match self {
+            HandlerMatcher::FileType(matcher_file_type) => matcher_file_type == file_type,
+            HandlerMatcher::Archive(_, regex) => get_archive_name(file_name).is_some_and(|archive_name| {
+                let archive_file_name = Path::new(&archive_name).file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+                regex.is_match(&archive_file_name)
+            }),
+            _ => true,
+        }
+    }
 }
 
-pub type IniHandlerFunction = fn(&String, &String, Ini) -> Option<(String, String, Ini)>;
-pub type AnimationHandlerFunction = fn(&String, &String, Animation) -> Option<(String, String, Animation)>;
-pub type RawBytesHandlerFunction = fn(&String, &String, Box<[u8]>) -> Option<(String, String, Box<[u8]>)>;
+impl fmt::Display for HandlerMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandlerMatcher::Prefix(prefix) => write!(f, "prefix:{}", prefix),
+            HandlerMatcher::Suffix(suffix) => write!(f, "suffix:{}", suffix),
+            HandlerMatcher::Glob(glob, _) => write!(f, "glob:{}", glob),
+            HandlerMatcher::Regex(regex) => write!(f, "regex:{}", regex),
+            HandlerMatcher::FileType(file_type) => write!(f, "type:{}", file_type),
+            HandlerMatcher::Archive(archive_glob, _) => write!(f, "archive:{}", archive_glob),
+        }
+    }
+}
 
 pub struct HandlerBuilder<const HAS_STAGE: bool, const HAS_HANDLER: bool> {
-    matcher_prefix: Option<String>,
-    matcher_suffix: Option<String>,
+    matchers: Vec<HandlerMatcher>,
+    priority: i32,
     ini_handler: Option<IniHandlerFunction>,
     animation_handler: Option<AnimationHandlerFunction>,
     raw_bytes_handler: Option<RawBytesHandlerFunction>,
+    text_handler: Option<TextHandlerFunction>,
+    toml_handler: Option<TomlHandlerFunction>,
+    palette_handler: Option<PaletteHandlerFunction>,
+    image_handler: Option<ImageHandlerFunction>,
+    path_handler: Option<PathHandlerFunction>,
     stage: Option<RunStage>,
 }
 
 impl<const HAS_STAGE: bool, const HAS_HANDLER: bool> HandlerBuilder<HAS_STAGE, HAS_HANDLER> {
     pub fn prefix(mut self, prefix: &str) -> HandlerBuilder<HAS_STAGE, HAS_HANDLER> {
-        self.matcher_prefix = Some(prefix.to_owned());
+        self.matchers.push(HandlerMatcher::Prefix(prefix.to_owned()));
         self
     }
 
     pub fn suffix(mut self, suffix: &str) -> HandlerBuilder<HAS_STAGE, HAS_HANDLER> {
-        self.matcher_suffix = Some(suffix.to_owned());
+        self.matchers.push(HandlerMatcher::Suffix(suffix.to_owned()));
         self
     }
 
-    pub fn ini_handler(self, handler: IniHandlerFunction) -> HandlerBuilder<HAS_STAGE, true> {
-        HandlerBuilder {
-            matcher_prefix: self.matcher_prefix,
-            matcher_suffix: self.matcher_suffix,
-            ini_handler: Some(handler),
-            animation_handler: self.animation_handler,
-            raw_bytes_handler: self.raw_bytes_handler,
-            stage: self.stage
+    /// Matches the full (lowercase) resource path against a glob, see `glob_to_regex` for supported syntax
+    pub fn glob(mut self, glob: &str) -> Result<HandlerBuilder<HAS_STAGE, HAS_HANDLER>, regex::Error> {
+        self.matchers.push(HandlerMatcher::Glob(glob.to_owned(), glob_to_regex(glob)?));
+        Ok(self)
+    }
+
+    pub fn regex(mut self, regex: Regex) -> HandlerBuilder<HAS_STAGE, HAS_HANDLER> {
+        self.matchers.push(HandlerMatcher::Regex(regex));
+        self
+    }
+
+    pub fn file_type(mut self, file_type: ZTFileType) -> HandlerBuilder<HAS_STAGE, HAS_HANDLER> {
+        self.matchers.push(HandlerMatcher::FileType(file_type));
+        self
+    }
+
+    /// Matches the file name of the source archive against a glob, e.g. "animals*.ztd"
+    pub fn archive(mut self, archive_glob: &str) -> Result<HandlerBuilder<HAS_STAGE, HAS_HANDLER>, regex::Error> {
+        self.matchers.push(HandlerMatcher::Archive(archive_glob.to_owned(), glob_to_regex(archive_glob)?));
+        Ok(self)
+    }
+
+    pub fn priority(mut self, priority: i32) -> HandlerBuilder<HAS_STAGE, HAS_HANDLER> {
+        self.priority = priority;
+        self
+    }
+
+    pub fn ini_handler<F>(self, handler: F) -> HandlerBuilder<HAS_STAGE, true>
+    where
+        F: Fn(&String, &String, Ini) -> HandlerOutcome<Ini> + Send + Sync + 'static,
+    {
+        HandlerBuilder {
+            matchers: self.matchers,
+            priority: self.priority,
+            ini_handler: Some(Arc::new(handler)),
+            animation_handler: self.animation_handler,
+            raw_bytes_handler: self.raw_bytes_handler,
+            text_handler: self.text_handler,
+            toml_handler: self.toml_handler,
+            palette_handler: self.palette_handler,
+            image_handler: self.image_handler,
+            path_handler: self.path_handler,
+            stage: self.stage
+        }
+    }
+
+    pub fn animation_handler<F>(self, handler: F) -> HandlerBuilder<HAS_STAGE, true>
+    where
+        F: Fn(&String, &String, Animation) -> HandlerOutcome<Animation> + Send + Sync + 'static,
+    {
+        HandlerBuilder {
+            matchers: self.matchers,
+            priority: self.priority,
+            ini_handler: self.ini_handler,
+            animation_handler: Some(Arc::new(handler)),
+            raw_bytes_handler: self.raw_bytes_handler,
+            text_handler: self.text_handler,
+            toml_handler: self.toml_handler,
+            palette_handler: self.palette_handler,
+            image_handler: self.image_handler,
+            path_handler: self.path_handler,
+            stage: self.stage
+        }
+    }
+
+    pub fn raw_bytes_handler<F>(self, handler: F) -> HandlerBuilder<HAS_STAGE, true>
+    where
+        F: Fn(&String, &String, Box<[u8]>) -> HandlerOutcome<Box<[u8]>> + Send + Sync + 'static,
+    {
+        HandlerBuilder {
+            matchers: self.matchers,
+            priority: self.priority,
+            ini_handler: self.ini_handler,
+            animation_handler: self.animation_handler,
+            raw_bytes_handler: Some(Arc::new(handler)),
+            text_handler: self.text_handler,
+            toml_handler: self.toml_handler,
+            palette_handler: self.palette_handler,
+            image_handler: self.image_handler,
+            path_handler: self.path_handler,
+            stage: self.stage
+        }
+    }
+
+    pub fn text_handler<F>(self, handler: F) -> HandlerBuilder<HAS_STAGE, true>
+    where
+        F: Fn(&String, &String, String) -> HandlerOutcome<String> + Send + Sync + 'static,
+    {
+        HandlerBuilder {
+            matchers: self.matchers,
+            priority: self.priority,
+            ini_handler: self.ini_handler,
+            animation_handler: self.animation_handler,
+            raw_bytes_handler: self.raw_bytes_handler,
+            text_handler: Some(Arc::new(handler)),
+            toml_handler: self.toml_handler,
+            palette_handler: self.palette_handler,
+            image_handler: self.image_handler,
+            path_handler: self.path_handler,
+            stage: self.stage
+        }
+    }
+
+    pub fn toml_handler<F>(self, handler: F) -> HandlerBuilder<HAS_STAGE, true>
+    where
+        F: Fn(&String, &String, toml::Table) -> HandlerOutcome<toml::Table> + Send + Sync + 'static,
+    {
+        HandlerBuilder {
+            matchers: self.matchers,
+            priority: self.priority,
+            ini_handler: self.ini_handler,
+            animation_handler: self.animation_handler,
+            raw_bytes_handler: self.raw_bytes_handler,
+            text_handler: self.text_handler,
+            toml_handler: Some(Arc::new(handler)),
+            palette_handler: self.palette_handler,
+            image_handler: self.image_handler,
+            path_handler: self.path_handler,
+            stage: self.stage
+        }
+    }
+
+    /// Palettes are passed to the palette handler if one is set, otherwise they are passed to the raw bytes handler
+    pub fn palette_handler<F>(self, handler: F) -> HandlerBuilder<HAS_STAGE, true>
+    where
+        F: Fn(&String, &String, Palette) -> HandlerOutcome<Palette> + Send + Sync + 'static,
+    {
+        HandlerBuilder {
+            matchers: self.matchers,
+            priority: self.priority,
+            ini_handler: self.ini_handler,
+            animation_handler: self.animation_handler,
+            raw_bytes_handler: self.raw_bytes_handler,
+            text_handler: self.text_handler,
+            toml_handler: self.toml_handler,
+            palette_handler: Some(Arc::new(handler)),
+            image_handler: self.image_handler,
+            path_handler: self.path_handler,
+            stage: self.stage
         }
     }
 
-    pub fn animation_handler(self, handler: AnimationHandlerFunction) -> HandlerBuilder<HAS_STAGE, true> {
+    /// TGAs and BMPs are passed to the image handler if one is set, otherwise they are passed to the raw bytes handler.
+    /// Modified images are written back in the format they were read in unless the handler changes it
+    pub fn image_handler<F>(self, handler: F) -> HandlerBuilder<HAS_STAGE, true>
+    where
+        F: Fn(&String, &String, UiImage) -> HandlerOutcome<UiImage> + Send + Sync + 'static,
+    {
         HandlerBuilder {
-            matcher_prefix: self.matcher_prefix,
-            matcher_suffix: self.matcher_suffix,
+            matchers: self.matchers,
+            priority: self.priority,
             ini_handler: self.ini_handler,
-            animation_handler: Some(handler),
+            animation_handler: self.animation_handler,
             raw_bytes_handler: self.raw_bytes_handler,
+            text_handler: self.text_handler,
+            toml_handler: self.toml_handler,
+            palette_handler: self.palette_handler,
+            image_handler: Some(Arc::new(handler)),
+            path_handler: self.path_handler,
             stage: self.stage
         }
     }
 
-    pub fn raw_bytes_handler(self, handler: RawBytesHandlerFunction) -> HandlerBuilder<HAS_STAGE, true> {
+    /// Path handlers run before any typed handler on the same Handler
+    pub fn path_handler<F>(self, handler: F) -> HandlerBuilder<HAS_STAGE, true>
+    where
+        F: Fn(&String, &String) + Send + Sync + 'static,
+    {
         HandlerBuilder {
-            matcher_prefix: self.matcher_prefix,
-            matcher_suffix: self.matcher_suffix,
+            matchers: self.matchers,
+            priority: self.priority,
             ini_handler: self.ini_handler,
             animation_handler: self.animation_handler,
-            raw_bytes_handler: Some(handler),
+            raw_bytes_handler: self.raw_bytes_handler,
+            text_handler: self.text_handler,
+            toml_handler: self.toml_handler,
+            palette_handler: self.palette_handler,
+            image_handler: self.image_handler,
+            path_handler: Some(Arc::new(handler)),
             stage: self.stage
         }
     }
 
     pub fn run_stage(self, stage: RunStage) -> HandlerBuilder<true, HAS_HANDLER> {
         HandlerBuilder {
-            matcher_prefix: self.matcher_prefix,
-            matcher_suffix: self.matcher_suffix,
+            matchers: self.matchers,
+            priority: self.priority,
             ini_handler: self.ini_handler,
             animation_handler: self.animation_handler,
             raw_bytes_handler: self.raw_bytes_handler,
+            text_handler: self.text_handler,
+            toml_handler: self.toml_handler,
+            palette_handler: self.palette_handler,
+            image_handler: self.image_handler,
+            path_handler: self.path_handler,
             stage: Some(stage)
         }
     }
@@ -844,11 +1731,16 @@ impl HandlerBuilder<true, true> {
     pub fn build(self) -> Handler {
         unsafe {
             Handler {
-                matcher_prefix: self.matcher_prefix,
-                matcher_suffix: self.matcher_suffix,
+                matchers: self.matchers,
+                priority: self.priority,
                 ini_handler: self.ini_handler,
                 animation_handler: self.animation_handler,
                 raw_bytes_handler: self.raw_bytes_handler,
+                text_handler: self.text_handler,
+                toml_handler: self.toml_handler,
+                palette_handler: self.palette_handler,
+                image_handler: self.image_handler,
+                path_handler: self.path_handler,
                 stage: self.stage.unwrap_unchecked()
             }
         }
@@ -857,36 +1749,56 @@ impl HandlerBuilder<true, true> {
 
 #[derive(Clone)]
 pub struct Handler {
-    matcher_prefix: Option<String>,
-    matcher_suffix: Option<String>,
+    matchers: Vec<HandlerMatcher>,
+    priority: i32,
     ini_handler: Option<IniHandlerFunction>,
     animation_handler: Option<AnimationHandlerFunction>,
     raw_bytes_handler: Option<RawBytesHandlerFunction>,
+    text_handler: Option<TextHandlerFunction>,
+    toml_handler: Option<TomlHandlerFunction>,
+    palette_handler: Option<PaletteHandlerFunction>,
+    image_handler: Option<ImageHandlerFunction>,
+    path_handler: Option<PathHandlerFunction>,
     stage: RunStage
 }
 
+impl fmt::Display for Handler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let matchers = self.matchers.iter().map(|matcher| matcher.to_string()).collect::<Vec<String>>();
+        write!(f, "[{}] (priority {})", matchers.join(" "), self.priority)
+    }
+}
+
 impl Handler {
     pub fn builder() -> HandlerBuilder<false, false> {
         HandlerBuilder::<false, false> {
-            matcher_prefix: None,
-            matcher_suffix: None,
+            matchers: Vec::new(),
+            priority: DEFAULT_HANDLER_PRIORITY,
             ini_handler: None,
             animation_handler: None,
             raw_bytes_handler: None,
+            text_handler: None,
+            toml_handler: None,
+            palette_handler: None,
+            image_handler: None,
+            path_handler: None,
             stage: None
         }
     }
 
+    // Used to decide if the handler could still change a file before it has run, handlers without path matchers
+    // (only FileType or Archive) can't be narrowed down from the path so they never hold a file back
+    fn matches_path(&self, file_name: &str) -> bool {
+        self.matchers.iter().any(HandlerMatcher::is_path_matcher) && self.path_matchers_match(file_name)
+    }
+
+    fn path_matchers_match(&self, file_name: &str) -> bool {
+        self.matchers.iter().all(|matcher| matcher.matches_path(file_name))
+    }
+
     fn handle(&self, file_name: &String) {
-        if let Some(prefix) = &self.matcher_prefix {
-            if !file_name.starts_with(prefix) {
-                return;
-            }
-        }
-        if let Some(suffix) = &self.matcher_suffix {
-            if !file_name.ends_with(suffix) {
-                return;
-            }
+        if !self.path_matchers_match(file_name) {
+            return;
         }
 
         let file_type = match ZTFileType::try_from(Path::new(&file_name)) {
@@ -897,94 +1809,300 @@ impl Handler {
             }
         };
 
-        let new_file = match file_type {
+        if !self.matchers.iter().all(|matcher| matcher.matches_resource(file_name, &file_type)) {
+            return;
+        }
+
+        if let Some(handler) = &self.path_handler {
+            info!("Path Handler {} is handling file: {}", self, file_name);
+            let archive_name = get_archive_name(file_name).unwrap_or_default();
+            handler(&archive_name, file_name);
+        }
+
+        let (archive_name, outcome) = match file_type {
             ZTFileType::Ini | ZTFileType::Ai | ZTFileType::Ani | ZTFileType::Cfg | ZTFileType::Lyt | ZTFileType::Scn | ZTFileType::Uca | ZTFileType::Ucs | ZTFileType::Ucb => {
-                if let Some(handler) = self.ini_handler {
-                    info!("Ini Handler {} {} is handling file: {}", self.matcher_prefix.as_deref().unwrap_or_default(), self.matcher_suffix.as_deref().unwrap_or_default(), file_name);
-                    let Some((archive_name, file)) = get_file(file_name) else {
-                        error!("Error getting file: {}", file_name);
-                        return;
-                    };
-                    let mut ini = Ini::new_cs();
-                    ini.set_comment_symbols(&[';', '#', ':']);
+                let Some(handler) = &self.ini_handler else {
+                    return;
+                };
+                info!("Ini Handler {} is handling file: {}", self, file_name);
+                let Some((archive_name, file)) = get_file(file_name) else {
+                    error!("Error getting file: {}", file_name);
+                    return;
+                };
+                let mut ini = Ini::new_cs();
+                ini.set_comment_symbols(&[';', '#', ':']);
 
-                    let Ok(input_string) = str::from_utf8(&file) else {
-                        error!("Error converting file to string: {}", file_name);
-                        return;
-                    };
-                    if let Err(e) = ini.read(input_string.to_string()) {
-                        error!("Error reading ini {}: {}", file_name, e);
+                let Ok(input_string) = str::from_utf8(&file) else {
+                    error!("Error converting file to string: {}", file_name);
+                    return;
+                };
+                if let Err(e) = ini.read(input_string.to_string()) {
+                    error!("Error reading ini {}: {}", file_name, e);
+                    return;
+                }
+                let outcome = handler(&archive_name, file_name, ini).filter_map(file_name, |new_file_name, new_ini| {
+                    string_to_ztfile(new_file_name, write_ini_to_string(&new_ini), &file_type)
+                });
+                (archive_name, outcome)
+            }
+            ZTFileType::Txt => {
+                let Some(handler) = &self.text_handler else {
+                    return;
+                };
+                info!("Text Handler {} is handling file: {}", self, file_name);
+                let Some((archive_name, input_string)) = get_file_as_string(file_name) else {
+                    return;
+                };
+                let outcome = handler(&archive_name, file_name, input_string)
+                    .filter_map(file_name, |new_file_name, new_string| string_to_ztfile(new_file_name, new_string, &file_type));
+                (archive_name, outcome)
+            }
+            ZTFileType::Toml => {
+                let Some(handler) = &self.toml_handler else {
+                    return;
+                };
+                info!("Toml Handler {} is handling file: {}", self, file_name);
+                let Some((archive_name, input_string)) = get_file_as_string(file_name) else {
+                    return;
+                };
+                let table = match input_string.parse::<toml::Table>() {
+                    Ok(table) => table,
+                    Err(e) => {
+                        error!("Error reading toml {}: {}", file_name, e);
                         return;
                     }
-                    if let Some((new_archive_name, new_file_path, new_ini)) = handler(&archive_name, file_name, ini) {
-                        let mut write_options = WriteOptions::default();
-                        write_options.space_around_delimiters = true;
-                        write_options.blank_lines_between_sections = 1;
-                        let new_string = new_ini.pretty_writes(&write_options);
-                        let new_string_length = new_string.len() as u32;
-
-                        match CString::new(new_string) {
-                            Ok(new_c_string) => {
-                                Some((new_archive_name, new_file_path, ZTFile::Text(new_c_string, file_type, new_string_length)))
-                            },
-                            Err(_) => {
-                                error!("Error converting ini to CString after modifying {}", file_name);
-                                None
-                            }
+                };
+                let outcome = handler(&archive_name, file_name, table).filter_map(file_name, |new_file_name, new_table| {
+                    match toml::to_string(&new_table) {
+                        Ok(new_string) => string_to_ztfile(new_file_name, new_string, &file_type),
+                        Err(e) => {
+                            error!("Error writing toml after modifying {}: {}", new_file_name, e);
+                            None
                         }
-                    } else {
-                        None
                     }
-                } else {
-                    None
-                }
+                });
+                (archive_name, outcome)
             }
-            ZTFileType::Animation => {
-                if let Some(handler) = self.animation_handler {
-                    info!("Animation Handler {} {} is handling file: {}", self.matcher_prefix.as_deref().unwrap_or_default(), self.matcher_suffix.as_deref().unwrap_or_default(), file_name);
-                    let Some((archive_name, file)) = get_file(file_name) else {
-                        error!("Error getting file: {}", file_name);
+            ZTFileType::Palette if self.palette_handler.is_some() => {
+                let Some(handler) = &self.palette_handler else {
+                    return;
+                };
+                info!("Palette Handler {} is handling file: {}", self, file_name);
+                let Some((archive_name, file)) = get_file(file_name) else {
+                    error!("Error getting file: {}", file_name);
+                    return;
+                };
+                let palette = match Palette::parse(&file) {
+                    Ok(palette) => palette,
+                    Err(e) => {
+                        error!("Error reading palette {}: {}", file_name, e);
                         return;
-                    };
-                    let animation = Animation::parse(&file);
-                    if let Some((new_archive_name, new_file_path, new_animation)) = handler(&archive_name, file_name, animation) {
-                        let (new_animation_bytes, animation_size) = new_animation.write();
-                        Some((new_archive_name, new_file_path, ZTFile::RawBytes(new_animation_bytes.into_boxed_slice(), ZTFileType::Animation, animation_size as u32)))
-                    } else {
-                        None
                     }
-                } else {
-                    None
-                }
+                };
+                let outcome = handler(&archive_name, file_name, palette).filter_map(file_name, |_, new_palette| {
+                    let (new_palette_bytes, palette_size) = new_palette.write();
+                    Some(ZTFile::RawBytes(new_palette_bytes.into_boxed_slice(), ZTFileType::Palette, palette_size as u32))
+                });
+                (archive_name, outcome)
             }
-            ZTFileType::Bmp | ZTFileType::Lle | ZTFileType::TGA | ZTFileType::Wav | ZTFileType::Palette => {
-                if let Some(handler) = self.raw_bytes_handler {
-                    info!("Raw Bytes Handler {} {} is handling file: {}", self.matcher_prefix.as_deref().unwrap_or_default(), self.matcher_suffix.as_deref().unwrap_or_default(), file_name);
-                    let Some((archive_name, file)) = get_file(file_name) else {
-                        error!("Error getting file: {}", file_name);
+            ZTFileType::Bmp | ZTFileType::TGA if self.image_handler.is_some() => {
+                let Some(handler) = &self.image_handler else {
+                    return;
+                };
+                info!("Image Handler {} is handling file: {}", self, file_name);
+                let Some((archive_name, file)) = get_file(file_name) else {
+                    error!("Error getting file: {}", file_name);
+                    return;
+                };
+                let image = match UiImage::parse(file_name, &file) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        error!("Error reading image {}: {:#}", file_name, e);
                         return;
-                    };
-                    if let Some((new_archive_name, new_file_path, new_data)) = handler(&archive_name, file_name, file) {
-                        let new_data_len = new_data.len() as u32;
-                        Some((new_archive_name, new_file_path, ZTFile::RawBytes(new_data, file_type, new_data_len)))
-                    } else {
+                    }
+                };
+                let outcome = handler(&archive_name, file_name, image).filter_map(file_name, |new_file_name, new_image| match new_image.write() {
+                    Ok(new_image_bytes) => {
+                        let image_size = new_image_bytes.len() as u32;
+                        let image_file_type = match new_image.format {
+                            UiImageFormat::Tga { .. } => ZTFileType::TGA,
+                            UiImageFormat::Bmp { .. } => ZTFileType::Bmp,
+                        };
+                        Some(ZTFile::RawBytes(new_image_bytes.into_boxed_slice(), image_file_type, image_size))
+                    }
+                    Err(e) => {
+                        error!("Error writing image after modifying {}: {:#}", new_file_name, e);
                         None
                     }
-                } else {
-                    None
-                }
+                });
+                (archive_name, outcome)
+            }
+            ZTFileType::Animation => {
+                let Some(handler) = &self.animation_handler else {
+                    return;
+                };
+                info!("Animation Handler {} is handling file: {}", self, file_name);
+                let Some((archive_name, file)) = get_file(file_name) else {
+                    error!("Error getting file: {}", file_name);
+                    return;
+                };
+                let animation = match Animation::parse(&file) {
+                    Ok(animation) => animation,
+                    Err(e) => {
+                        error!("Error reading animation {}: {}", file_name, e);
+                        return;
+                    }
+                };
+                let outcome = handler(&archive_name, file_name, animation).filter_map(file_name, |_, new_animation| {
+                    let (new_animation_bytes, animation_size) = new_animation.write();
+                    Some(ZTFile::RawBytes(new_animation_bytes.into_boxed_slice(), ZTFileType::Animation, animation_size as u32))
+                });
+                (archive_name, outcome)
+            }
+            ZTFileType::Bmp | ZTFileType::Lle | ZTFileType::TGA | ZTFileType::Wav | ZTFileType::Palette | ZTFileType::Zoo => {
+                let Some(handler) = &self.raw_bytes_handler else {
+                    return;
+                };
+                info!("Raw Bytes Handler {} is handling file: {}", self, file_name);
+                let Some((archive_name, file)) = get_file(file_name) else {
+                    error!("Error getting file: {}", file_name);
+                    return;
+                };
+                let outcome = handler(&archive_name, file_name, file).filter_map(file_name, |_, new_data| {
+                    let new_data_len = new_data.len() as u32;
+                    Some(ZTFile::RawBytes(new_data, file_type.clone(), new_data_len))
+                });
+                (archive_name, outcome)
             }
-
-            _ => return
         };
 
-        if let Some((new_archive_name, new_file_name, ztfile)) = new_file {
-            add_ztfile(Path::new(&new_archive_name), new_file_name, ztfile)
+        apply_handler_outcome(&archive_name, file_name, outcome);
+    }
+}
+
+/// What should happen to a file after a handler has run on it
+pub enum HandlerAction<T> {
+    /// Leave the file as it is
+    Keep,
+    /// Replace the file with a new version under the same path
+    Modify(T),
+    /// Remove the file from the resource map so the game falls back to its own copy (if any)
+    Delete,
+}
+
+/// Additional file created by a handler, file_name is the resource path the file is added under
+pub struct EmittedFile<T> {
+    pub archive_name: String,
+    pub file_name: String,
+    pub file: T,
+}
+
+/// Result returned by handler functions, built via `HandlerOutcome::unchanged()`, `modify()`, `delete()` and `emit()`
+pub struct HandlerOutcome<T> {
+    pub action: HandlerAction<T>,
+    pub emitted: Vec<EmittedFile<T>>,
+}
+
+impl<T> HandlerOutcome<T> {
+    pub fn unchanged() -> Self {
+        HandlerOutcome {
+            action: HandlerAction::Keep,
+            emitted: Vec::new(),
+        }
+    }
+
+    pub fn modify(file: T) -> Self {
+        HandlerOutcome {
+            action: HandlerAction::Modify(file),
+            emitted: Vec::new(),
+        }
+    }
+
+    pub fn delete() -> Self {
+        HandlerOutcome {
+            action: HandlerAction::Delete,
+            emitted: Vec::new(),
+        }
+    }
+
+    /// Leaves the handled file as is and adds a new file
+    pub fn emit(archive_name: String, file_name: String, file: T) -> Self {
+        HandlerOutcome::unchanged().and_emit(archive_name, file_name, file)
+    }
+
+    pub fn and_emit(mut self, archive_name: String, file_name: String, file: T) -> Self {
+        self.emitted.push(EmittedFile {
+            archive_name,
+            file_name,
+            file,
+        });
+        self
+    }
+
+    // Converts every file in the outcome, the mapper is given the path of each file and files that fail to convert are dropped (the mapper is expected to log why)
+    fn filter_map<U, F>(self, file_name: &str, mapper: F) -> HandlerOutcome<U>
+    where
+        F: Fn(&str, T) -> Option<U>,
+    {
+        HandlerOutcome {
+            action: match self.action {
+                HandlerAction::Keep => HandlerAction::Keep,
+                HandlerAction::Modify(file) => match mapper(file_name, file) {
+                    Some(new_file) => HandlerAction::Modify(new_file),
+                    None => HandlerAction::Keep,
+                },
+                HandlerAction::Delete => HandlerAction::Delete,
+            },
+            emitted: self
+                .emitted
+                .into_iter()
+                .filter_map(|emitted| {
+                    let file = mapper(&emitted.file_name, emitted.file)?;
+                    Some(EmittedFile {
+                        archive_name: emitted.archive_name,
+                        file_name: emitted.file_name,
+                        file,
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+fn apply_handler_outcome(archive_name: &str, file_name: &str, outcome: HandlerOutcome<ZTFile>) {
+    match outcome.action {
+        HandlerAction::Keep => {}
+        HandlerAction::Modify(ztfile) => add_ztfile(Path::new(archive_name), file_name.to_string(), ztfile),
+        HandlerAction::Delete => {
+            info!("Removing file: {}", file_name);
+            LAZY_RESOURCE_MAP.lock().unwrap().remove(file_name);
         }
+    }
+    for emitted in outcome.emitted {
+        add_ztfile(Path::new(&emitted.archive_name), emitted.file_name, emitted.file);
+    }
+}
 
+// Text based files keep the type of the file they were created from unless the new path has a recognised extension
+fn string_to_ztfile(file_name: &str, string: String, default_file_type: &ZTFileType) -> Option<ZTFile> {
+    let string_length = string.len() as u32;
+    let file_type = ZTFileType::try_from(Path::new(file_name)).unwrap_or(default_file_type.clone());
+    match CString::new(string) {
+        Ok(c_string) => Some(ZTFile::Text(c_string, file_type, string_length)),
+        Err(_) => {
+            error!("Error converting {} to CString", file_name);
+            None
+        }
     }
 }
 
+fn write_ini_to_string(ini: &Ini) -> String {
+    let mut write_options = WriteOptions::default();
+    write_options.space_around_delimiters = true;
+    write_options.blank_lines_between_sections = 1;
+    ini.pretty_writes(&write_options)
+}
+
 fn get_file(file_name: &str) -> Option<(String, Box<[u8]>)> {
     let mut map = LAZY_RESOURCE_MAP.lock().unwrap();
     match map.get(&file_name) {
@@ -1006,6 +2124,20 @@ fn get_file(file_name: &str) -> Option<(String, Box<[u8]>)> {
     }
 }
 
+fn get_file_as_string(file_name: &str) -> Option<(String, String)> {
+    let Some((archive_name, file)) = get_file(file_name) else {
+        error!("Error getting file: {}", file_name);
+        return None;
+    };
+    match String::from_utf8(file.into_vec()) {
+        Ok(string) => Some((archive_name, string.trim_end_matches('\0').to_string())),
+        Err(_) => {
+            error!("Error converting file to string: {}", file_name);
+            None
+        }
+    }
+}
+
 // Note: We are excluding ztat* files until we need to override anything inside them, as they have a rediculous amount of files
 fn get_ztd_resources(dir: &Path, recursive: bool) -> Vec<PathBuf> {
     let mut resources = Vec::new();
@@ -1036,13 +2168,141 @@ static LAZY_RESOURCE_MAP: Lazy<Mutex<LazyResourceMap>> = Lazy::new(|| Mutex::new
 
 pub struct LazyResourceMap {
     map: HashMap<String, LazyResource>,
+    // Archives that also contain a resource but were overridden by a later archive, in load order
+    overridden: HashMap<String, Vec<ResourceProvider>>,
+    // Resources that were replaced or removed while the game could still hold them, freed once the game has released them
+    retired: Vec<LazyResource>,
+    // BFResourcePtrs that have been given to the game and the key of the resource that owns them
+    handed_to_game: HashMap<u32, String>,
+    acquires_since_release_check: usize,
 }
 
+/// Applied to a copy of the alias target the first time the alias is loaded, given the alias path and the target file
+pub type AliasTransform = Arc<dyn Fn(&str, ZTFile) -> anyhow::Result<ZTFile> + Send + Sync>;
+
+// Aliases can point at other aliases, this stops cycles from recursing forever
+const MAX_ALIAS_DEPTH: usize = 8;
+
 #[derive(Clone)]
 enum ResourceBacking {
     LazyZipFile{archive_name: String, archive: Arc<Mutex<ZipArchive<BufReader<File>>>>},
     LoadedZipFile{archive_name: String, archive: Arc<Mutex<ZipArchive<BufReader<File>>>>, data: u32},
     Custom{data: u32},
+    // Shares the target's data, aliases with a transform (or that are modified) are replaced with a Custom copy when loaded
+    Alias{target: String, transform: Option<AliasTransform>},
+    // Converted from a PNG and replaced with Custom resources the first time the animation or its palette is requested
+    Generated{source: Arc<PngSource>},
+}
+
+/// A PNG shipped by an OpenZT mod in place of an animation and palette pair, both are made from it at once as the animation names its palette
+struct PngSource {
+    archive_name: String,
+    png: Box<[u8]>,
+    animation_file_name: String,
+    palette_file_name: String,
+}
+
+impl PngSource {
+    // The animation and palette made from the PNG, the animation names the palette by its resource name
+    fn generate(&self) -> anyhow::Result<[(String, ZTFile); 2]> {
+        let (animation, palette) = sprite::png_to_animation(&self.png, self.palette_file_name.clone())
+            .with_context(|| format!("Error converting {} from {}", self.animation_file_name, self.archive_name))?;
+        let (animation_bytes, animation_size) = animation.write();
+        let (palette_bytes, palette_size) = palette.write();
+        Ok([
+            (self.animation_file_name.clone(), ZTFile::RawBytes(animation_bytes.into_boxed_slice(), ZTFileType::Animation, animation_size as u32)),
+            (self.palette_file_name.clone(), ZTFile::RawBytes(palette_bytes.into_boxed_slice(), ZTFileType::Palette, palette_size as u32)),
+        ])
+    }
+}
+
+impl ResourceBacking {
+    // The BFResourcePtr of a loaded resource
+    fn data(&self) -> Option<u32> {
+        match self {
+            ResourceBacking::LoadedZipFile{data, ..} | ResourceBacking::Custom{data} => Some(*data),
+            ResourceBacking::LazyZipFile{..} | ResourceBacking::Alias{..} | ResourceBacking::Generated{..} => None,
+        }
+    }
+
+    fn state_name(&self) -> &'static str {
+        match self {
+            ResourceBacking::LazyZipFile{..} => "lazy",
+            ResourceBacking::LoadedZipFile{..} => "loaded",
+            ResourceBacking::Custom{..} => "custom",
+            ResourceBacking::Alias{..} => "alias",
+            ResourceBacking::Generated{..} => "generated",
+        }
+    }
+}
+
+struct ResourceSearchResult {
+    file_name: String,
+    size: Option<u64>,
+    state: &'static str,
+    layer: ResourceLayer,
+    archive_name: Option<String>,
+}
+
+impl fmt::Display for ResourceSearchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.file_name,
+            self.size.map(|size| size.to_string()).unwrap_or("?".to_string()),
+            self.state,
+            self.layer,
+            self.archive_name.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+struct ResourceMemory {
+    file_name: String,
+    archive_name: String,
+    bytes: usize,
+}
+
+impl ResourceMemory {
+    // OpenZT mod resources are named openzt.mods.<mod_id>.* and resources a mod rewrote belong to that mod,
+    // everything else belongs to the mod id of the archive it came from or the archive itself for legacy archives.
+    // Mod ids contain dots so they are matched against the loaded mod ids, the longest match wins
+    fn owner(&self, archive_mod_ids: &HashMap<String, String>, patched_resources: &HashMap<String, String>) -> String {
+        let file_name = self.file_name.strip_suffix(" (retired)").unwrap_or(&self.file_name);
+        if let Some(mod_resource) = file_name.strip_prefix("openzt.mods.") {
+            let mod_id = archive_mod_ids
+                .values()
+                .filter(|mod_id| mod_resource.strip_prefix(mod_id.as_str()).is_some_and(|rest| rest.starts_with('.')))
+                .max_by_key(|mod_id| mod_id.len());
+            if let Some(mod_id) = mod_id {
+                return mod_id.clone();
+            }
+        }
+        if let Some(mod_id) = patched_resources.get(file_name) {
+            return mod_id.clone();
+        }
+        let archive_name = archive_file_name(&self.archive_name);
+        if archive_name == "openzt.ztd" {
+            return "openzt".to_string();
+        }
+        archive_mod_ids.get(&archive_name).cloned().unwrap_or(archive_name)
+    }
+}
+
+// Lowercase file name of an archive, resources name theirs in a few different ways (zip::./openzt.ztd, ./dlupdate\x.ztd, ...)
+fn archive_file_name(archive_name: &str) -> String {
+    let archive_name = archive_name.strip_prefix("zip::").unwrap_or(archive_name).replace('\\', "/");
+    Path::new(&archive_name).file_name().unwrap_or_default().to_string_lossy().to_lowercase()
+}
+
+// Approximate memory the zip crate holds for an open archive. Its entry type is private so entries are estimated
+// from their names, each name is stored three times (raw, decoded and as the index key)
+const ZIP_BUFFER_BYTES: usize = 8 * 1024;
+const ZIP_ENTRY_ESTIMATED_BYTES: usize = 160;
+
+fn zip_memory_usage(archive: &ZipArchive<BufReader<File>>) -> usize {
+    ZIP_BUFFER_BYTES + archive.file_names().map(|file_name| ZIP_ENTRY_ESTIMATED_BYTES + file_name.len() * 3).sum::<usize>()
 }
 
 struct ConcreteResource {
@@ -1052,24 +2312,35 @@ struct ConcreteResource {
     data: u32,
 }
 
+#[derive(Clone)]
+struct ResourceProvider {
+    layer: ResourceLayer,
+    archive_name: String,
+    archive: Arc<Mutex<ZipArchive<BufReader<File>>>>,
+    filename: String,
+}
+
 struct LazyResource {
     pub backing: ResourceBacking,
     pub filename: String,
     pub type_: ZTFileType,
+    pub layer: ResourceLayer,
 }
 
 impl LazyResourceMap {
     fn new() -> Self {
         Self {
             map: HashMap::new(),
+            overridden: HashMap::new(),
+            retired: Vec::new(),
+            handed_to_game: HashMap::new(),
+            acquires_since_release_check: 0,
         }
     }
 
-    fn _drop(&mut self, file_name: String) -> Option<()> {
-        let Some(value) = self.map.remove(&file_name) else {
-            return None;
-        };
-        
+    fn remove(&mut self, file_name: &str) -> Option<()> {
+        let value = self.map.remove(&file_name.to_ascii_lowercase())?;
+
         self.drop_inner(value);
         Some(())
     }
@@ -1082,30 +2353,77 @@ impl LazyResourceMap {
             ResourceBacking::Custom{data} => {
                 data
             },
-            ResourceBacking::LazyZipFile{archive_name: _, archive: _} => {
+            ResourceBacking::LazyZipFile{archive_name: _, archive: _} | ResourceBacking::Alias{target: _, transform: _} | ResourceBacking::Generated{source: _} => {
                 return;
             }
         };
-        let bf_resource_ptr = unsafe { Box::from_raw(data as *mut BFResourcePtr) };
-        match resource.type_ {
-            ZTFileType::Ini | ZTFileType::Ai | ZTFileType::Ani | ZTFileType::Cfg | ZTFileType::Lyt | ZTFileType::Scn | ZTFileType::Uca | ZTFileType::Ucs | ZTFileType::Ucb | ZTFileType::Toml | ZTFileType::Txt => {
-                let data_string = unsafe { CString::from_raw(data as *mut i8) };
-                drop(data_string);
+        // Only the game thread frees resources the game has been given, the game may be taking a ref to it right now
+        if self.handed_to_game.contains_key(&data) {
+            info!("Resource {} has been given to the game, keeping it until the game releases it", resource.filename);
+            self.retired.push(resource);
+            return;
+        }
+        free_bf_resource_ptr(data, &resource.type_);
+    }
+
+    // The game releases its refs through its own release path which OpenZT doesn't hook, so the resources it has been given
+    // are checked from acquire on the game thread. Once the game holds no refs, retired resources are freed and resources
+    // loaded from an archive go back to being lazy. Custom resources can't be reloaded and stay loaded
+    fn release_unreferenced(&mut self) {
+        self.acquires_since_release_check = 0;
+        let released = self.handed_to_game.keys().copied().filter(|data| game_refs(*data) == 0).collect::<Vec<u32>>();
+        for data in released {
+            let Some(key) = self.handed_to_game.remove(&data) else {
+                continue;
+            };
+            if let Some(index) = self.retired.iter().position(|resource| resource.backing.data() == Some(data)) {
+                let resource = self.retired.swap_remove(index);
+                free_bf_resource_ptr(data, &resource.type_);
+                continue;
             }
-            ZTFileType::Animation | ZTFileType::Bmp | ZTFileType::Lle | ZTFileType::TGA | ZTFileType::Wav | ZTFileType::Palette | ZTFileType::Zoo => {
-                let data_vec: Box<[u8]> = unsafe {
-                    Box::from_raw(slice::from_raw_parts_mut(
-                        bf_resource_ptr.data_ptr as *mut _,
-                        bf_resource_ptr.content_size as usize,
-                    ))
-                };
-                drop(data_vec);
+            let Some(resource) = self.map.get_mut(&key) else {
+                continue;
+            };
+            if let ResourceBacking::LoadedZipFile{archive_name, archive, data: loaded} = &resource.backing
+                && *loaded == data
+            {
+                resource.backing = ResourceBacking::LazyZipFile{archive_name: archive_name.clone(), archive: archive.clone()};
+                free_bf_resource_ptr(data, &resource.type_);
             }
         }
-        drop(bf_resource_ptr);
     }
-    
-    fn insert_lazy(&mut self, archive_path: String, file_name: String, archive: Arc<Mutex<ZipArchive<BufReader<File>>>>) {
+
+    // Resources modified in place no longer match their archive, they are reported as custom from then on
+    fn mark_custom(&mut self, key: &str) {
+        let Some(resource) = self.map.get_mut(key) else {
+            return;
+        };
+        if let ResourceBacking::LoadedZipFile{data, ..} = resource.backing {
+            resource.backing = ResourceBacking::Custom{data};
+        }
+    }
+
+    // Loads the resource if needed and takes a game reference to it, the ref is released by the game when it's done with the resource
+    fn acquire(&mut self, key: &str) -> anyhow::Result<Option<u32>> {
+        let Some(resource) = self.get(key)? else {
+            return Ok(None);
+        };
+        let mut bf_resource_ptr = get_from_memory::<BFResourcePtr>(resource.data);
+        if bf_resource_ptr.num_refs < OPENZT_PINNED_REFS {
+            error!("Resource {} has fewer refs than expected ({:#x}), re-pinning", resource.filename, bf_resource_ptr.num_refs);
+            bf_resource_ptr.num_refs = OPENZT_PINNED_REFS;
+        }
+        bf_resource_ptr.num_refs += 1;
+        save_to_memory(resource.data, bf_resource_ptr);
+        self.handed_to_game.insert(resource.data, resource.filename.to_ascii_lowercase());
+        self.acquires_since_release_check += 1;
+        if self.acquires_since_release_check >= RELEASE_CHECK_INTERVAL {
+            self.release_unreferenced();
+        }
+        Ok(Some(resource.data))
+    }
+
+    fn insert_lazy(&mut self, archive_path: String, file_name: String, archive: Arc<Mutex<ZipArchive<BufReader<File>>>>, layer: ResourceLayer) {
         let file_type = match ZTFileType::try_from(Path::new(&file_name)) {
             Ok(file_type) => file_type,
             Err(e) => {
@@ -1114,39 +2432,205 @@ impl LazyResourceMap {
             }
         };
 
-        if let Some(existing) = self.map.insert(file_name.clone().to_ascii_lowercase(), LazyResource {
+        let key = file_name.clone().to_ascii_lowercase();
+        if let Some(existing) = self.map.insert(key.clone(), LazyResource {
             backing: ResourceBacking::LazyZipFile{archive_name: archive_path.clone(), archive},
             filename: file_name.clone(),
             type_: file_type,
+            layer,
         }) {
+            if let ResourceBacking::LazyZipFile{archive_name, archive} | ResourceBacking::LoadedZipFile{archive_name, archive, data: _} = &existing.backing {
+                self.overridden.entry(key).or_default().push(ResourceProvider {
+                    layer: existing.layer,
+                    archive_name: archive_name.clone(),
+                    archive: archive.clone(),
+                    filename: existing.filename.clone(),
+                });
+            }
             self.drop_inner(existing);
         }
     }
 
+    // Every archive that provides a resource in load order, the last one is the one in use unless the resource was replaced by OpenZT
+    fn providers(&self, key: &str) -> Vec<(String, ResourceLayer)> {
+        let mut providers = self.overridden.get(key).map(|overridden| overridden.iter().map(|provider| (provider.archive_name.clone(), provider.layer)).collect()).unwrap_or(Vec::new());
+        if let (Some(archive_name), Some(resource)) = (self.archive_name(key), self.map.get(key)) {
+            providers.push((archive_name, resource.layer));
+        }
+        providers
+    }
+
     fn insert_loaded(&mut self, resource: LazyResource) {
         if let Some(existing) = self.map.insert(resource.filename.to_ascii_lowercase(), resource) {
             self.drop_inner(existing);
         }
     }
 
+    // Replacements keep the layer of the resource they replace, new resources are created by OpenZT so belong to the OpenZTMod layer
     fn insert_custom(&mut self, file_name: String, file_type: ZTFileType, data: u32) {
-        if let Some(existing) = self.map.insert(file_name.to_ascii_lowercase(), LazyResource {
+        let key = file_name.to_ascii_lowercase();
+        let layer = self.map.get(&key).map(|existing| existing.layer).unwrap_or(ResourceLayer::OpenZTMod);
+        if let Some(existing) = self.map.insert(key, LazyResource {
             backing: ResourceBacking::Custom{data},
             filename: file_name.clone(),
             type_: file_type,
+            layer,
         }) {
             self.drop_inner(existing);
         }
     }
 
-    fn get(&mut self, key: &str) -> anyhow::Result<Option<ConcreteResource>> {
-        let lowercase_key = key.to_ascii_lowercase();
-        let Some(resource) = self.map.get_mut(&lowercase_key) else {
-            info!("LazyResource not found: {}", lowercase_key);
-            return Ok(None);
-        };
-
-        // TODO: Use std::mem::take/replace to avoid cloning
+    fn insert_generated(&mut self, archive_name: String, png: Box<[u8]>, animation_file_name: String, palette_file_name: String, layer: ResourceLayer) {
+        let source = Arc::new(PngSource {
+            archive_name,
+            png,
+            animation_file_name: animation_file_name.clone(),
+            palette_file_name: palette_file_name.clone(),
+        });
+        for (file_name, file_type) in [(animation_file_name, ZTFileType::Animation), (palette_file_name, ZTFileType::Palette)] {
+            if let Some(existing) = self.map.insert(file_name.to_ascii_lowercase(), LazyResource {
+                backing: ResourceBacking::Generated{source: source.clone()},
+                filename: file_name,
+                type_: file_type,
+                layer,
+            }) {
+                self.drop_inner(existing);
+            }
+        }
+    }
+
+    // Converts the PNG and swaps in the results for whichever of the animation and palette are still backed by it
+    fn generate(&mut self, source: &Arc<PngSource>) -> anyhow::Result<()> {
+        for (file_name, ztfile) in source.generate()? {
+            let Some(resource) = self.map.get_mut(&file_name.to_ascii_lowercase()) else {
+                continue;
+            };
+            let ResourceBacking::Generated{source: backing_source} = &resource.backing else {
+                continue;
+            };
+            if !Arc::ptr_eq(backing_source, source) {
+                continue;
+            }
+            let data = ztfile_to_raw_resource(&source.archive_name, file_name, ztfile)?;
+            resource.backing = ResourceBacking::Custom{data};
+        }
+        info!("Generated {} and {} from PNG", source.animation_file_name, source.palette_file_name);
+        Ok(())
+    }
+
+    fn get(&mut self, key: &str) -> anyhow::Result<Option<ConcreteResource>> {
+        self.get_with_depth(key, 0)
+    }
+
+    // Aliases take the type and layer of their target at the time they are added
+    fn insert_alias(&mut self, alias: &str, target: &str, transform: Option<AliasTransform>) -> anyhow::Result<()> {
+        let key = alias.to_ascii_lowercase();
+        let target_key = target.to_ascii_lowercase();
+        if key == target_key {
+            return Err(anyhow!("Resource {} can't be an alias of itself", alias));
+        }
+        let Some(target_resource) = self.map.get(&target_key) else {
+            return Err(anyhow!("Alias target {} not found", target));
+        };
+        let alias_resource = LazyResource {
+            backing: ResourceBacking::Alias{target: target_key, transform},
+            filename: alias.to_string(),
+            type_: target_resource.type_.clone(),
+            layer: target_resource.layer,
+        };
+        if let Some(existing) = self.map.insert(key, alias_resource) {
+            self.drop_inner(existing);
+        }
+        Ok(())
+    }
+
+    // Replaces an alias with a Custom copy of its target (with the transform applied), used before an alias is modified so the target is left untouched
+    fn materialise_alias(&mut self, key: &str, depth: usize) -> anyhow::Result<()> {
+        let Some(resource) = self.map.get(key) else {
+            return Ok(());
+        };
+        let ResourceBacking::Alias{target, transform} = resource.backing.clone() else {
+            return Ok(());
+        };
+        let alias_filename = resource.filename.clone();
+        let Some(target_resource) = self.get_with_depth(&target, depth + 1)? else {
+            return Err(anyhow!("Alias target {} not found for {}", target, alias_filename));
+        };
+
+        let target_ptr = get_from_memory::<BFResourcePtr>(target_resource.data);
+        let target_data: Box<[u8]> = unsafe { slice::from_raw_parts(target_ptr.data_ptr as *const u8, target_ptr.content_size as usize) }.into();
+        let mut ztfile = ZTFile::new(target_resource.filename.clone(), target_ptr.content_size, target_data)?;
+        if let Some(transform) = transform {
+            ztfile = transform(&alias_filename, ztfile).with_context(|| format!("Error transforming alias {}", alias_filename))?;
+        }
+        let archive_name = get_string_from_memory(target_ptr.bf_zip_name_ptr);
+        let data = ztfile_to_raw_resource(&archive_name, alias_filename.clone(), ztfile)?;
+        if let Some(resource) = self.map.get_mut(key) {
+            resource.backing = ResourceBacking::Custom{data};
+        }
+        Ok(())
+    }
+
+    // Resources the game has been given can't be changed in place as the game may be reading them (modify_ztfile_as_ini frees the old text),
+    // so they're swapped for a copy and the original is retired. The game sees the change the next time it requests the file
+    fn copy_if_handed_to_game(&mut self, key: &str) -> anyhow::Result<()> {
+        let Some(resource) = self.map.get(key) else {
+            return Ok(());
+        };
+        let data = match resource.backing {
+            ResourceBacking::LoadedZipFile{data, ..} | ResourceBacking::Custom{data} => data,
+            ResourceBacking::LazyZipFile{..} | ResourceBacking::Alias{..} | ResourceBacking::Generated{..} => return Ok(()),
+        };
+        if !self.handed_to_game.contains_key(&data) {
+            return Ok(());
+        }
+        let resource_ptr = get_from_memory::<BFResourcePtr>(data);
+        let resource_data: Box<[u8]> = unsafe { slice::from_raw_parts(resource_ptr.data_ptr as *const u8, resource_ptr.content_size as usize) }.into();
+        let ztfile = ZTFile::new(resource.filename.clone(), resource_ptr.content_size, resource_data)?;
+        let archive_name = get_string_from_memory(resource_ptr.bf_zip_name_ptr);
+        let copy = ztfile_to_raw_resource(&archive_name, resource.filename.clone(), ztfile)?;
+        let file_type = resource.type_.clone();
+        let file_name = resource.filename.clone();
+        self.insert_custom(file_name, file_type, copy);
+        Ok(())
+    }
+
+    // Follows an alias chain to the resource that holds the data, returns None if the chain is broken or too long
+    fn resolve_alias(&self, key: &str) -> Option<String> {
+        let mut key = key.to_string();
+        for _ in 0..=MAX_ALIAS_DEPTH {
+            match &self.map.get(&key)?.backing {
+                ResourceBacking::Alias{target, transform: _} => key = target.clone(),
+                _ => return Some(key),
+            }
+        }
+        None
+    }
+
+    fn get_with_depth(&mut self, key: &str, depth: usize) -> anyhow::Result<Option<ConcreteResource>> {
+        let lowercase_key = key.to_ascii_lowercase();
+        let Some(resource) = self.map.get_mut(&lowercase_key) else {
+            info!("LazyResource not found: {}", lowercase_key);
+            return Ok(None);
+        };
+
+        if let ResourceBacking::Alias{target, transform} = resource.backing.clone() {
+            if depth >= MAX_ALIAS_DEPTH {
+                return Err(anyhow!("Alias chain for {} is too long, is there a cycle?", lowercase_key));
+            }
+            if transform.is_none() {
+                return self.get_with_depth(&target, depth + 1);
+            }
+            self.materialise_alias(&lowercase_key, depth)?;
+            return self.get_with_depth(&lowercase_key, depth);
+        }
+
+        if let ResourceBacking::Generated{source} = resource.backing.clone() {
+            self.generate(&source)?;
+            return self.get_with_depth(&lowercase_key, depth);
+        }
+
+        // TODO: Use std::mem::take/replace to avoid cloning
         let (archive_name, data) = match resource.backing.clone() {
             ResourceBacking::LazyZipFile{archive_name, archive} => {
                 let mut binding = archive.lock().unwrap();
@@ -1164,7 +2648,8 @@ impl LazyResourceMap {
             },
             ResourceBacking::Custom{data} => {
                 (None, data)
-            }
+            },
+            ResourceBacking::Alias{..} | ResourceBacking::Generated{..} => unreachable!("Aliases and generated resources are resolved above"),
         };
 
         Ok(Some(ConcreteResource{
@@ -1180,7 +2665,7 @@ impl LazyResourceMap {
     }
 
     fn not_loaded_len(&self) -> usize {
-        self.map.values().filter(|x| matches!(x.backing, ResourceBacking::LazyZipFile{..})).count()
+        self.map.values().filter(|x| matches!(x.backing, ResourceBacking::LazyZipFile{..} | ResourceBacking::Generated{..})).count()
     }
 
     fn len(&self) -> usize {
@@ -1191,9 +2676,172 @@ impl LazyResourceMap {
         self.map.contains_key(key)
     }
 
+    // Aliases report the archive of their target
+    fn archive_name(&self, key: &str) -> Option<String> {
+        match &self.map.get(&self.resolve_alias(key)?)?.backing {
+            ResourceBacking::LazyZipFile{archive_name, archive: _} | ResourceBacking::LoadedZipFile{archive_name, archive: _, data: _} => Some(archive_name.clone()),
+            ResourceBacking::Custom{data} => Some(get_string_from_memory(get_from_memory::<BFResourcePtr>(*data).bf_zip_name_ptr)),
+            ResourceBacking::Generated{source} => Some(source.archive_name.clone()),
+            ResourceBacking::Alias{..} => None,
+        }
+    }
+
+    // Aliases with a transform are materialised and PNGs are converted so the data the game would get is returned
+    fn peek(&mut self, key: &str) -> anyhow::Result<Option<Box<[u8]>>> {
+        if let Some(LazyResource{backing: ResourceBacking::Alias{transform: Some(_), ..}, ..}) = self.map.get(key) {
+            self.materialise_alias(key, 0)?;
+        }
+        if let Some(ResourceBacking::Generated{source}) = self.resolve_alias(key).and_then(|key| self.map.get(&key)).map(|resource| resource.backing.clone()) {
+            self.generate(&source)?;
+        }
+        let Some(resource) = self.resolve_alias(key).and_then(|key| self.map.get(&key)) else {
+            return Ok(None);
+        };
+        match &resource.backing {
+            ResourceBacking::LazyZipFile{archive_name: _, archive} => {
+                let mut binding = archive.lock().unwrap();
+                Ok(Some(read_file_from_zip(&mut binding, &resource.filename)?))
+            },
+            ResourceBacking::LoadedZipFile{data, ..} | ResourceBacking::Custom{data} => {
+                let resource_ptr = get_from_memory::<BFResourcePtr>(*data);
+                let file = unsafe { slice::from_raw_parts(resource_ptr.data_ptr as *const u8, resource_ptr.content_size as usize) };
+                Ok(Some(file.into()))
+            },
+            ResourceBacking::Alias{..} | ResourceBacking::Generated{..} => Ok(None),
+        }
+    }
+
+    // The resource as shipped by the first archive that provided it, None if no later archive overrode it
+    fn peek_original(&self, key: &str) -> anyhow::Result<Option<Box<[u8]>>> {
+        let Some(provider) = self.overridden.get(key).and_then(|providers| providers.first()) else {
+            return Ok(None);
+        };
+        let mut binding = provider.archive.lock().unwrap();
+        Ok(Some(read_file_from_zip(&mut binding, &provider.filename)?))
+    }
+
+    // Loaded and custom resources along with retired resources the game still holds, lazy, alias and generated resources don't own any game memory.
+    // Each open archive's read buffer and central directory is listed as <archive> (zip index)
+    fn memory_usage(&self) -> Vec<ResourceMemory> {
+        // Keyed by pointer as every resource from an archive shares its Arc
+        let mut archives = HashMap::new();
+        let backings = self.map.values().chain(self.retired.iter()).filter_map(|resource| match &resource.backing {
+            ResourceBacking::LazyZipFile{archive_name, archive} | ResourceBacking::LoadedZipFile{archive_name, archive, ..} => Some((archive_name, archive)),
+            _ => None,
+        });
+        let providers = self.overridden.values().flatten().map(|provider| (&provider.archive_name, &provider.archive));
+        for (archive_name, archive) in backings.chain(providers) {
+            archives.entry(Arc::as_ptr(archive)).or_insert((archive_name, archive));
+        }
+        let zip_indexes = archives.into_values().map(|(archive_name, archive)| ResourceMemory {
+            file_name: format!("{} (zip index)", archive_file_name(archive_name)),
+            archive_name: archive_name.clone(),
+            bytes: zip_memory_usage(&archive.lock().unwrap()),
+        });
+
+        let resident = self.map.iter().map(|(file_name, resource)| (file_name.clone(), resource));
+        let retired = self.retired.iter().map(|resource| (format!("{} (retired)", resource.filename.to_lowercase()), resource));
+        resident
+            .chain(retired)
+            .filter_map(|(file_name, resource)| {
+                let data = match &resource.backing {
+                    ResourceBacking::LoadedZipFile{data, ..} | ResourceBacking::Custom{data} => *data,
+                    ResourceBacking::LazyZipFile{..} | ResourceBacking::Alias{..} | ResourceBacking::Generated{..} => return None,
+                };
+                let resource_ptr = get_from_memory::<BFResourcePtr>(data);
+                let archive_name = get_string_from_memory(resource_ptr.bf_zip_name_ptr);
+                let resource_name = get_string_from_memory(resource_ptr.bf_resource_name_ptr);
+                // Text files are stored as CStrings so have a trailing nul
+                let data_bytes = match resource.type_ {
+                    ZTFileType::Ini | ZTFileType::Ai | ZTFileType::Ani | ZTFileType::Cfg | ZTFileType::Lyt | ZTFileType::Scn | ZTFileType::Uca | ZTFileType::Ucs | ZTFileType::Ucb | ZTFileType::Toml | ZTFileType::Txt => resource_ptr.content_size as usize + 1,
+                    ZTFileType::Animation | ZTFileType::Bmp | ZTFileType::Lle | ZTFileType::TGA | ZTFileType::Wav | ZTFileType::Palette | ZTFileType::Zoo => resource_ptr.content_size as usize,
+                };
+                let bytes = data_bytes + archive_name.len() + 1 + resource_name.len() + 1 + std::mem::size_of::<BFResourcePtr>();
+                Some(ResourceMemory { file_name, archive_name, bytes })
+            })
+            .chain(zip_indexes)
+            .collect()
+    }
+
     fn files(&self) -> Box<dyn Iterator<Item = String> + '_> {
         Box::new(self.map.keys().cloned())
     }
+
+    // Returns matching resources sorted by path, archive_filter is matched as a substring of the lowercased archive name
+    fn search(&self, matcher: &Regex, file_type: Option<&ZTFileType>, archive_filter: Option<&str>) -> Vec<ResourceSearchResult> {
+        let mut results = Vec::new();
+        for (file_name, resource) in self.map.iter() {
+            if !matcher.is_match(file_name) {
+                continue;
+            }
+            if file_type.is_some_and(|file_type| file_type != &resource.type_) {
+                continue;
+            }
+
+            let (archive_name, size) = match &resource.backing {
+                ResourceBacking::LazyZipFile{archive_name, archive} => {
+                    let size = archive.lock().unwrap().by_name(&resource.filename).map(|file| file.size()).ok();
+                    (Some(archive_name.clone()), size)
+                },
+                ResourceBacking::LoadedZipFile{archive_name, archive: _, data} => {
+                    let resource_ptr = get_from_memory::<BFResourcePtr>(*data);
+                    (Some(archive_name.clone()), Some(resource_ptr.content_size as u64))
+                },
+                ResourceBacking::Custom{data} => {
+                    let resource_ptr = get_from_memory::<BFResourcePtr>(*data);
+                    (Some(get_string_from_memory(resource_ptr.bf_zip_name_ptr)), Some(resource_ptr.content_size as u64))
+                },
+                ResourceBacking::Alias{..} => (self.archive_name(file_name), None),
+                ResourceBacking::Generated{source} => (Some(source.archive_name.clone()), None),
+            };
+
+            if let Some(archive_filter) = archive_filter
+                && !archive_name.as_ref().is_some_and(|archive_name| archive_name.to_lowercase().contains(archive_filter))
+            {
+                continue;
+            }
+
+            results.push(ResourceSearchResult {
+                file_name: file_name.clone(),
+                size,
+                state: resource.backing.state_name(),
+                layer: resource.layer,
+                archive_name,
+            });
+        }
+        results.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        results
+    }
+}
+
+/// Returns the contents of a resource without loading it into the resource map
+pub fn peek_file(file_name: &str) -> Option<Box<[u8]>> {
+    let mut binding = LAZY_RESOURCE_MAP.lock().unwrap();
+    match binding.peek(&file_name.to_lowercase()) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Error reading file: {} error: {}", file_name, e);
+            None
+        }
+    }
+}
+
+/// Like peek_file but returns the first version of a resource that a later archive overrode, e.g. the vanilla file a mod replaces.
+/// Resources that were never overridden return their only version
+pub fn peek_original_file(file_name: &str) -> Option<Box<[u8]>> {
+    let original = LAZY_RESOURCE_MAP.lock().unwrap().peek_original(&file_name.to_lowercase());
+    match original {
+        Ok(Some(file)) => Some(file),
+        Ok(None) => peek_file(file_name),
+        Err(e) => {
+            error!("Error reading file: {} error: {}", file_name, e);
+            None
+        }
+    }
+}
+
+pub fn get_resource_names() -> Vec<String> {
+    LAZY_RESOURCE_MAP.lock().unwrap().files().collect()
 }
 
 pub fn check_file(file_name: &str) -> bool {
@@ -1201,6 +2849,52 @@ pub fn check_file(file_name: &str) -> bool {
     binding.contains_key(&file_name.to_lowercase())
 }
 
+// Returns the archive a resource was loaded from without loading the resource
+fn get_archive_name(file_name: &str) -> Option<String> {
+    let binding = LAZY_RESOURCE_MAP.lock().unwrap();
+    binding.archive_name(&file_name.to_lowercase())
+}
+
+// Number of references the game currently holds to an OpenZT owned BFResourcePtr
+fn game_refs(bf_resource_ptr_ptr: u32) -> u32 {
+    get_from_memory::<BFResourcePtr>(bf_resource_ptr_ptr).num_refs.saturating_sub(OPENZT_PINNED_REFS)
+}
+
+// Frees a BFResourcePtr created by ztfile_to_raw_resource along with its data and name strings
+fn free_bf_resource_ptr(bf_resource_ptr_ptr: u32, file_type: &ZTFileType) {
+    let bf_resource_ptr = unsafe { Box::from_raw(bf_resource_ptr_ptr as *mut BFResourcePtr) };
+    match file_type {
+        ZTFileType::Ini | ZTFileType::Ai | ZTFileType::Ani | ZTFileType::Cfg | ZTFileType::Lyt | ZTFileType::Scn | ZTFileType::Uca | ZTFileType::Ucs | ZTFileType::Ucb | ZTFileType::Toml | ZTFileType::Txt => {
+            let data_string = unsafe { CString::from_raw(bf_resource_ptr.data_ptr as *mut i8) };
+            drop(data_string);
+        }
+        ZTFileType::Animation | ZTFileType::Bmp | ZTFileType::Lle | ZTFileType::TGA | ZTFileType::Wav | ZTFileType::Palette | ZTFileType::Zoo => {
+            let data_vec: Box<[u8]> = unsafe {
+                Box::from_raw(slice::from_raw_parts_mut(
+                    bf_resource_ptr.data_ptr as *mut _,
+                    bf_resource_ptr.content_size as usize,
+                ))
+            };
+            drop(data_vec);
+        }
+    }
+    drop(unsafe { CString::from_raw(bf_resource_ptr.bf_zip_name_ptr as *mut i8) });
+    drop(unsafe { CString::from_raw(bf_resource_ptr.bf_resource_name_ptr as *mut i8) });
+    drop(bf_resource_ptr);
+}
+
+// Like get_file_ptr but also takes a game reference, only use this when handing the resource to the game
+fn acquire_file_ptr(file_name: &str) -> Option<u32> {
+    let mut binding = LAZY_RESOURCE_MAP.lock().unwrap();
+    match binding.acquire(&file_name.to_lowercase()) {
+        Ok(ptr) => ptr,
+        Err(e) => {
+            error!("Error loading file: {} error: {}", file_name, e);
+            None
+        }
+    }
+}
+
 pub fn get_file_ptr(file_name: &str) -> Option<u32> {
     let mut binding = LAZY_RESOURCE_MAP.lock().unwrap();
     if let Ok(Some(resource)) = binding.get(&file_name.to_lowercase()) {
@@ -1210,25 +2904,174 @@ pub fn get_file_ptr(file_name: &str) -> Option<u32> {
     }
 }
 
+/// Progress of OpenZT's own resource loading, which runs on a background thread once the vanilla resource manager is constructed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LoadState {
+    NotStarted,
+    /// Archives are being opened and indexed, OpenZT mod definitions are loaded here. Once every archive is open only files
+    /// in archives that haven't been indexed yet, or that a pending handler could touch, aren't ready
+    Indexing,
+    /// Handlers are running, only files a pending handler could touch aren't ready yet
+    Handling,
+    Ready,
+}
+
+static LOAD_STATE: Lazy<(Mutex<LoadState>, Condvar)> = Lazy::new(|| (Mutex::new(LoadState::NotStarted), Condvar::new()));
+
+// Handlers that haven't finished running over every file yet, a requested file is only held back if one of these could still change it
+static PENDING_HANDLERS: Lazy<Mutex<Vec<Handler>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Archives that have been opened but not indexed yet, None while archives are still being opened as any of them could hold any file
+static PENDING_ARCHIVES: Lazy<Mutex<Option<PendingArchives>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Default)]
+struct PendingArchives {
+    // Lowercase names of the files in pending archives, cfgs stay until legacy cfgs have been merged
+    files: HashSet<String>,
+    openzt_archives: usize,
+}
+
+fn set_load_state(state: LoadState) {
+    let (lock, condvar) = &*LOAD_STATE;
+    *lock.lock().unwrap() = state;
+    condvar.notify_all();
+    info!("Resource load state: {:?}", state);
+}
+
+pub fn load_state() -> LoadState {
+    *LOAD_STATE.0.lock().unwrap()
+}
+
+/// Blocks until background loading has reached at least the given state, returns immediately if it already has
+/// or if loading hasn't started (nothing would wake us up if the resource manager is never constructed)
+pub fn wait_for_load_state(state: LoadState) {
+    let (lock, condvar) = &*LOAD_STATE;
+    let current = lock.lock().unwrap();
+    if *current == LoadState::NotStarted || *current >= state {
+        return;
+    }
+    let now = std::time::Instant::now();
+    drop(condvar.wait_while(current, |current| *current < state).unwrap());
+    info!("Waited {:.2?} for resource load state {:?}", now.elapsed(), state);
+}
+
+/// Blocks until all OpenZT resources are loaded and every non OnDemand handler has run
+pub fn wait_for_resources() {
+    wait_for_load_state(LoadState::Ready);
+}
+
+// Whether a file can be handed to the game without waiting for background loading to finish
+fn resource_ready(file_name: &str) -> bool {
+    match load_state() {
+        // NotStarted means the resource manager hasn't been constructed so there is nothing to wait for
+        LoadState::NotStarted | LoadState::Ready => true,
+        LoadState::Indexing | LoadState::Handling => {
+            let openzt_archives_pending = match &*PENDING_ARCHIVES.lock().unwrap() {
+                None => return false,
+                Some(pending) if pending.files.contains(file_name) => return false,
+                Some(pending) => pending.openzt_archives > 0,
+            };
+            let (handlers_pending, handler_matches) = {
+                let pending_handlers = PENDING_HANDLERS.lock().unwrap();
+                (!pending_handlers.is_empty(), pending_handlers.iter().any(|handler| handler.matches_path(file_name)))
+            };
+            // OpenZT mods and handlers can both emit new OpenZT resources so missing ones might not exist yet
+            if (openzt_archives_pending || handlers_pending) && file_name.starts_with("openzt") && !check_file(file_name) {
+                return false;
+            }
+            !handler_matches
+        }
+    }
+}
+
+fn mark_stage_handled(stage: &RunStage) {
+    PENDING_HANDLERS.lock().unwrap().retain(|handler| &handler.stage != stage);
+}
+
+/// Loads resources from zoo.ini's resource paths on a background thread so the game can carry on starting up,
+/// BFResource_attempt/BFResource_prepare wait for the loader if a file that isn't ready is requested.
+///
+/// State shared between the loader and the game thread:
+/// - LAZY_RESOURCE_MAP, only through its mutex. The loader indexes archives, runs handlers and adds or replaces resources.
+///   It never frees or changes in place a resource the game has been given, drop_inner retires those and modify_ztfile copies them first
+/// - LOAD_STATE, PENDING_ARCHIVES and PENDING_HANDLERS, which hold the game back from files that aren't ready
+/// - registries filled from OpenZT mod defs (strings, locations/habitats, colour variants, mod ids), ZTUI_general_getInfoImageName
+///   waits for LoadState::Handling before reading them
+///
+/// Nothing is swept or unloaded from the loader thread
+fn load_resources_in_background(paths: Vec<String>) {
+    set_load_state(LoadState::Indexing);
+    let thread_paths = paths.clone();
+    let spawn_result = std::thread::Builder::new().name("openzt-resource-loader".to_string()).spawn(move || {
+        load_resources(thread_paths);
+        set_load_state(LoadState::Ready);
+        run_startup_checks();
+    });
+    if let Err(e) = spawn_result {
+        error!("Failed to start resource loading thread, loading on the main thread: {}", e);
+        load_resources(paths);
+        set_load_state(LoadState::Ready);
+        run_startup_checks();
+    }
+}
+
 fn load_resources(paths: Vec<String>) {
     use std::time::Instant;
     let now = Instant::now();
     let mut resource_count = 0;
 
+    // Cloned so OnDemand handlers and add_handler aren't blocked on the game thread while handlers run here
+    let handlers = get_handlers();
+    *PENDING_HANDLERS.lock().unwrap() = handlers.iter().filter(|handler| handler.stage != RunStage::OnDemand).cloned().collect();
+    *PENDING_ARCHIVES.lock().unwrap() = None;
+
+    let mut archives = Vec::new();
     paths.iter().rev().for_each(|path| {
         let resources = get_ztd_resources(Path::new(path), false);
         resources.iter().for_each(|resource| {
-            info!("Loading resource: {}", resource.display());
             let file_name = resource.to_str().unwrap_or_default().to_lowercase();
             if file_name.ends_with(".ztd") {
-                match handle_ztd(resource) {
-                    Ok(count) => resource_count += count,
-                    Err(err) => error!("Error loading ztd: {} -> {}", file_name, err),
+                match open_ztd(resource) {
+                    Ok(archive) => archives.push(archive),
+                    Err(err) => error!("Error opening ztd: {} -> {}", file_name, err),
                 }
             }
         });
     });
 
+    // sort_by_key is stable so archives in the same layer keep their zoo.ini order
+    archives.sort_by_key(|archive| archive.layer);
+
+    let archive_files = archives.iter().map(|archive| archive.file_names()).collect::<Vec<Vec<String>>>();
+    *PENDING_ARCHIVES.lock().unwrap() = Some(PendingArchives {
+        files: archive_files.iter().flatten().cloned().collect(),
+        openzt_archives: archives.iter().filter(|archive| archive.meta.is_some()).count(),
+    });
+
+    for (archive, files) in archives.into_iter().zip(archive_files) {
+        info!("Loading resource: {} ({})", archive.path.display(), archive.layer);
+        let path = archive.path.clone();
+        let is_openzt = archive.meta.is_some();
+        match handle_ztd(archive) {
+            Ok(count) => resource_count += count,
+            Err(err) => error!("Error loading ztd: {} -> {}", path.display(), err),
+        }
+        if let Some(pending) = PENDING_ARCHIVES.lock().unwrap().as_mut() {
+            files.iter().filter(|file| !file.ends_with(".cfg")).for_each(|file| {
+                pending.files.remove(file);
+            });
+            if is_openzt {
+                pending.openzt_archives -= 1;
+            }
+        }
+    }
+
+    let merged_count = merge_legacy_cfgs();
+    info!("Merged {} legacy cfgs shipped by multiple archives", merged_count);
+    *PENDING_ARCHIVES.lock().unwrap() = Some(PendingArchives::default());
+
+    set_load_state(LoadState::Handling);
+
     let files = {
         let map = LAZY_RESOURCE_MAP.lock().unwrap();
 
@@ -1247,29 +3090,29 @@ fn load_resources(paths: Vec<String>) {
 
     let now = Instant::now();
 
-    let data_mutex = RESOURCE_HANDLER_ARRAY.lock().unwrap();
-
 
     info!("Running BeforeOpenZTMods handlers");
-    for handler in data_mutex.iter() {
+    for handler in handlers.iter() {
         if handler.stage == RunStage::BeforeOpenZTMods {
             files.clone().for_each(|file| {
                 handler.handle(&file);
             });
         }
     }
+    mark_stage_handled(&RunStage::BeforeOpenZTMods);
 
     // TODO: Implement patching
     // apply_patches();
 
     info!("Running AfterOpenZTMods handlers");
-    for handler in data_mutex.iter() {
+    for handler in handlers.iter() {
         if handler.stage == RunStage::AfterOpenZTMods {
             files.clone().for_each(|file| {
                 handler.handle(&file);
             });
         }
     }
+    mark_stage_handled(&RunStage::AfterOpenZTMods);
 
     let mut filtered_files = Vec::new();
 
@@ -1288,14 +3131,20 @@ fn load_resources(paths: Vec<String>) {
     info!("Loaded {} filtered files", filtered_files.len());
 
     info!("Running AfterFiltering handlers");
-    for handler in data_mutex.iter() {
+    for handler in handlers.iter() {
         if handler.stage == RunStage::AfterFiltering {
             filtered_files.clone().into_iter().for_each(|file| {
                handler.handle(&file);
             });
         }
     }
+    mark_stage_handled(&RunStage::AfterFiltering);
     
+    let on_demand_count = handlers.iter().filter(|handler| handler.stage == RunStage::OnDemand).count();
+    if on_demand_count > 0 {
+        info!("{} OnDemand handlers will run when files are first requested", on_demand_count);
+    }
+
     let elapsed = now.elapsed();
     info!(
         "Extra handling took an extra: {:.2?}",
@@ -1303,21 +3152,58 @@ fn load_resources(paths: Vec<String>) {
     );
 }
 
-fn handle_ztd(resource: &PathBuf) -> anyhow::Result<i32> {
-    let mut load_count = 0;
+struct ZtdArchive {
+    path: PathBuf,
+    zip: ZipArchive<BufReader<File>>,
+    layer: ResourceLayer,
+    // Parsed meta.toml, only OpenZT mods have one
+    meta: Option<mods::Meta>,
+}
+
+impl ZtdArchive {
+    // Lowercase names of the files in the archive, without directories
+    fn file_names(&self) -> Vec<String> {
+        self.zip.file_names().filter(|file_name| !file_name.ends_with('/')).map(|file_name| file_name.to_lowercase()).collect()
+    }
+}
+
+// Opens a ztd and works out its layer, OpenZT mods are identified by their meta.toml
+fn open_ztd(resource: &PathBuf) -> anyhow::Result<ZtdArchive> {
     let file = File::open(resource).with_context(|| format!("Error opening file: {}", resource.display()))?;
 
+    let buf_reader = BufReader::new(file);
+
+    let mut zip = zip::ZipArchive::new(buf_reader).with_context(|| format!("Error reading zip: {}", resource.display()))?;
+
+    let meta = if zip.by_name("meta.toml").is_ok() {
+        Some(toml::from_str::<mods::Meta>(&read_file_from_zip_to_string(&mut zip, "meta.toml")?).with_context(|| "Failed to parse meta.toml")?)
+    } else {
+        None
+    };
+
+    let layer = resolve_archive_layer(&resource.to_string_lossy(), meta.as_ref().map(|meta| meta.mod_id().as_str()));
+
+    Ok(ZtdArchive {
+        path: resource.clone(),
+        zip,
+        layer,
+        meta,
+    })
+}
+
+fn handle_ztd(ztd_archive: ZtdArchive) -> anyhow::Result<i32> {
+    let mut load_count = 0;
+    let ZtdArchive { path: resource, mut zip, layer, meta } = ztd_archive;
+
     let resource_string = resource
         .clone()
         .into_os_string()
         .into_string()
         .map_err(|e| anyhow::anyhow!("error converting resource path to string: {}", e.to_string_lossy()))?;
 
-    let buf_reader = BufReader::new(file);
+    LOADED_ARCHIVES.lock().unwrap().push((resource_string.clone(), layer, meta.as_ref().map(|meta| meta.mod_id().to_string())));
 
-    let mut zip = zip::ZipArchive::new(buf_reader).with_context(|| format!("Error reading zip: {}", resource.display()))?;
-
-    let ztd_type = load_open_zt_mod(&mut zip)?;
+    let ztd_type = load_open_zt_mod(&mut zip, &resource_string, layer, meta)?;
 
     if ztd_type == mods::ZtdType::Openzt {
         return Ok(0);
@@ -1328,7 +3214,7 @@ fn handle_ztd(resource: &PathBuf) -> anyhow::Result<i32> {
     let mut map = LAZY_RESOURCE_MAP.lock().unwrap();
     
     archive.lock().unwrap().file_names().filter(|s| !s.ends_with("/")).for_each(|file_name| {
-        map.insert_lazy(resource_string.clone(), file_name.to_string(), archive.clone());
+        map.insert_lazy(resource_string.clone(), file_name.to_string(), archive.clone(), layer);
         load_count += 1;
     });
 
@@ -1337,99 +3223,453 @@ fn handle_ztd(resource: &PathBuf) -> anyhow::Result<i32> {
 
 
 fn parse_cfg(file_name: &String) -> Vec<String> {
-    if let Some(legacy_cfg) = get_legacy_cfg_type(file_name) {
-        info!("Legacy cfg: {} {:?}", file_name, legacy_cfg.cfg_type);
+    if get_legacy_cfg_type(file_name).is_none() {
+        return Vec::new();
+    }
+    let Some((_archive_name, file)) = get_file(file_name) else {
+        error!("Error getting file: {}", file_name);
+        return Vec::new();
+    };
+    let Some(ini) = read_ini(file_name, &file) else {
+        return Vec::new();
+    };
+    parse_legacy_cfg(file_name, &ini).unwrap_or_default()
+}
+
+pub fn read_ini(file_name: &str, file: &[u8]) -> Option<Ini> {
+    let mut ini = Ini::new_cs();
+    ini.set_comment_symbols(&[';', '#', ':']);
+    let Ok(input_string) = str::from_utf8(file) else {
+        error!("Error converting file to string: {}", file_name);
+        return None;
+    };
+    if let Err(e) = ini.read(input_string.to_string()) {
+        error!("Error reading ini {}: {}", file_name, e);
+        return None;
+    }
+    Some(ini)
+}
+
+/// Returns the files the game loads because of a legacy cfg, or None if the file isn't a legacy cfg.
+/// For cfgs with subtypes this includes the files each listed subtype uses from its entity file, references to files that don't exist are dropped
+pub fn parse_legacy_cfg(file_name: &String, ini: &Ini) -> Option<Vec<String>> {
+    let legacy_cfg = get_legacy_cfg_type(file_name)?;
+    info!("Legacy cfg: {} {:?}", file_name, legacy_cfg.cfg_type);
+    let files = match legacy_cfg.cfg_type.layout() {
+        LegacyCfgLayout::List(sections) => sections.iter().flat_map(|section| parse_simple_cfg(ini, section)).collect(),
+        LegacyCfgLayout::Subtypes(section) => parse_subtypes_cfg(ini, section, |entity_file| {
+            let file = peek_file(entity_file)?;
+            read_ini(entity_file, &file)
+        }),
+        LegacyCfgLayout::Ids => Vec::new(),
+    };
+    Some(files)
+}
+
+/// The references written in a legacy cfg, without following subtypes into entity files and without checking the files exist.
+/// Returns None if the file isn't a legacy cfg
+pub fn legacy_cfg_references(file_name: &String, ini: &Ini) -> Option<Vec<String>> {
+    let legacy_cfg = get_legacy_cfg_type(file_name)?;
+    let files = match legacy_cfg.cfg_type.layout() {
+        LegacyCfgLayout::List(sections) => sections.iter().flat_map(|section| parse_simple_cfg(ini, section)).collect(),
+        LegacyCfgLayout::Subtypes(section) => parse_subtype_entries(ini, section).into_iter().map(|entry| entry.file).collect(),
+        LegacyCfgLayout::Ids => Vec::new(),
+    };
+    Some(files)
+}
+
+/// An entry in a list section of a cfg with subtypes. The key is repeated, the first value is the entity file and the rest are the
+/// subtypes the game creates from it, e.g. `aardvark = animals/aardvark.ai` followed by `aardvark = m`, `aardvark = f` and `aardvark = y`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CfgSubtypeEntry {
+    pub name: String,
+    pub file: String,
+    pub subtypes: Vec<String>,
+}
+
+pub fn parse_subtype_entries(file: &Ini, section_name: &str) -> Vec<CfgSubtypeEntry> {
+    let mut entries = Vec::new();
+    let Some(section) = file.get_map().unwrap_or_default().get(section_name).cloned() else {
+        return entries;
+    };
+    for (name, values) in section.iter() {
+        let Some(values) = values else {
+            continue;
+        };
+        let Some(entity_file) = values.iter().find(|value| Path::new(value.trim()).extension().is_some()) else {
+            continue;
+        };
+        // Merged cfgs can list more than one entity file for a name, the first is used and the rest aren't subtypes
+        let subtypes = values
+            .iter()
+            .filter(|value| Path::new(value.trim()).extension().is_none())
+            .map(|value| value.trim().to_lowercase())
+            .filter(|value| !value.is_empty())
+            .collect();
+        entries.push(CfgSubtypeEntry {
+            name: name.clone(),
+            file: normalise_cfg_path(entity_file),
+            subtypes,
+        });
+    }
+    entries
+}
+
+// Every entity file followed by the files its listed subtypes use, read_entity_file is given the entity file's path
+fn parse_subtypes_cfg<F>(file: &Ini, section_name: &str, read_entity_file: F) -> Vec<String>
+where
+    F: Fn(&str) -> Option<Ini>,
+{
+    let mut results = Vec::new();
+    for entry in parse_subtype_entries(file, section_name) {
+        let subtype_files = match read_entity_file(&entry.file) {
+            Some(entity) => entity_subtype_files(&entity, &entry.subtypes),
+            None => Vec::new(),
+        };
+        results.push(entry.file);
+        for subtype_file in subtype_files {
+            if !results.contains(&subtype_file) {
+                results.push(subtype_file);
+            }
+        }
+    }
+    results
+}
+
+// Sections of an entity file are prefixed with the subtype they belong to (e.g. [m/Icon], [f/Walk]), the game only reads the sections
+// of subtypes listed in the cfg. Sections without a subtype prefix and [Characteristics/*] apply to every subtype
+fn entity_subtype_files(entity: &Ini, subtypes: &[String]) -> Vec<String> {
+    let mut results = Vec::new();
+    for (section_name, section) in entity.get_map().unwrap_or_default().iter() {
+        if let Some((prefix, _)) = section_name.split_once('/')
+            && !subtypes.is_empty()
+            && !prefix.eq_ignore_ascii_case("characteristics")
+            && !subtypes.iter().any(|subtype| subtype.eq_ignore_ascii_case(prefix))
+        {
+            continue;
+        }
+        for value in section.values().flatten().flatten() {
+            if let Some(path) = cfg_path_value(value)
+                && !results.contains(&path)
+            {
+                results.push(path);
+            }
+        }
+    }
+    results
+}
+
+/// Every path-like value in every section, without checking the files exist so missing ones can be reported.
+/// Values without an extension are animations, which the game loads with .ani appended
+pub fn cfg_path_references(file: &Ini) -> Vec<String> {
+    let mut results = Vec::new();
+    for (_, section) in file.get_map().unwrap_or_default().iter() {
+        for value in section.values().flatten().flatten() {
+            if let Some(path) = cfg_path_value(value) {
+                results.push(path);
+            }
+        }
+    }
+    results
+}
+
+// Paths have an alphabetic extension (so numbers like 0.5 aren't paths) or are a directory path to an animation
+fn cfg_path_value(value: &str) -> Option<String> {
+    let path = normalise_cfg_path(value);
+    if path.is_empty() || path.contains(char::is_whitespace) {
+        return None;
+    }
+    match Path::new(&path).extension().and_then(|extension| extension.to_str()) {
+        Some(extension) if !extension.is_empty() && extension.chars().all(|c| c.is_ascii_alphabetic()) => Some(path),
+        Some(_) => None,
+        None if path.contains('/') => Some(format!("{}.ani", path)),
+        None => None,
+    }
+}
 
-        let Some((_archive_name, file)) = get_file(file_name) else {
-            error!("Error getting file: {}", file_name);
-            return Vec::new();
+pub fn normalise_cfg_path(value: &str) -> String {
+    value.trim().trim_matches('"').replace('\\', "/").to_lowercase()
+}
+
+fn parse_simple_cfg(file: &Ini, section_name: &str) -> Vec<String> {
+    let mut results = Vec::new();
+    if let Some(section) = file.get_map().unwrap_or_default().get(section_name) {
+        for (_, value) in section.iter() {
+            if let Some(value) = value {
+                if value.len() == 1 {
+                    results.push(value[0].clone());
+                }
+            };
+        }
+    }
+    results
+}
+
+/// Finds the file an entity type is loaded from (e.g. animals/aardvark.ai) by its key in the legacy list cfgs, names are compared case insensitively
+pub fn find_entity_file(names: &[String]) -> Option<String> {
+    for file_name in get_resource_names().into_iter().filter(|file_name| file_name.ends_with(".cfg")) {
+        let sections: Vec<&str> = match get_legacy_cfg_type(&file_name).map(|legacy_cfg| legacy_cfg.cfg_type.layout()) {
+            Some(LegacyCfgLayout::List(sections)) => sections.to_vec(),
+            Some(LegacyCfgLayout::Subtypes(section)) => vec![section],
+            _ => continue,
+        };
+        let Some(ini) = peek_file(&file_name).and_then(|file| read_ini(&file_name, &file)) else {
+            continue;
+        };
+        let map = ini.get_map().unwrap_or_default();
+        for section in sections.iter().filter_map(|section| map.get(*section)) {
+            for (key, values) in section.iter() {
+                if !names.iter().any(|name| name.eq_ignore_ascii_case(key)) {
+                    continue;
+                }
+                if let Some(entity_file) = values.iter().flatten().find(|value| Path::new(value.trim()).extension().is_some()) {
+                    return Some(normalise_cfg_path(entity_file));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Where a legacy cfg type keeps its file references
+enum LegacyCfgLayout {
+    /// One file per key in each of these sections
+    List(&'static [&'static str]),
+    /// Entity files with subtypes in this section, see CfgSubtypeEntry
+    Subtypes(&'static str),
+    /// No file references, only ids, string ids and numbers, e.g. expansion cfgs which are parsed by the expansions module
+    Ids,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LegacyCfgType {
+    Ambient,
+    Animal,
+    Building,
+    Fence,
+    Filter,
+    Food,
+    Free,
+    Fringe,
+    Guest,
+    Help,
+    Item,
+    Path,
+    Rubble,
+    Scenario,
+    Scenery,
+    Staff,
+    Tile,
+    Wall,
+    Expansion,
+    Show,
+    Tank,
+    UIInfoImage,
+    Economy,
+}
+
+impl LegacyCfgType {
+    fn layout(&self) -> LegacyCfgLayout {
+        match self {
+            LegacyCfgType::Ambient => LegacyCfgLayout::List(&["ambient"]),
+            LegacyCfgType::Animal => LegacyCfgLayout::Subtypes("animals"),
+            LegacyCfgType::Building => LegacyCfgLayout::List(&["building"]),
+            LegacyCfgType::Fence => LegacyCfgLayout::Subtypes("fences"),
+            LegacyCfgType::Filter => LegacyCfgLayout::Subtypes("filter"),
+            LegacyCfgType::Food => LegacyCfgLayout::List(&["food"]),
+            LegacyCfgType::Free => LegacyCfgLayout::List(&["freeform"]),
+            LegacyCfgType::Guest => LegacyCfgLayout::List(&["guest"]),
+            LegacyCfgType::Item => LegacyCfgLayout::List(&["items"]),
+            LegacyCfgType::Path => LegacyCfgLayout::List(&["paths"]),
+            LegacyCfgType::Rubble => LegacyCfgLayout::List(&["other"]),
+            LegacyCfgType::Scenery => LegacyCfgLayout::List(&["objects", "foliage", "other"]),
+            LegacyCfgType::Staff => LegacyCfgLayout::Subtypes("staff"),
+            LegacyCfgType::Wall => LegacyCfgLayout::Subtypes("tankwall"),
+            LegacyCfgType::Fringe => LegacyCfgLayout::List(&["fringe"]),
+            LegacyCfgType::Help => LegacyCfgLayout::List(&["help"]),
+            LegacyCfgType::Scenario => LegacyCfgLayout::List(&["scenario"]),
+            LegacyCfgType::Tile => LegacyCfgLayout::List(&["tiles"]),
+            LegacyCfgType::Show => LegacyCfgLayout::List(&["shows"]),
+            LegacyCfgType::Tank => LegacyCfgLayout::List(&["tanks"]),
+            LegacyCfgType::UIInfoImage => LegacyCfgLayout::List(&["infoimg"]),
+            LegacyCfgType::Expansion | LegacyCfgType::Economy => LegacyCfgLayout::Ids,
+        }
+    }
+
+    // List style cfgs are merged so packs shipping the same cfg don't hide each other's entries
+    fn default_merge_mode(&self) -> CfgMergeMode {
+        match self {
+            LegacyCfgType::Ambient
+            | LegacyCfgType::Animal
+            | LegacyCfgType::Building
+            | LegacyCfgType::Fence
+            | LegacyCfgType::Filter
+            | LegacyCfgType::Food
+            | LegacyCfgType::Free
+            | LegacyCfgType::Guest
+            | LegacyCfgType::Item
+            | LegacyCfgType::Path
+            | LegacyCfgType::Rubble
+            | LegacyCfgType::Scenery
+            | LegacyCfgType::Staff
+            | LegacyCfgType::Wall => CfgMergeMode::MergeSections,
+            LegacyCfgType::Fringe
+            | LegacyCfgType::Help
+            | LegacyCfgType::Scenario
+            | LegacyCfgType::Tile
+            | LegacyCfgType::Expansion
+            | LegacyCfgType::Show
+            | LegacyCfgType::Tank
+            | LegacyCfgType::UIInfoImage
+            | LegacyCfgType::Economy => CfgMergeMode::Override,
+        }
+    }
+}
+
+/// How legacy cfgs that are shipped by more than one archive are combined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CfgMergeMode {
+    /// The cfg from the last loaded archive is used, this is what vanilla does
+    Override,
+    /// Sections from every archive are combined, when a key exists in several archives its values are combined (without duplicates) with the last loaded archive's first
+    MergeSections,
+}
+
+impl FromStr for CfgMergeMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "override" => Ok(CfgMergeMode::Override),
+            "merge" => Ok(CfgMergeMode::MergeSections),
+            _ => Err("Invalid cfg merge mode, expected override or merge"),
+        }
+    }
+}
+
+static CFG_MERGE_MODES: Lazy<Mutex<HashMap<LegacyCfgType, CfgMergeMode>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn set_cfg_merge_mode(cfg_type: LegacyCfgType, merge_mode: CfgMergeMode) {
+    CFG_MERGE_MODES.lock().unwrap().insert(cfg_type, merge_mode);
+}
+
+fn get_cfg_merge_mode(cfg_type: &LegacyCfgType) -> CfgMergeMode {
+    CFG_MERGE_MODES.lock().unwrap().get(cfg_type).copied().unwrap_or(cfg_type.default_merge_mode())
+}
+
+// Reads the [cfg_merge] section of zoo.ini, keys are the legacy cfg prefixes (e.g. animal, scener, twall) and values are override or merge
+fn load_cfg_merge_modes(zoo_ini: &Ini) {
+    let Some(section) = zoo_ini.get_map().unwrap_or_default().get("cfg_merge").cloned() else {
+        return;
+    };
+    for (key, value) in section.iter() {
+        let Ok(legacy_cfg) = map_legacy_cfg_type(key, String::new()) else {
+            error!("Unknown legacy cfg type in [cfg_merge]: {}", key);
+            continue;
+        };
+        match value.as_ref().and_then(|values| values.last()).map(|value| value.parse::<CfgMergeMode>()) {
+            Some(Ok(merge_mode)) => {
+                info!("Using {:?} for {:?} cfgs", merge_mode, legacy_cfg.cfg_type);
+                set_cfg_merge_mode(legacy_cfg.cfg_type, merge_mode);
+            }
+            Some(Err(e)) => error!("Error parsing [cfg_merge] {}: {}", key, e),
+            None => error!("Missing value for [cfg_merge] {}", key),
+        }
+    }
+}
+
+// Combines legacy cfgs that more than one archive provides according to their merge mode, returns the number of cfgs merged
+fn merge_legacy_cfgs() -> usize {
+    let candidates = {
+        let binding = LAZY_RESOURCE_MAP.lock().unwrap();
+        binding
+            .overridden
+            .iter()
+            .filter(|(file_name, _)| {
+                get_legacy_cfg_type(file_name).is_some_and(|legacy_cfg| get_cfg_merge_mode(&legacy_cfg.cfg_type) == CfgMergeMode::MergeSections)
+            })
+            .map(|(file_name, providers)| (file_name.clone(), providers.clone()))
+            .collect::<Vec<(String, Vec<ResourceProvider>)>>()
+    };
+
+    let mut merged_count = 0;
+    for (file_name, providers) in candidates {
+        let Some(archive_name) = get_archive_name(&file_name) else {
+            continue;
         };
-        let mut ini = Ini::new_cs();
-        ini.set_comment_symbols(&[';', '#', ':']);
-        let Ok(input_string) = str::from_utf8(&file) else {
-            error!("Error converting file to string: {}", file_name);
-            return Vec::new();
+        let Some(file) = peek_file(&file_name) else {
+            continue;
         };
-        if let Err(e) = ini.read(input_string.to_string()) {
-            error!("Error reading ini {}: {}", file_name, e);
-            return Vec::new();
-        }
-
-        match legacy_cfg.cfg_type {
-            LegacyCfgType::Ambient => parse_simple_cfg(&ini, "ambient"),
-            LegacyCfgType::Animal => parse_simple_cfg(&ini, "animals"), //parse_subtypes_cfg(&ini, "animals"),
-            LegacyCfgType::Building => parse_simple_cfg(&ini, "building"),
-            LegacyCfgType::Fence => parse_simple_cfg(&ini, "fences"), //parse_subtypes_cfg(&ini, "fences"),
-            LegacyCfgType::Filter => parse_simple_cfg(&ini, "filter"), //parse_subtypes_cfg(&ini, "filter"),
-            LegacyCfgType::Food => parse_simple_cfg(&ini, "food"),
-            LegacyCfgType::Free => parse_simple_cfg(&ini, "freeform"),
-            // LegacyCfgType::Fringe => Vec::new(),
-            LegacyCfgType::Guest => parse_simple_cfg(&ini, "guest"),
-            // LegacyCfgType::Help => Vec::new(),
-            LegacyCfgType::Item => parse_simple_cfg(&ini, "items"),
-            LegacyCfgType::Path => parse_simple_cfg(&ini, "paths"),
-            LegacyCfgType::Rubble => parse_simple_cfg(&ini, "other"),
-            // LegacyCfgType::Scenario => Vec::new(),
-            LegacyCfgType::Scenery => {
-                let mut results = parse_simple_cfg(&ini, "objects");
-                results.append(&mut parse_simple_cfg(&ini, "foliage"));
-                results.append(&mut parse_simple_cfg(&ini, "other"));
-                results
-            },
-            LegacyCfgType::Staff => parse_simple_cfg(&ini, "staff"), //parse_subtypes_cfg(&ini, "staff"),
-            LegacyCfgType::Tile => Vec::new(),
-            LegacyCfgType::Wall => parse_simple_cfg(&ini, "tankwall"), //parse_subtypes_cfg(&ini, "tankwall"),
-            // LegacyCfgType::Expansion => Vec::new(),
-            // LegacyCfgType::Show => Vec::new(),
-            // LegacyCfgType::Tank => Vec::new(),
-            // LegacyCfgType::UIInfoImage => Vec::new(),
-            // LegacyCfgType::Economy => Vec::new(),
-            _ => Vec::new(),
+
+        // Most recently overridden first so later archives win when several provide the same key
+        let mut provider_files = Vec::new();
+        for provider in providers.iter().rev() {
+            match read_file_from_zip(&mut provider.archive.lock().unwrap(), &provider.filename) {
+                Ok(provider_file) => provider_files.push(provider_file),
+                Err(e) => error!("Error reading {} from {}: {}", provider.filename, provider.archive_name, e),
+            }
+        }
+        let Some((merged_string, added)) = merge_cfg_files(&file_name, &file, &provider_files) else {
+            continue;
+        };
+        if added == 0 {
+            continue;
+        }
+
+        info!("Merged {} entries into {} from {} other archives", added, file_name, providers.len());
+        if let Some(ztfile) = string_to_ztfile(&file_name, merged_string, &ZTFileType::Cfg) {
+            add_ztfile(Path::new(&archive_name), file_name, ztfile);
+            merged_count += 1;
         }
-    } else {
-        Vec::new()
     }
+    merged_count
 }
 
-fn parse_simple_cfg(file: &Ini, section_name: &str) -> Vec<String> {
-    let mut results = Vec::new();
-    if let Some(section) = file.get_map().unwrap_or_default().get(section_name) {
-        for (_, value) in section.iter() {
-            if let Some(value) = value {
-                if value.len() == 1 {
-                    results.push(value[0].clone());
+// Adds the sections, keys and values from each provider that `file` doesn't already have. Keys are often repeated to make a list (e.g. `item = ...`)
+// so values are combined rather than replaced, values from `file` and earlier providers come first.
+// Returns the merged cfg and the number of values added, None if `file` can't be parsed
+fn merge_cfg_files(file_name: &str, file: &[u8], provider_files: &[Box<[u8]>]) -> Option<(String, usize)> {
+    let mut merged = read_ini(file_name, file).and_then(|ini| ini.get_map())?;
+
+    let mut added = 0;
+    for provider_file in provider_files {
+        let Some(provider_map) = read_ini(file_name, provider_file).and_then(|ini| ini.get_map()) else {
+            continue;
+        };
+        for (section_name, section) in provider_map {
+            let merged_section = merged.entry(section_name).or_default();
+            for (key, value) in section {
+                match (merged_section.get_mut(&key), value) {
+                    (None, value) => {
+                        added += value.as_ref().map_or(1, |values| values.len());
+                        merged_section.insert(key, value);
+                    }
+                    (Some(Some(merged_values)), Some(values)) => {
+                        for value in values {
+                            if !merged_values.contains(&value) {
+                                merged_values.push(value);
+                                added += 1;
+                            }
+                        }
+                    }
+                    (Some(_), _) => {}
                 }
-            };
+            }
         }
     }
-    results
-}
 
-#[derive(Debug)]
-enum LegacyCfgType {
-    Ambient,
-    Animal,
-    Building,
-    Fence,
-    Filter,
-    Food,
-    Free,
-    Fringe,
-    Guest,
-    Help,
-    Item,
-    Path,
-    Rubble,
-    Scenario,
-    Scenery,
-    Staff,
-    Tile,
-    Wall,
-    Expansion,
-    Show,
-    Tank,
-    UIInfoImage,
-    Economy,
+    let mut merged_string = String::new();
+    for (section_name, section) in merged.iter() {
+        merged_string.push_str(&format!("[{}]\n", section_name));
+        for (key, value) in section.iter() {
+            match value {
+                Some(values) => values.iter().for_each(|value| merged_string.push_str(&format!("{} = {}\n", key, value))),
+                None => merged_string.push_str(&format!("{}\n", key)),
+            }
+        }
+        merged_string.push('\n');
+    }
+    Some((merged_string, added))
 }
 
 #[derive(Debug)]
@@ -1584,12 +3824,10 @@ fn zip_file_to_string(mut zip: ZipFile) -> anyhow::Result<String> {
         .to_string())
 }
 
-fn load_open_zt_mod(archive: &mut ZipArchive<BufReader<File>>) -> anyhow::Result<mods::ZtdType> {
-    if archive.by_name("meta.toml").is_err() {
+fn load_open_zt_mod(archive: &mut ZipArchive<BufReader<File>>, archive_name: &str, layer: ResourceLayer, meta: Option<mods::Meta>) -> anyhow::Result<mods::ZtdType> {
+    let Some(meta) = meta else {
         return Ok(mods::ZtdType::Legacy);
-    }
-
-    let meta = toml::from_str::<mods::Meta>(&read_file_from_zip_to_string(archive, "meta.toml")?).with_context(|| "Failed to parse meta.toml")?;
+    };
 
     if meta.ztd_type() == &mods::ZtdType::Legacy {
         return Ok(mods::ZtdType::Legacy);
@@ -1626,13 +3864,26 @@ fn load_open_zt_mod(archive: &mut ZipArchive<BufReader<File>>) -> anyhow::Result
 
     for file_name in keys {
         if file_name.starts_with("defs/") {
-            load_def(&mod_id, &file_name, &file_map)?;
+            load_def(&mod_id, archive_name, layer, &file_name, &file_map)?;
         }
     }
 
     Ok(meta.ztd_type().clone())
 }
 
+// Registers a PNG used as an icon as an animation, the animation and its generated palette are only created when the game first asks for either.
+// Icon definitions that share a PNG share the registered animation
+fn load_png_resource(mod_id: &String, archive_name: &str, layer: ResourceLayer, png_path: &str, png: &[u8]) -> String {
+    let animation_file_name = openzt_png_resource_id(mod_id, png_path);
+    let mut map = LAZY_RESOURCE_MAP.lock().unwrap();
+    if !map.contains_key(&animation_file_name.to_ascii_lowercase()) {
+        let palette_file_name = format!("{}.pal", animation_file_name);
+        info!("Adding PNG {} from {} as {}", png_path, mod_id, animation_file_name);
+        map.insert_generated(archive_name.to_string(), png.into(), animation_file_name.clone(), palette_file_name, layer);
+    }
+    animation_file_name
+}
+
 // Map between the id ZT uses to reference locations/habitats and the string ptr of the animation (icon) resource
 static LOCATIONS_HABITATS_RESOURCE_MAP: Lazy<Mutex<HashMap<u32, u32>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
@@ -1697,6 +3948,7 @@ fn add_new_mod_id(mod_id: &String) -> bool {
 enum ResourceType {
     Location,
     Habitat,
+    ColorVariant,
 }
 
 impl Display for ResourceType {
@@ -1704,6 +3956,7 @@ impl Display for ResourceType {
         match self {
             ResourceType::Location => write!(f, "location"),
             ResourceType::Habitat => write!(f, "habitat"),
+            ResourceType::ColorVariant => write!(f, "colorrep"),
         }
     }
 }
@@ -1724,7 +3977,13 @@ impl Display for ZTResourceType {
     }
 }
 
-fn load_def(mod_id: &String, file_name: &String, file_map: &HashMap<String, Box<[u8]>>) -> anyhow::Result<mods::ModDefinition> {
+fn load_def(
+    mod_id: &String,
+    archive_name: &str,
+    layer: ResourceLayer,
+    file_name: &String,
+    file_map: &HashMap<String, Box<[u8]>>,
+) -> anyhow::Result<mods::ModDefinition> {
     info!("Loading defs {} from {}", file_name, mod_id);
 
     let file = file_map
@@ -1743,13 +4002,7 @@ fn load_def(mod_id: &String, file_name: &String, file_map: &HashMap<String, Box<
     if let Some(habitats) = defs.habitats() {
         for (habitat_name, habitat_def) in habitats.iter() {
             let base_resource_id = openzt_base_resource_id(&mod_id, ResourceType::Habitat, habitat_name);
-            load_icon_definition(
-                &base_resource_id,
-                habitat_def,
-                &file_map,
-                mod_id,
-                include_str!("../resources/include/infoimg-habitat.ani").to_string(),
-            )?;
+            load_icon_definition(&base_resource_id, habitat_def, &file_map, mod_id, archive_name, layer)?;
             add_location_or_habitat(&habitat_def.name(), &base_resource_id)?;
         }
     }
@@ -1758,16 +4011,25 @@ fn load_def(mod_id: &String, file_name: &String, file_map: &HashMap<String, Box<
     if let Some(locations) = defs.locations() {
         for (location_name, location_def) in locations.iter() {
             let base_resource_id = openzt_base_resource_id(&mod_id, ResourceType::Location, location_name);
-            load_icon_definition(
-                &base_resource_id,
-                location_def,
-                &file_map,
-                mod_id,
-                include_str!("../resources/include/infoimg-location.ani").to_string(),
-            )?;
+            load_icon_definition(&base_resource_id, location_def, &file_map, mod_id, archive_name, layer)?;
             add_location_or_habitat(&location_def.name(), &base_resource_id)?;
         }
     }
+
+    // Colour variants
+    if let Some(color_variants) = defs.color_variants() {
+        for (variant_name, variant_def) in color_variants.iter() {
+            let base_resource_id = openzt_base_resource_id(&mod_id, ResourceType::ColorVariant, variant_name);
+            load_color_variant(&base_resource_id, variant_def, &file_map, mod_id)?;
+        }
+    }
+
+    // UI images
+    if let Some(ui_images) = defs.ui_images() {
+        for ui_image_def in ui_images.values() {
+            load_ui_image(ui_image_def, &file_map, mod_id)?;
+        }
+    }
     Ok(defs)
 }
 
@@ -1776,75 +4038,229 @@ fn load_icon_definition(
     icon_definition: &mods::IconDefinition,
     file_map: &HashMap<String, Box<[u8]>>,
     mod_id: &String,
-    base_config: String,
+    archive_name: &str,
+    layer: ResourceLayer,
 ) -> anyhow::Result<()> {
-    let icon_file = file_map.get(icon_definition.icon_path()).with_context(|| {
-        format!(
+    if !file_map.contains_key(icon_definition.icon_path()) {
+        return Err(anyhow!(
             "Error loading openzt mod {}, cannot find file {} for icon_def {}",
             mod_id,
             icon_definition.icon_path(),
             icon_definition.name()
+        ));
+    }
+
+    let animation_file_name = openzt_full_resource_id_path(&base_resource_id, ZTResourceType::Animation);
+    let bounds = if icon_definition.icon_path().to_ascii_lowercase().ends_with(".png") {
+        let png_file_name = load_png_resource(mod_id, archive_name, layer, icon_definition.icon_path(), &file_map[icon_definition.icon_path()]);
+        add_alias(&animation_file_name, &png_file_name, None)
+            .with_context(|| format!("Error loading openzt mod {}, cannot use PNG icon for icon_def {}", mod_id, icon_definition.name()))?;
+        // Bounds come from the PNG so it isn't converted until the game asks for it
+        sprite::png_bounds(&file_map[icon_definition.icon_path()])
+            .with_context(|| format!("Error loading openzt mod {}, invalid PNG icon for icon_def {}", mod_id, icon_definition.name()))?
+    } else {
+        load_icon_animation(base_resource_id, &animation_file_name, icon_definition, file_map, mod_id)?
+    };
+
+    let file_name = openzt_full_resource_id_path(&base_resource_id, ZTResourceType::Ani);
+    let ani = AniFile::for_openzt_resource(&animation_file_name, bounds);
+    info!("New ani: \n{}", ani);
+    let ztfile = ani
+        .to_ztfile(&file_name)
+        .with_context(|| format!("Error loading openzt mod {} when creating ZTFile for {}", mod_id, file_name))?;
+
+    add_ztfile(Path::new("zip::./openzt.ztd"), file_name, ztfile);
+
+    Ok(())
+}
+
+// Legacy icons are an animation and palette pair, the animation is pointed at a copy of the palette under the icon's resource id.
+// Returns the animation's bounds for the generated .ani
+fn load_icon_animation(
+    base_resource_id: &String,
+    animation_file_name: &String,
+    icon_definition: &mods::IconDefinition,
+    file_map: &HashMap<String, Box<[u8]>>,
+    mod_id: &String,
+) -> anyhow::Result<FrameBounds> {
+    let icon_file = &file_map[icon_definition.icon_path()];
+
+    let icon_palette_path = icon_definition.icon_palette_path().as_ref().with_context(|| {
+        format!(
+            "Error loading openzt mod {}, icon_def {} needs an icon_palette_path as {} isn't a PNG",
+            mod_id,
+            icon_definition.name(),
+            icon_definition.icon_path()
         )
     })?;
 
-    let icon_file_palette = file_map.get(icon_definition.icon_palette_path()).with_context(|| {
+    let icon_file_palette = file_map.get(icon_palette_path).with_context(|| {
         format!(
             "Error loading openzt mod {}, cannot find file {} for icon_def {}",
             mod_id,
-            icon_definition.icon_palette_path(),
+            icon_palette_path,
+            icon_definition.name()
+        )
+    })?;
+
+    // Parsed so broken palettes are reported when the mod is loaded rather than when the game tries to draw the icon
+    let palette = Palette::parse(icon_file_palette).with_context(|| {
+        format!(
+            "Error loading openzt mod {}, invalid palette {} for icon_def {}",
+            mod_id,
+            icon_palette_path,
             icon_definition.name()
         )
     })?;
+    let (palette_bytes, palette_size) = palette.write();
 
     let palette_file_name = openzt_full_resource_id_path(&base_resource_id, ZTResourceType::Palette);
-    let palette_ztfile = ZTFile::new_raw_bytes(palette_file_name.clone(), icon_file_palette.len() as u32, icon_file_palette.clone());
+    let palette_ztfile = ZTFile::new_raw_bytes(palette_file_name.clone(), palette_size as u32, palette_bytes.into_boxed_slice());
     add_ztfile(Path::new("zip::./openzt.ztd"), palette_file_name.clone(), palette_ztfile);
 
-    let mut animation = Animation::parse(icon_file);
+    let mut animation = Animation::parse(icon_file).with_context(|| {
+        format!(
+            "Error loading openzt mod {}, invalid animation {} for icon_def {}",
+            mod_id,
+            icon_definition.icon_path(),
+            icon_definition.name()
+        )
+    })?;
     animation.set_palette_filename(palette_file_name.clone());
+    let bounds = FrameBounds::of_animation(&animation).with_context(|| {
+        format!(
+            "Error loading openzt mod {}, animation {} for icon_def {} has no frames",
+            mod_id,
+            icon_definition.icon_path(),
+            icon_definition.name()
+        )
+    })?;
     let (new_animation_bytes, icon_size) = animation.write();
 
-    let new_icon_file = new_animation_bytes.into_boxed_slice();
-
-    let mut ani_cfg = Ini::new_cs();
-    ani_cfg.set_comment_symbols(&[';', '#', ':']);
-    ani_cfg.read(base_config).map_err(|s| anyhow!("Error reading ini: {}", s))?;
+    let animation_ztfile = ZTFile::new_raw_bytes(animation_file_name.clone(), icon_size as u32, new_animation_bytes.into_boxed_slice());
 
-    if ani_cfg.set("animation", "dir1", Some(openzt_full_resource_id_path(&base_resource_id, ZTResourceType::Animation))) == None {
-        return Err(anyhow!("Error setting dir1 for ani"));
-    }
+    add_ztfile(Path::new("zip::./openzt.ztd"), animation_file_name.clone(), animation_ztfile);
 
-    let mut write_options = WriteOptions::default();
-    write_options.space_around_delimiters = true;
-    write_options.blank_lines_between_sections = 1;
-    let new_string = ani_cfg.pretty_writes(&write_options);
-    info!("New ani: \n{}", new_string);
-    let file_size = new_string.len() as u32;
-    let file_name = openzt_full_resource_id_path(&base_resource_id, ZTResourceType::Ani);
+    Ok(bounds)
+}
 
-    let Ok(new_c_string) = CString::new(new_string) else {
-        return Err(anyhow!(
-            "Error loading openzt mod {} when converting .ani to CString after modifying {}",
-            mod_id,
-            file_name
-        ));
+// Generates the variant's palette and adds it to the entity's [colorrep] section
+fn load_color_variant(
+    base_resource_id: &String,
+    variant_definition: &mods::ColorVariantDefinition,
+    file_map: &HashMap<String, Box<[u8]>>,
+    mod_id: &String,
+) -> anyhow::Result<()> {
+    let base_palette_path = variant_definition.base_palette();
+    let base_palette_file = match file_map.get(base_palette_path) {
+        Some(file) => file.clone(),
+        None => peek_file(base_palette_path).with_context(|| {
+            format!(
+                "Error loading openzt mod {}, cannot find palette {} for color variant {}",
+                mod_id,
+                base_palette_path,
+                variant_definition.name()
+            )
+        })?,
     };
+    let mut palette = Palette::parse(&base_palette_file).with_context(|| {
+        format!(
+            "Error loading openzt mod {}, invalid palette {} for color variant {}",
+            mod_id,
+            base_palette_path,
+            variant_definition.name()
+        )
+    })?;
+    let replacements = variant_definition
+        .replacements()
+        .iter()
+        .map(|replacement| (*replacement.from(), *replacement.to()))
+        .collect::<HashMap<Color, Color>>();
+    if palette.replace_colors(&replacements) == 0 {
+        info!("Color variant {} from mod {} doesn't change any colors in {}", variant_definition.name(), mod_id, base_palette_path);
+    }
 
-    let ztfile = ZTFile::new_text(file_name.clone(), file_size, new_c_string)
-        .with_context(|| format!("Error loading openzt mod {} when creating ZTFile for .ani after modifying {}", mod_id, file_name))?;
-
-    add_ztfile(Path::new("zip::./openzt.ztd"), file_name, ztfile);
+    let palette_file_name = openzt_full_resource_id_path(&base_resource_id, ZTResourceType::Palette);
+    let (palette_bytes, palette_size) = palette.write();
+    let palette_ztfile = ZTFile::new_raw_bytes(palette_file_name.clone(), palette_size as u32, palette_bytes.into_boxed_slice());
+    add_ztfile(Path::new("zip::./openzt.ztd"), palette_file_name.clone(), palette_ztfile);
 
-    let animation_file_name = openzt_full_resource_id_path(&base_resource_id, ZTResourceType::Animation);
-    let animation_ztfile = ZTFile::new_raw_bytes(animation_file_name.clone(), icon_size as u32, new_icon_file);
+    let config_file_name = variant_definition.config_file();
+    let config_file = peek_file(config_file_name).with_context(|| {
+        format!(
+            "Error loading openzt mod {}, cannot find config {} for color variant {}",
+            mod_id,
+            config_file_name,
+            variant_definition.name()
+        )
+    })?;
+    let config_string = str::from_utf8(&config_file)
+        .with_context(|| format!("Error converting {} to utf8 for color variant {}", config_file_name, variant_definition.name()))?
+        .trim_end_matches('\0');
+    let new_config = add_colorrep_palette(config_string, &format!("{}/{}", OPENZT_DIR0, palette_file_name));
+    let config_size = new_config.len() as u32;
+    let config_ztfile = ZTFile::new_text(config_file_name.clone(), config_size, CString::new(new_config)?)
+        .with_context(|| format!("Error loading openzt mod {} when creating ZTFile for {}", mod_id, config_file_name))?;
+    add_patched_ztfile(mod_id, config_file_name.clone(), config_ztfile);
+
+    add_color_variant(ColorVariant {
+        mod_id: mod_id.clone(),
+        name: variant_definition.name().clone(),
+        entity_type: variant_definition.entity_type().clone(),
+        palette_resource: palette_file_name,
+    });
+    Ok(())
+}
 
-    add_ztfile(Path::new("zip::./openzt.ztd"), animation_file_name.clone(), animation_ztfile);
+// Replaces or extends a UI image with a png from the mod
+fn load_ui_image(ui_image_definition: &mods::UiImageDefinition, file_map: &HashMap<String, Box<[u8]>>, mod_id: &String) -> anyhow::Result<()> {
+    let image_path = ui_image_definition.image_path();
+    let png = file_map.get(image_path).with_context(|| {
+        format!(
+            "Error loading openzt mod {}, cannot find file {} for ui image {}",
+            mod_id,
+            image_path,
+            ui_image_definition.target()
+        )
+    })?;
+    let image = RgbaImage::from_png(png).with_context(|| format!("Error loading openzt mod {}, invalid png {}", mod_id, image_path))?;
+    patch_ui_image(&ui_image_definition.target().to_lowercase(), image, *ui_image_definition.mode(), mod_id)
+        .with_context(|| format!("Error loading openzt mod {} when patching {}", mod_id, ui_image_definition.target()))?;
+    Ok(())
+}
 
-    let palette_file_name = openzt_full_resource_id_path(&base_resource_id, ZTResourceType::Palette);
-    let palette_ztfile = ZTFile::new_raw_bytes(palette_file_name.clone(), icon_file_palette.len() as u32, icon_file_palette.clone());
-    add_ztfile(Path::new("zip::./openzt.ztd"), palette_file_name, palette_ztfile);
+/// Writes `image` to a TGA or BMP resource in the format of the image already there, or a default format for new images.
+/// Appending needs an existing image
+fn patch_ui_image(file_name: &str, image: RgbaImage, mode: mods::UiImageMode, patched_by: &str) -> anyhow::Result<UiImage> {
+    let existing = match peek_file(file_name) {
+        Some(file) => Some(UiImage::parse(file_name, &file)?),
+        None => None,
+    };
+    let ui_image = match (existing, mode) {
+        (Some(existing), mods::UiImageMode::Replace) => UiImage {
+            image,
+            format: existing.format,
+        },
+        (None, mods::UiImageMode::Replace) => UiImage {
+            image,
+            format: UiImageFormat::default_for(file_name).ok_or_else(|| anyhow!("{} isn't a TGA or BMP", file_name))?,
+        },
+        (Some(existing), mods::UiImageMode::AppendBelow | mods::UiImageMode::AppendRight) => UiImage {
+            image: stack_images(&[existing.image, image], mode == mods::UiImageMode::AppendBelow),
+            format: existing.format,
+        },
+        (None, _) => return Err(anyhow!("Cannot append to {}, image not found", file_name)),
+    };
+    let data = ui_image.write()?;
+    let ztfile = ZTFile::new_raw_bytes(file_name.to_string(), data.len() as u32, data.into_boxed_slice());
+    add_patched_ztfile(patched_by, file_name.to_string(), ztfile);
+    Ok(ui_image)
+}
 
-    Ok(())
+// Replaces an existing resource on behalf of a mod, the mod is recorded so resource_memory can attribute the new version to it
+fn add_patched_ztfile(patched_by: &str, file_name: String, ztfile: ZTFile) {
+    PATCHED_RESOURCES.lock().unwrap().insert(file_name.to_lowercase(), patched_by.to_string());
+    add_ztfile(Path::new("zip::./openzt.ztd"), file_name, ztfile);
 }
 
 fn openzt_base_resource_id(mod_id: &String, resource_type: ResourceType, resource_name: &String) -> String {
@@ -1852,6 +4268,12 @@ fn openzt_base_resource_id(mod_id: &String, resource_type: ResourceType, resourc
     format!("openzt.mods.{}.{}.{}", mod_id, resource_type_name, resource_name)
 }
 
+// OpenZT resource strings can't contain slashes so directories are separated by dots, e.g. resources/moon/icon.png -> openzt.mods.<mod_id>.resources.moon.icon
+fn openzt_png_resource_id(mod_id: &String, png_path: &str) -> String {
+    let path = &png_path[..png_path.len() - ".png".len()];
+    format!("openzt.mods.{}.{}", mod_id, path.replace('/', "."))
+}
+
 fn openzt_full_resource_id_path(base_resource_id: &String, file_type: ZTResourceType) -> String {
     format!("{}.{}", base_resource_id, file_type.to_string())
 }
@@ -1861,8 +4283,385 @@ static RESOURCE_HANDLER_ARRAY: Lazy<Mutex<Vec<Handler>>> = Lazy::new(|| Mutex::n
 pub fn add_handler(handler: Handler) {
     let mut data_mutex = RESOURCE_HANDLER_ARRAY.lock().unwrap();
     data_mutex.push(handler);
+    // sort_by_key is stable so handlers with the same priority keep their registration order
+    data_mutex.sort_by_key(|handler| handler.priority);
 }
 
 fn get_handlers() -> Vec<Handler> {
     RESOURCE_HANDLER_ARRAY.lock().unwrap().clone()
 }
+
+// Files that have already had OnDemand handlers applied, keys are lowercase
+static ON_DEMAND_HANDLED_FILES: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Runs OnDemand handlers the first time a file is requested, handlers load and replace the file so no locks can be held while they run.
+// Called on every BFResource_attempt/prepare so files that have already been seen return before the handler list is looked at
+fn apply_on_demand_handlers(file_name: &str) {
+    let file_name = file_name.to_lowercase();
+    if ON_DEMAND_HANDLED_FILES.lock().unwrap().contains(&file_name) {
+        return;
+    }
+    let handlers = RESOURCE_HANDLER_ARRAY
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|handler| handler.stage == RunStage::OnDemand && handler.path_matchers_match(&file_name))
+        .cloned()
+        .collect::<Vec<Handler>>();
+    if !check_file(&file_name) {
+        return;
+    }
+    // Marked before running so files requested by the handlers themselves don't recurse, files no handler matches are marked too
+    if !ON_DEMAND_HANDLED_FILES.lock().unwrap().insert(file_name.clone()) {
+        return;
+    }
+    for handler in handlers {
+        handler.handle(&file_name);
+    }
+}
+
+#[cfg(test)]
+mod parsing_tests {
+    use std::collections::HashMap;
+
+    use super::{
+        archive_layer, cfg_path_references, glob_to_regex, legacy_cfg_references, merge_cfg_files, parse_subtype_entries,
+        parse_subtypes_cfg, read_ini, CfgMergeMode, CfgSubtypeEntry, Handler, HandlerMatcher, LazyResource, LazyResourceMap, ResourceBacking, ResourceLayer,
+        ResourceMemory, RunStage, ZTFile, ZTFileType,
+    };
+    use zt_formats::{animation::Animation, sprite::RgbaImage};
+
+    fn test_ini(file_name: &str, data: &[u8]) -> bf_configparser::ini::Ini {
+        read_ini(file_name, data).unwrap()
+    }
+
+    #[test]
+    fn test_parse_subtype_entries() {
+        let cfg = test_ini("animal-subtypes.cfg", include_bytes!("../resources/test/animal-subtypes.cfg"));
+        assert_eq!(
+            parse_subtype_entries(&cfg, "animals"),
+            vec![
+                CfgSubtypeEntry {
+                    name: "aardvark".to_string(),
+                    file: "animals/aardvark.ai".to_string(),
+                    subtypes: vec!["m".to_string(), "f".to_string()],
+                },
+                CfgSubtypeEntry {
+                    name: "okapi".to_string(),
+                    file: "animals/okapi.ai".to_string(),
+                    subtypes: Vec::new(),
+                },
+            ]
+        );
+        assert!(parse_subtype_entries(&cfg, "staff").is_empty());
+    }
+
+    #[test]
+    fn test_parse_subtypes_cfg() {
+        let cfg = test_ini("animal-subtypes.cfg", include_bytes!("../resources/test/animal-subtypes.cfg"));
+        let files = parse_subtypes_cfg(&cfg, "animals", |entity_file| match entity_file {
+            "animals/aardvark.ai" => Some(test_ini(entity_file, include_bytes!("../resources/test/aardvark.ai"))),
+            _ => None,
+        });
+        assert_eq!(
+            files,
+            vec![
+                "animals/aardvark.ai",
+                "animals/aardvark/aardvark.pal",
+                "animals/aardvark/m/icaard/icaard.ani",
+                "animals/aardvark/f/icaard/icaard.ani",
+                "animals/okapi.ai",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_legacy_cfg_references() {
+        let cfg = test_ini("animal01.cfg", include_bytes!("../resources/test/animal-subtypes.cfg"));
+        assert_eq!(legacy_cfg_references(&"animal01.cfg".to_string(), &cfg), Some(vec!["animals/aardvark.ai".to_string(), "animals/okapi.ai".to_string()]));
+        assert_eq!(legacy_cfg_references(&"aardvark.cfg".to_string(), &cfg), None);
+    }
+
+    #[test]
+    fn test_cfg_path_references() {
+        let cfg = test_ini("help01.cfg", include_bytes!("../resources/test/file-references.cfg"));
+        assert_eq!(cfg_path_references(&cfg), vec!["ui/help/topic1.lyt", "ui/help/image.tga", "ui/help/anim.ani"]);
+    }
+
+    fn legacy_cfg_fixture_references(file_name: &str, data: &[u8]) -> Vec<String> {
+        let cfg = test_ini(file_name, data);
+        legacy_cfg_references(&file_name.to_string(), &cfg).unwrap()
+    }
+
+    #[test]
+    fn test_legacy_cfg_references_fringe() {
+        let references = legacy_cfg_fixture_references("fringe.cfg", include_bytes!("../resources/test/legacy-cfgs/fringe.cfg"));
+        assert_eq!(references, vec!["terrain/fringe/grass.ani", "terrain/fringe/sand.ani"]);
+    }
+
+    #[test]
+    fn test_legacy_cfg_references_help() {
+        let references = legacy_cfg_fixture_references("help.cfg", include_bytes!("../resources/test/legacy-cfgs/help.cfg"));
+        assert_eq!(references, vec!["ui/help/animals.lyt", "ui/help/staff.lyt"]);
+    }
+
+    #[test]
+    fn test_legacy_cfg_references_scenario() {
+        let references = legacy_cfg_fixture_references("scenar.cfg", include_bytes!("../resources/test/legacy-cfgs/scenar.cfg"));
+        assert_eq!(references, vec!["scenario/tutorial/tut1.scn", "scenario/tutorial/tut2.scn"]);
+    }
+
+    #[test]
+    fn test_legacy_cfg_references_tile() {
+        let references = legacy_cfg_fixture_references("tile.cfg", include_bytes!("../resources/test/legacy-cfgs/tile.cfg"));
+        assert_eq!(references, vec!["terrain/grass.ai", "terrain/dirt.ai"]);
+    }
+
+    #[test]
+    fn test_legacy_cfg_references_show() {
+        let references = legacy_cfg_fixture_references("shows.cfg", include_bytes!("../resources/test/legacy-cfgs/shows.cfg"));
+        assert_eq!(references, vec!["shows/dolphin.ai", "shows/orca.ai"]);
+    }
+
+    #[test]
+    fn test_legacy_cfg_references_tank() {
+        let references = legacy_cfg_fixture_references("tanks.cfg", include_bytes!("../resources/test/legacy-cfgs/tanks.cfg"));
+        assert_eq!(references, vec!["tanks/tank.ai"]);
+    }
+
+    #[test]
+    fn test_legacy_cfg_references_ui_info_image() {
+        let references = legacy_cfg_fixture_references("ui/infoimg.cfg", include_bytes!("../resources/test/legacy-cfgs/infoimg.cfg"));
+        assert_eq!(references, vec!["ui/infoimg/savannah.tga", "ui/infoimg/rainforest.tga"]);
+    }
+
+    #[test]
+    fn test_legacy_cfg_references_economy() {
+        let references = legacy_cfg_fixture_references("economy.cfg", include_bytes!("../resources/test/legacy-cfgs/economy.cfg"));
+        assert!(references.is_empty());
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        let glob = glob_to_regex("animals/*.ai").unwrap();
+        assert!(glob.is_match("animals/aardvark.ai"));
+        assert!(glob.is_match("ANIMALS/Aardvark.AI"));
+        assert!(!glob.is_match("animals/aardvark/aardvark.ai"));
+        assert!(!glob.is_match("animals/aardvark.aix"));
+
+        let glob = glob_to_regex("animals/**.ani").unwrap();
+        assert!(glob.is_match("animals/aardvark/m/walk/walk.ani"));
+        assert!(!glob.is_match("scenery/aardvark/walk.ani"));
+
+        let glob = glob_to_regex("ui/?.tga").unwrap();
+        assert!(glob.is_match("ui/a.tga"));
+        assert!(!glob.is_match("ui/ab.tga"));
+        assert!(!glob.is_match("ui/a-tga"));
+    }
+
+    #[test]
+    fn test_glob_to_regex_classes() {
+        let glob = glob_to_regex("[ab]*.cfg").unwrap();
+        assert!(glob.is_match("animal01.cfg"));
+        assert!(glob.is_match("bldg01.cfg"));
+        assert!(!glob.is_match("staff01.cfg"));
+
+        let glob = glob_to_regex("[!ab]*.cfg").unwrap();
+        assert!(!glob.is_match("animal01.cfg"));
+        assert!(glob.is_match("staff01.cfg"));
+    }
+
+    #[test]
+    fn test_handler_matcher_path() {
+        assert!(HandlerMatcher::Prefix("animals/".to_string()).matches_path("animals/aardvark.ai"));
+        assert!(!HandlerMatcher::Prefix("animals/".to_string()).matches_path("scenery/aardvark.ai"));
+        assert!(HandlerMatcher::Suffix(".ai".to_string()).matches_path("animals/aardvark.ai"));
+        assert!(!HandlerMatcher::Suffix(".ai".to_string()).matches_path("animals/aardvark.cfg"));
+        assert!(HandlerMatcher::Glob("animals/*.ai".to_string(), glob_to_regex("animals/*.ai").unwrap()).matches_path("animals/aardvark.ai"));
+        assert!(HandlerMatcher::Regex(regex::Regex::new("^animals/.*/m/").unwrap()).matches_path("animals/aardvark/m/walk/walk.ani"));
+        assert!(!HandlerMatcher::Regex(regex::Regex::new("^animals/.*/m/").unwrap()).matches_path("animals/aardvark/f/walk/walk.ani"));
+        // Resource matchers are left to matches_resource
+        assert!(HandlerMatcher::FileType(ZTFileType::Ai).matches_path("animals/aardvark.cfg"));
+    }
+
+    #[test]
+    fn test_handler_matches_path() {
+        let handler = Handler::builder().prefix("animals/").file_type(ZTFileType::Ai).run_stage(RunStage::AfterFiltering).path_handler(|_, _| {}).build();
+        assert!(handler.matches_path("animals/aardvark.ai"));
+        assert!(!handler.matches_path("scenery/aardvark.ai"));
+        // Without a path matcher the handler can't be narrowed down from the path
+        let handler = Handler::builder().file_type(ZTFileType::Ai).run_stage(RunStage::AfterFiltering).path_handler(|_, _| {}).build();
+        assert!(!handler.matches_path("animals/aardvark.ai"));
+        assert!(handler.path_matchers_match("animals/aardvark.ai"));
+    }
+
+    #[test]
+    fn test_resource_memory_owner() {
+        let archive_mod_ids = HashMap::from([("moon-location.ztd".to_string(), "finn.moon".to_string())]);
+        let patched_resources = HashMap::from([("ui/sharedui/listbk.tga".to_string(), "finn.ui".to_string())]);
+        let owner = |file_name: &str, archive_name: &str| {
+            ResourceMemory {
+                file_name: file_name.to_string(),
+                archive_name: archive_name.to_string(),
+                bytes: 0,
+            }
+            .owner(&archive_mod_ids, &patched_resources)
+        };
+        assert_eq!(owner("openzt.mods.finn.moon.location.moon.ani", "zip::./openzt.ztd"), "finn.moon");
+        assert_eq!(owner("openzt.mods.finn.moon.location.moon.ani (retired)", "zip::./openzt.ztd"), "finn.moon");
+        assert_eq!(owner("openzt.mods.finn.moonlight.location.moon.ani", "zip::./openzt.ztd"), "openzt");
+        // Vanilla files rewritten by a mod belong to the mod rather than OpenZT
+        assert_eq!(owner("ui/sharedui/listbk.tga", "zip::./openzt.ztd"), "finn.ui");
+        assert_eq!(owner("ui/sharedui/listbk.tga (retired)", "zip::./openzt.ztd"), "finn.ui");
+        assert_eq!(owner("openzt/generated.ani", "zip::./openzt.ztd"), "openzt");
+        assert_eq!(owner("moon-location.ztd (zip index)", ".\\mods\\Moon-Location.ztd"), "finn.moon");
+        assert_eq!(owner("animals/aardvark.ai", "./dlupdate/ZUpdate1.ztd"), "zupdate1.ztd");
+    }
+
+    #[test]
+    fn test_handler_matcher_resource() {
+        assert!(HandlerMatcher::FileType(ZTFileType::Ai).matches_resource("animals/aardvark.ai", &ZTFileType::Ai));
+        assert!(!HandlerMatcher::FileType(ZTFileType::Ai).matches_resource("animals/aardvark.cfg", &ZTFileType::Cfg));
+        // Path matchers are left to matches_path
+        assert!(HandlerMatcher::Prefix("scenery/".to_string()).matches_resource("animals/aardvark.ai", &ZTFileType::Ai));
+        // Resources that haven't been loaded from an archive never match
+        assert!(!HandlerMatcher::Archive("*.ztd".to_string(), glob_to_regex("*.ztd").unwrap()).matches_resource("animals/aardvark.ai", &ZTFileType::Ai));
+    }
+
+    #[test]
+    fn test_cfg_merge_mode_from_str() {
+        assert_eq!("merge".parse::<CfgMergeMode>(), Ok(CfgMergeMode::MergeSections));
+        assert_eq!(" OVERRIDE ".parse::<CfgMergeMode>(), Ok(CfgMergeMode::Override));
+        assert!("sections".parse::<CfgMergeMode>().is_err());
+    }
+
+    #[test]
+    fn test_merge_cfg_files() {
+        let file = b"[animals]\naardvark = animals/aardvark.ai\n";
+        let provider_files: Vec<Box<[u8]>> = vec![
+            b"[animals]\naardvark = mod1/aardvark.ai\nokapi = animals/okapi.ai\n[staff]\nkeeper = staff/keeper.ai\n".as_slice().into(),
+            b"\xff".as_slice().into(),
+            b"[animals]\nokapi = mod2/okapi.ai\nzebra = animals/zebra.ai\n".as_slice().into(),
+        ];
+        let (merged, added) = merge_cfg_files("animal01.cfg", file, &provider_files).unwrap();
+        assert_eq!(added, 5);
+        assert_eq!(
+            merged,
+            "[animals]\naardvark = animals/aardvark.ai\naardvark = mod1/aardvark.ai\nokapi = animals/okapi.ai\nokapi = mod2/okapi.ai\nzebra = animals/zebra.ai\n\n\
+             [staff]\nkeeper = staff/keeper.ai\n\n"
+        );
+        assert!(merge_cfg_files("animal01.cfg", b"\xff", &provider_files).is_none());
+    }
+
+    #[test]
+    fn test_merge_cfg_files_repeated_keys() {
+        let file = b"[items]\nitem = items/a.ai\nitem = items/b.ai\n";
+        let provider_files: Vec<Box<[u8]>> = vec![
+            b"[items]\nitem = items/b.ai\nitem = items/c.ai\n".as_slice().into(),
+            b"[items]\nitem = items/d.ai\nitem = items/a.ai\n".as_slice().into(),
+        ];
+        assert_eq!(
+            merge_cfg_files("items.cfg", file, &provider_files),
+            Some(("[items]\nitem = items/a.ai\nitem = items/b.ai\nitem = items/c.ai\nitem = items/d.ai\n\n".to_string(), 2))
+        );
+    }
+
+    #[test]
+    fn test_archive_layer() {
+        assert_eq!(archive_layer("animals.ztd"), ResourceLayer::Vanilla);
+        assert_eq!(archive_layer("./animals.ztd"), ResourceLayer::Vanilla);
+        assert_eq!(archive_layer("./zupdate/zupdate.ztd"), ResourceLayer::OfficialUpdate);
+        assert_eq!(archive_layer("./xpack1/custom.ztd"), ResourceLayer::OfficialUpdate);
+        assert_eq!(archive_layer("./dlupdate/animals.ztd"), ResourceLayer::OfficialUpdate);
+        assert_eq!(archive_layer("./dlupdate/ANIMALS.ZTD"), ResourceLayer::OfficialUpdate);
+        assert_eq!(archive_layer("./dlupdate/custom.ztd"), ResourceLayer::LegacyCc);
+        assert_eq!(archive_layer("./custom.ztd"), ResourceLayer::LegacyCc);
+        assert_eq!(archive_layer("./mods/animals.ztd"), ResourceLayer::LegacyCc);
+    }
+
+    #[test]
+    fn test_resource_layer_from_str() {
+        assert_eq!(" Legacy_CC ".parse::<ResourceLayer>(), Ok(ResourceLayer::LegacyCc));
+        assert!("legacy".parse::<ResourceLayer>().is_err());
+        for layer in [
+            ResourceLayer::Vanilla,
+            ResourceLayer::OfficialUpdate,
+            ResourceLayer::LegacyCc,
+            ResourceLayer::OpenZTMod,
+            ResourceLayer::UserOverride,
+        ] {
+            assert_eq!(layer.to_string().parse::<ResourceLayer>(), Ok(layer));
+        }
+    }
+
+    fn test_resource_map() -> LazyResourceMap {
+        let mut resource_map = LazyResourceMap::new();
+        resource_map.map.insert(
+            "animals/aardvark.ai".to_string(),
+            LazyResource {
+                backing: ResourceBacking::Custom{data: 0},
+                filename: "animals/aardvark.ai".to_string(),
+                type_: ZTFileType::Ai,
+                layer: ResourceLayer::LegacyCc,
+            },
+        );
+        resource_map
+    }
+
+    #[test]
+    fn test_generate_png() {
+        let mut resource_map = LazyResourceMap::new();
+        let mut image = RgbaImage::new(4, 4);
+        image.pixels[..8].copy_from_slice(&[255, 0, 0, 255, 0, 0, 255, 255]);
+        resource_map.insert_generated(
+            "moon-location.ztd".to_string(),
+            image.to_png().unwrap().into_boxed_slice(),
+            "openzt.mods.finn.moon.resources.moon.icon".to_string(),
+            "openzt.mods.finn.moon.resources.moon.icon.pal".to_string(),
+            ResourceLayer::OpenZTMod,
+        );
+        let Some(ResourceBacking::Generated{source}) = resource_map.map.get("openzt.mods.finn.moon.resources.moon.icon.pal").map(|resource| resource.backing.clone()) else {
+            panic!("palette should be generated");
+        };
+        let [(animation_file_name, ZTFile::RawBytes(animation, ..)), (palette_file_name, _)] = source.generate().unwrap() else {
+            panic!("animation should be raw bytes");
+        };
+        assert_eq!(animation_file_name, "openzt.mods.finn.moon.resources.moon.icon");
+        assert_eq!(Animation::parse(&animation).unwrap().palette_filename, palette_file_name);
+
+        resource_map.generate(&source).unwrap();
+        for (file_name, file_type) in [(animation_file_name, ZTFileType::Animation), (palette_file_name, ZTFileType::Palette)] {
+            let resource = &resource_map.map[&file_name];
+            assert!(matches!(resource.backing, ResourceBacking::Custom{..}));
+            assert_eq!(resource.type_, file_type);
+        }
+    }
+
+    #[test]
+    fn test_insert_alias() {
+        let mut resource_map = test_resource_map();
+        resource_map.insert_alias("animals/Aardvark2.ai", "Animals/Aardvark.ai", None).unwrap();
+        let alias = &resource_map.map["animals/aardvark2.ai"];
+        assert_eq!(alias.filename, "animals/Aardvark2.ai");
+        assert_eq!(alias.type_, ZTFileType::Ai);
+        assert_eq!(alias.layer, ResourceLayer::LegacyCc);
+        assert!(matches!(&alias.backing, ResourceBacking::Alias{target, transform: None} if target == "animals/aardvark.ai"));
+
+        assert!(resource_map.insert_alias("animals/aardvark3.ai", "animals/missing.ai", None).is_err());
+        assert!(resource_map.insert_alias("animals/aardvark2.ai", "animals/Aardvark2.ai", None).is_err());
+    }
+
+    #[test]
+    fn test_resolve_alias() {
+        let mut resource_map = test_resource_map();
+        resource_map.insert_alias("animals/aardvark2.ai", "animals/aardvark.ai", None).unwrap();
+        resource_map.insert_alias("animals/aardvark3.ai", "animals/aardvark2.ai", None).unwrap();
+        assert_eq!(resource_map.resolve_alias("animals/aardvark3.ai"), Some("animals/aardvark.ai".to_string()));
+        assert_eq!(resource_map.resolve_alias("animals/aardvark.ai"), Some("animals/aardvark.ai".to_string()));
+        assert_eq!(resource_map.resolve_alias("animals/missing.ai"), None);
+
+        // Replacing an alias is allowed even if it makes a cycle, the cycle is caught when resolving
+        resource_map.insert_alias("animals/aardvark2.ai", "animals/aardvark3.ai", None).unwrap();
+        assert_eq!(resource_map.resolve_alias("animals/aardvark2.ai"), None);
+    }
+}
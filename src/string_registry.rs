@@ -63,7 +63,11 @@ pub mod zoo_string {
     use tracing::info;
 
     use super::{is_user_type_id, STRING_REGISTRY_ID_OFFSET};
-    use crate::{debug_dll::save_string_to_memory, string_registry::get_string_from_registry};
+    use crate::{
+        debug_dll::save_string_to_memory,
+        resource_manager::{wait_for_load_state, LoadState},
+        string_registry::get_string_from_registry,
+    };
 
     #[hook(unsafe extern "thiscall" BFApp_loadString, offset = 0x00004e0a)]
     fn bf_app_load_string(this_ptr: u32, string_id: u32, string_buffer: u32) -> u32 {
@@ -71,7 +75,13 @@ pub mod zoo_string {
             info!("BFApp::loadString {:#x} {} {:#x}", this_ptr, string_id, string_buffer);
         }
         if string_id >= STRING_REGISTRY_ID_OFFSET {
-            if let Ok(string) = get_string_from_registry(string_id) {
+            // OpenZT strings are registered on the resource loading thread by mod definitions and handlers, a registered string
+            // never changes so only ids that aren't registered yet wait for loading to finish
+            let string = get_string_from_registry(string_id).or_else(|_| {
+                wait_for_load_state(LoadState::Ready);
+                get_string_from_registry(string_id)
+            });
+            if let Ok(string) = string {
                 info!(
                     "BFApp::loadString string_id: {}, override: {} -> {}",
                     string_id,